@@ -0,0 +1,109 @@
+use sha2::{Digest, Sha256};
+use std::{cell::RefCell, collections::HashMap, fs, path, sync::Mutex};
+
+use crate::snapshot::list_files;
+
+thread_local! {
+    // input path and app dir of the app file being rendered on this thread
+    static APP_DIR: RefCell<Option<(path::PathBuf, path::PathBuf)>> = const { RefCell::new(None) };
+}
+
+// runs f, which renders one of the app's files, with dir_hash and glob paths resolved relative to
+// app_dir. Files are rendered concurrently on the thread pool so the app dir is tracked per
+// thread
+pub fn with_app_dir<T>(input_path: &path::Path, app_dir: &path::Path, f: impl FnOnce() -> T) -> T {
+    APP_DIR.with(|dir| *dir.borrow_mut() = Some((input_path.to_path_buf(), app_dir.to_path_buf())));
+    let result = f();
+    APP_DIR.with(|dir| *dir.borrow_mut() = None);
+    return result;
+}
+
+// tera function returning a sha256 of the names and contents of every file in a directory
+// relative to the app dir, eg. to roll a deployment whenever its config changes
+//
+//   checksum/config: {{ dir_hash(path="config") }}
+//
+// The directory has to be within the app's input path. Apps reused with --cache aren't re-rendered
+// when only a directory outside of the app dir changes
+#[derive(Default)]
+pub struct DirHash {
+    hashes: Mutex<HashMap<path::PathBuf, String>>,
+}
+
+impl tera::Function for DirHash {
+    fn call(&self, args: &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value> {
+        let dir = args
+            .get("path")
+            .and_then(|value| value.as_str())
+            .ok_or_else(|| tera::Error::msg("dir_hash: missing string argument path"))?;
+        let (input_path, app_dir) = current_app_dir("dir_hash")?;
+
+        let resolved = app_dir.join(dir).canonicalize().map_err(|e| {
+            tera::Error::msg(format!("dir_hash: failed to resolve {:?}: {}", dir, e))
+        })?;
+        if !resolved.starts_with(&input_path) {
+            return Err(tera::Error::msg(format!(
+                "dir_hash: {:?} resolves to {:?} which is outside the input path {:?}",
+                dir, resolved, input_path
+            )));
+        }
+
+        let mut hashes = self.hashes.lock().unwrap();
+        match hashes.get(&resolved) {
+            Some(hash) => return Ok(serde_json::Value::String(hash.clone())),
+            None => (),
+        }
+        let hash = hash_dir(&resolved)
+            .map_err(|e| tera::Error::msg(format!("dir_hash: failed to hash {:?}: {}", dir, e)))?;
+        hashes.insert(resolved, hash.clone());
+        return Ok(serde_json::Value::String(hash));
+    }
+}
+
+// tera function returning the paths of the app's files matching a glob, relative to the app dir
+// and sorted, eg. to list the dashboards a sidecar should load
+//
+//   {% for file in glob(pattern="dashboards/*.json") %}
+//   - {{ file }}
+//   {% endfor %}
+pub fn glob_function(args: &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value> {
+    let pattern = args
+        .get("pattern")
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| tera::Error::msg("glob: missing string argument pattern"))?;
+    let pattern = glob::Pattern::new(pattern)
+        .map_err(|e| tera::Error::msg(format!("glob: invalid pattern {:?}: {}", pattern, e)))?;
+    let (_, app_dir) = current_app_dir("glob")?;
+
+    let files = list_files(&app_dir)
+        .map_err(|e| tera::Error::msg(format!("glob: failed to list the app's files: {}", e)))?;
+    return Ok(serde_json::Value::from(
+        files
+            .iter()
+            .filter(|file| pattern.matches_path(file))
+            .map(|file| file.to_string_lossy().to_string())
+            .collect::<Vec<_>>(),
+    ));
+}
+
+fn current_app_dir(function: &str) -> tera::Result<(path::PathBuf, path::PathBuf)> {
+    return APP_DIR
+        .with(|app_dir| app_dir.borrow().clone())
+        .ok_or_else(|| {
+            tera::Error::msg(format!("{} can only be used in an app's files", function))
+        });
+}
+
+fn hash_dir(dir: &path::Path) -> anyhow::Result<String> {
+    if !dir.is_dir() {
+        return Err(anyhow::anyhow!("not a directory"));
+    }
+    let mut hasher = Sha256::new();
+    for file in list_files(dir)? {
+        hasher.update(file.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher.update(fs::read(dir.join(&file))?);
+        hasher.update([0]);
+    }
+    return Ok(format!("{:x}", hasher.finalize()));
+}
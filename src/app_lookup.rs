@@ -0,0 +1,74 @@
+use sha2::{Digest, Sha256};
+use std::{
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+// tera function returning the context of another app, eg. to reference an app's ingress host
+//
+//   {% set ingress = lookup_app(project="platform", name="ingress", target=config.target.name) %}
+//   url: https://{{ ingress.vars.host }}
+//
+// The context holds the app's template context (namespace, path, source_dir, ...) along with its
+// vars for the target. Every app is registered before any is rendered, so apps can be looked up
+// regardless of the order they're processed in. Disabled apps and targets which weren't selected
+// can't be looked up. Apps calling it are re-rendered with --cache whenever any app's context
+// changes since which apps a render looks up isn't known beforehand
+#[derive(Clone, Default)]
+pub struct LookupApp {
+    apps: Arc<RwLock<HashMap<AppKey, serde_json::Value>>>,
+}
+
+// project, app name and target
+type AppKey = (String, String, String);
+
+impl LookupApp {
+    pub fn register(&self, project: &str, name: &str, target: &str, context: serde_json::Value) {
+        self.apps.write().unwrap().insert(
+            (project.to_string(), name.to_string(), target.to_string()),
+            context,
+        );
+    }
+
+    // hash of every registered app's context, it changes whenever lookup_app could return
+    // something different
+    pub fn hash(&self) -> Result<String, serde_json::Error> {
+        let apps = self.apps.read().unwrap();
+        let mut keys = apps.keys().collect::<Vec<_>>();
+        keys.sort();
+        let mut hasher = Sha256::new();
+        for key in keys {
+            hasher.update(serde_json::to_vec(&(key, &apps[key]))?);
+        }
+        return Ok(format!("{:x}", hasher.finalize()));
+    }
+}
+
+impl tera::Function for LookupApp {
+    fn call(&self, args: &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value> {
+        let project = string_arg(args, "project")?;
+        let name = string_arg(args, "name")?;
+        let target = string_arg(args, "target")?;
+
+        let apps = self.apps.read().unwrap();
+        return apps
+            .get(&(project.to_string(), name.to_string(), target.to_string()))
+            .cloned()
+            .ok_or_else(|| {
+                tera::Error::msg(format!(
+                    "lookup_app: no app {}/{} for target {}",
+                    project, name, target
+                ))
+            });
+    }
+}
+
+fn string_arg<'a>(
+    args: &'a HashMap<String, serde_json::Value>,
+    name: &str,
+) -> tera::Result<&'a str> {
+    return args
+        .get(name)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| tera::Error::msg(format!("lookup_app: missing string argument {}", name)));
+}
@@ -1,6 +1,6 @@
 use serde::Deserialize;
 use serde::Serialize;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, BTreeSet};
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
@@ -16,42 +16,109 @@ pub struct AppProject {
 pub struct AppProjectMetadata {
     pub name: String,
     pub namespace: String,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct AppProjectSpec {
-    pub destinations: HashSet<AppProjectDestination>,
-    pub cluster_resource_whitelist: HashSet<AppProjectClusterResourceWhitelist>,
-    pub source_repos: HashSet<String>,
+    pub destinations: BTreeSet<AppProjectDestination>,
+    pub cluster_resource_whitelist: BTreeSet<AppProjectClusterResourceWhitelist>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub cluster_resource_blacklist: BTreeSet<AppProjectClusterResourceWhitelist>,
+    pub source_repos: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub source_namespaces: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sync_windows: Vec<serde_json::Value>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub signature_keys: BTreeSet<AppProjectSignatureKey>,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, Eq, Hash)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, Eq, Hash, PartialOrd, Ord)]
 #[serde(rename_all = "camelCase")]
 pub struct AppProjectDestination {
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub name: String,
     pub namespace: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
     pub server: String,
 }
 
-#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, Eq, Hash)]
+#[derive(
+    Default,
+    Debug,
+    Clone,
+    PartialEq,
+    Serialize,
+    Deserialize,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    schemars::JsonSchema,
+)]
 #[serde(rename_all = "camelCase")]
+#[schemars(deny_unknown_fields)]
 pub struct AppProjectClusterResourceWhitelist {
     pub group: String,
     pub kind: String,
 }
 
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize, Eq, Hash, PartialOrd, Ord)]
+pub struct AppProjectSignatureKey {
+    #[serde(rename = "keyID")]
+    pub key_id: String,
+}
+
 impl AppProject {
     pub fn new(name: String, namespace: String) -> AppProject {
         return AppProject {
             api_version: "argoproj.io/v1alpha1".to_string(),
             kind: "AppProject".to_string(),
-            metadata: AppProjectMetadata { name, namespace },
+            metadata: AppProjectMetadata {
+                name,
+                namespace,
+                labels: BTreeMap::new(),
+                annotations: BTreeMap::new(),
+            },
             spec: AppProjectSpec {
-                destinations: HashSet::new(),
-                source_repos: HashSet::new(),
-                cluster_resource_whitelist: HashSet::new(),
+                destinations: BTreeSet::new(),
+                source_repos: BTreeSet::new(),
+                cluster_resource_whitelist: BTreeSet::new(),
+                cluster_resource_blacklist: BTreeSet::new(),
+                source_namespaces: BTreeSet::new(),
+                sync_windows: Vec::new(),
+                signature_keys: BTreeSet::new(),
             },
         };
     }
+
+    // removes destinations whose namespace is matched by a wildcard destination (eg. "*" or
+    // "team-*") on the same cluster
+    pub fn remove_covered_destinations(&mut self) {
+        let wildcards = self
+            .spec
+            .destinations
+            .iter()
+            .filter(|d| d.namespace.contains(['*', '?', '[']))
+            .filter_map(|d| {
+                glob::Pattern::new(&d.namespace)
+                    .ok()
+                    .map(|pattern| (d.clone(), pattern))
+            })
+            .collect::<Vec<_>>();
+
+        self.spec.destinations.retain(|d| {
+            !wildcards.iter().any(|(wildcard, pattern)| {
+                wildcard != d
+                    && wildcard.name == d.name
+                    && wildcard.server == d.server
+                    && pattern.matches(&d.namespace)
+            })
+        });
+    }
 }
@@ -0,0 +1,139 @@
+use anyhow::{anyhow, Result};
+use std::collections::HashMap;
+
+const SSM_PREFIX: &str = "ssm://";
+const SECRETS_MANAGER_PREFIX: &str = "aws-sm://";
+
+// replaces string vars which reference an aws value with the value, looked up with the aws cli so
+// its usual credential handling applies:
+//
+//   ssm://<parameter name>          the value of an SSM parameter, decrypted
+//   aws-sm://<secret id>            the string value of a Secrets Manager secret
+//
+// A #<key> suffix takes a key of a value holding a json object, eg. aws-sm://network#vpc_id.
+// Values end up in the rendered output so this is meant for centrally managed parameters like
+// account and vpc ids rather than secrets. Each parameter and secret is only looked up once per
+// run. Without the aws feature references are left as is with a warning
+#[derive(Default)]
+pub struct AwsVars {
+    resolved: HashMap<String, String>,
+}
+
+impl AwsVars {
+    pub fn resolve(&mut self, vars: &mut serde_json::Value) -> Result<()> {
+        match vars {
+            serde_json::Value::Object(map) => {
+                for value in map.values_mut() {
+                    self.resolve(value)?;
+                }
+            }
+            serde_json::Value::Array(values) => {
+                for value in values.iter_mut() {
+                    self.resolve(value)?;
+                }
+            }
+            serde_json::Value::String(reference) if is_reference(reference) => {
+                let (source, key) = match reference.split_once('#') {
+                    Some((source, key)) => (source, Some(key)),
+                    None => (reference.as_str(), None),
+                };
+                let value = match self.resolved.get(source) {
+                    Some(value) => value.clone(),
+                    None => match lookup(source)? {
+                        Some(value) => {
+                            self.resolved.insert(source.to_string(), value.clone());
+                            value
+                        }
+                        None => return Ok(()),
+                    },
+                };
+                let value = match key {
+                    Some(key) => json_key(source, &value, key)?,
+                    None => value,
+                };
+                *vars = serde_json::Value::String(value);
+            }
+            _ => (),
+        }
+        return Ok(());
+    }
+}
+
+fn json_key(source: &str, value: &str, key: &str) -> Result<String> {
+    let object: serde_json::Value =
+        serde_json::from_str(value).map_err(|e| anyhow!("{} doesn't hold json: {}", source, e))?;
+    return match &object[key] {
+        serde_json::Value::String(value) => Ok(value.clone()),
+        serde_json::Value::Null => Err(anyhow!("{} has no key {}", source, key)),
+        value => Ok(value.to_string()),
+    };
+}
+
+fn is_reference(value: &str) -> bool {
+    return value.starts_with(SSM_PREFIX) || value.starts_with(SECRETS_MANAGER_PREFIX);
+}
+
+#[cfg(feature = "aws")]
+fn lookup(reference: &str) -> Result<Option<String>> {
+    tracing::info!(reference, "resolving aws var");
+    match reference.strip_prefix(SSM_PREFIX) {
+        Some(name) => {
+            return aws(&[
+                "ssm",
+                "get-parameter",
+                "--name",
+                name,
+                "--with-decryption",
+                "--query",
+                "Parameter.Value",
+                "--output",
+                "text",
+            ])
+            .map(Some);
+        }
+        None => (),
+    }
+
+    // unwrap since only references are looked up
+    let secret_id = reference.strip_prefix(SECRETS_MANAGER_PREFIX).unwrap();
+    return aws(&[
+        "secretsmanager",
+        "get-secret-value",
+        "--secret-id",
+        secret_id,
+        "--query",
+        "SecretString",
+        "--output",
+        "text",
+    ])
+    .map(Some);
+}
+
+// a plain string which happens to look like a reference shouldn't fail builds without the feature
+#[cfg(not(feature = "aws"))]
+fn lookup(reference: &str) -> Result<Option<String>> {
+    tracing::warn!(
+        reference,
+        "leaving aws var reference as is, bargo was built without the aws feature"
+    );
+    return Ok(None);
+}
+
+#[cfg(feature = "aws")]
+fn aws(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("aws")
+        .args(args)
+        .output()
+        .map_err(|e| anyhow!("failed to run aws: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "aws {} failed: {}",
+            args[..2].join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    // the cli ends text output with a newline
+    return Ok(String::from_utf8_lossy(&output.stdout)
+        .trim_end_matches('\n')
+        .to_string());
+}
@@ -0,0 +1,85 @@
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local, Utc};
+use std::collections::HashMap;
+
+// env var pinning the clock when --frozen-time isn't given
+const FROZEN_TIME_ENV: &str = "BARGO_FROZEN_TIME";
+
+// the time templates see, either the real clock or a fixed time so output embedding timestamps
+// can be reproduced, eg. in golden file tests
+#[derive(Clone, Copy)]
+pub struct Clock {
+    frozen: Option<DateTime<Utc>>,
+}
+
+impl Clock {
+    // frozen_time (or BARGO_FROZEN_TIME) is an RFC 3339 timestamp or seconds since the unix epoch
+    pub fn new(frozen_time: Option<&str>) -> Result<Clock> {
+        let frozen_time = match frozen_time {
+            Some(frozen_time) => Some(frozen_time.to_string()),
+            None => std::env::var(FROZEN_TIME_ENV).ok(),
+        };
+        let frozen = match frozen_time {
+            Some(frozen_time) => Some(parse_time(&frozen_time)?),
+            None => None,
+        };
+        return Ok(Clock { frozen });
+    }
+
+    pub fn is_frozen(&self) -> bool {
+        return self.frozen.is_some();
+    }
+
+    pub fn now(&self) -> DateTime<Utc> {
+        return self.frozen.unwrap_or_else(Utc::now);
+    }
+}
+
+// replaces tera's now() with one reading the clock, taking the same timestamp and utc args
+impl tera::Function for Clock {
+    fn call(&self, args: &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value> {
+        let utc = bool_arg(args, "utc")?;
+        let timestamp = bool_arg(args, "timestamp")?;
+
+        let now = self.now();
+        if timestamp {
+            return Ok(serde_json::Value::from(now.timestamp()));
+        }
+        return match utc {
+            true => Ok(serde_json::Value::String(now.to_rfc3339())),
+            false => Ok(serde_json::Value::String(
+                now.with_timezone(&Local).to_rfc3339(),
+            )),
+        };
+    }
+}
+
+fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    match time.parse::<i64>() {
+        Ok(secs) => {
+            return DateTime::from_timestamp(secs, 0)
+                .ok_or_else(|| anyhow!("frozen time {:?} is out of range", time));
+        }
+        Err(_) => (),
+    }
+    return DateTime::parse_from_rfc3339(time)
+        .map(|time| time.with_timezone(&Utc))
+        .map_err(|e| {
+            anyhow!(
+                "invalid frozen time {:?}, expected an RFC 3339 timestamp or unix seconds: {}",
+                time,
+                e
+            )
+        });
+}
+
+fn bool_arg(args: &HashMap<String, serde_json::Value>, name: &str) -> tera::Result<bool> {
+    return match args.get(name) {
+        Some(serde_json::Value::Bool(value)) => Ok(*value),
+        Some(value) => Err(tera::Error::msg(format!(
+            "Function `now` received {}={} but `{}` can only be a boolean",
+            name, value, name
+        ))),
+        None => Ok(false),
+    };
+}
@@ -0,0 +1,163 @@
+use std::{fmt, io::IsTerminal};
+use tracing::{
+    field::{Field, Visit},
+    span, Event, Level, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{EnvFilter, LevelFilter},
+    fmt::{format::Writer, writer::BoxMakeWriter, FmtContext, FormatEvent, FormatFields},
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Pretty when writing to a terminal and full otherwise
+    Auto,
+    /// Short, colored lines without timestamps, prefixed with the app they're about
+    Pretty,
+    /// Tracing's full format with timestamps and spans
+    Full,
+    /// One json object per line
+    Json,
+}
+
+// info by default, -v/-vv for debug/trace and -q/-qq for warn/error. RUST_LOG directives are
+// applied over it, eg. RUST_LOG=bargo::processor=trace
+pub fn filter(verbose: u8, quiet: u8) -> EnvFilter {
+    let level = match (verbose, quiet) {
+        (0, 0) => LevelFilter::INFO,
+        (1, _) => LevelFilter::DEBUG,
+        (_, 0) => LevelFilter::TRACE,
+        (_, 1) => LevelFilter::WARN,
+        _ => LevelFilter::ERROR,
+    };
+    return EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
+}
+
+// the log layer writing to stderr or stdout in the given format
+pub fn layer<S>(format: LogFormat, stderr: bool) -> Box<dyn Layer<S> + Send + Sync>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    let (writer, is_terminal) = match stderr {
+        true => (
+            BoxMakeWriter::new(std::io::stderr),
+            std::io::stderr().is_terminal(),
+        ),
+        false => (
+            BoxMakeWriter::new(std::io::stdout),
+            std::io::stdout().is_terminal(),
+        ),
+    };
+    let format = match format {
+        LogFormat::Auto if is_terminal => LogFormat::Pretty,
+        LogFormat::Auto => LogFormat::Full,
+        format => format,
+    };
+
+    let layer = tracing_subscriber::fmt::layer().with_writer(writer);
+    return match format {
+        LogFormat::Pretty => AppLabelLayer
+            .and_then(layer.event_format(PrettyFormat).with_ansi(is_terminal))
+            .boxed(),
+        LogFormat::Json => layer.json().boxed(),
+        _ => layer.boxed(),
+    };
+}
+
+// the target and name of the app an app span is about, eg. "staging web/frontend"
+struct AppLabel(String);
+
+// stores an AppLabel in the extensions of app spans for PrettyFormat
+struct AppLabelLayer;
+
+impl<S> Layer<S> for AppLabelLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        if attrs.metadata().name() != "app" {
+            return;
+        }
+        let mut visitor = AppLabelVisitor::default();
+        attrs.record(&mut visitor);
+        match ctx.span(id) {
+            Some(span) => span
+                .extensions_mut()
+                .insert(AppLabel(visitor.values.join(" "))),
+            None => (),
+        }
+    }
+}
+
+// collects the values of the target and app fields in the order they're recorded
+#[derive(Default)]
+struct AppLabelVisitor {
+    values: Vec<String>,
+}
+
+impl Visit for AppLabelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if matches!(field.name(), "target" | "app") {
+            self.values.push(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if matches!(field.name(), "target" | "app") {
+            self.values.push(format!("{:?}", value));
+        }
+    }
+}
+
+// a line per event without timestamps or span context, prefixed with the target and name of the
+// app it's about. Warnings and errors are labelled and colored
+struct PrettyFormat;
+
+impl<S, N> FormatEvent<S, N> for PrettyFormat
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let ansi = writer.has_ansi_escapes();
+        let paint = |code: &str, text: &str| match ansi {
+            true => format!("\x1b[{}m{}\x1b[0m", code, text),
+            false => text.to_string(),
+        };
+
+        match *event.metadata().level() {
+            Level::ERROR => write!(writer, "{} ", paint("1;31", "error:"))?,
+            Level::WARN => write!(writer, "{} ", paint("1;33", "warning:"))?,
+            Level::INFO => (),
+            level => write!(
+                writer,
+                "{} ",
+                paint("2", &format!("{}:", level.as_str().to_lowercase()))
+            )?,
+        }
+
+        let app_span = ctx
+            .event_scope()
+            .and_then(|scope| scope.from_root().find(|span| span.name() == "app"));
+        match app_span {
+            Some(span) => match span.extensions().get::<AppLabel>() {
+                Some(AppLabel(label)) => write!(writer, "{} ", paint("36", label))?,
+                None => (),
+            },
+            None => (),
+        }
+
+        ctx.field_format().format_fields(writer.by_ref(), event)?;
+        return writeln!(writer);
+    }
+}
@@ -0,0 +1,59 @@
+use anyhow::anyhow;
+use std::{fmt::Write, path};
+
+// formats a toml parse error like a compiler diagnostic, showing the offending line of the file
+// with a caret under the column and a hint for common mistakes, eg.
+//
+// failed to parse "bargo.toml": unknown field `aplication_template`, expected ...
+//   --> bargo.toml:1:1
+//    |
+//  1 | aplication_template = "application.yaml.tera"
+//    | ^
+//    = hint: check the key for typos
+pub fn toml_error(file: &path::Path, contents: &str, error: &toml::de::Error) -> anyhow::Error {
+    // the position is shown separately so drop it from the message
+    let message = error.to_string();
+    let message = match message.rfind(" at line ") {
+        Some(i) if error.line_col().is_some() => &message[..i],
+        _ => message.as_str(),
+    };
+
+    let mut diagnostic = format!("failed to parse {:?}: {}", file, message);
+    if let Some((line, col)) = error.line_col() {
+        let source_line = contents.lines().nth(line).unwrap_or_default();
+        let line_number = (line + 1).to_string();
+        let gutter = " ".repeat(line_number.len());
+
+        write!(
+            diagnostic,
+            "\n{gutter} --> {}:{}:{}\n{gutter} |\n{line_number} | {}\n{gutter} | {}^",
+            file.display(),
+            line + 1,
+            col + 1,
+            source_line,
+            " ".repeat(col),
+        )
+        .unwrap();
+    }
+
+    if let Some(hint) = hint(message) {
+        write!(diagnostic, "\n  = hint: {}", hint).unwrap();
+    }
+    return anyhow!(diagnostic);
+}
+
+fn hint(message: &str) -> Option<&'static str> {
+    if message.contains("unknown field") {
+        return Some("check the key for typos, the expected keys are listed above");
+    }
+    if message.contains("missing field") {
+        return Some("add the missing key, it is required");
+    }
+    if message.contains("invalid type") {
+        return Some("check the value's type, strings need quotes and lists use [...]");
+    }
+    if message.contains("duplicate key") {
+        return Some("each key can only be set once per table");
+    }
+    return None;
+}
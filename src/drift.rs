@@ -0,0 +1,119 @@
+use anyhow::{anyhow, Result};
+use serde::Deserialize;
+use std::{collections::BTreeSet, fs, path};
+
+use crate::{
+    snapshot::{list_files, render_to_temp_dir},
+    Args,
+};
+
+const ARGOCD_KINDS: [&str; 2] = ["Application", "AppProject"];
+
+// renders the input and compares the generated Applications and AppProjects with the ones in the
+// argocd namespace of the current kubectl context, failing if either side has resources the other
+// doesn't. Select the targets deployed to the cluster with --target
+pub fn drift(args: Args, context: Option<&str>) -> Result<()> {
+    let (project_processor, _output_dir) = render_to_temp_dir(args, &[])?;
+
+    let mut generated = BTreeSet::new();
+    for target_name in project_processor.processed_targets() {
+        generated.extend(argocd_resources(
+            &project_processor
+                .output_path()
+                .join(&target_name)
+                .join("argocd-config"),
+        )?);
+    }
+
+    let live = list_live_resources(project_processor.argocd_namespace(), context)?;
+
+    let mut drifted = false;
+    for (kind, name) in live.difference(&generated) {
+        println!("not generated: {} {}", kind, name);
+        drifted = true;
+    }
+    for (kind, name) in generated.difference(&live) {
+        println!("not in cluster: {} {}", kind, name);
+        drifted = true;
+    }
+
+    if drifted {
+        return Err(anyhow!(
+            "the argocd resources in namespace {} don't match the generated output",
+            project_processor.argocd_namespace()
+        ));
+    }
+    println!(
+        "{} argocd resources in namespace {} match the generated output",
+        live.len(),
+        project_processor.argocd_namespace()
+    );
+    return Ok(());
+}
+
+// (kind, name) of every Application and AppProject in a generated argocd-config dir
+pub fn argocd_resources(config_dir: &path::Path) -> Result<BTreeSet<(String, String)>> {
+    let mut resources = BTreeSet::new();
+    for file in list_files(config_dir)? {
+        if file.extension().unwrap_or_default() != "yaml" {
+            continue;
+        }
+        let contents = fs::read_to_string(config_dir.join(&file))?;
+        for document in serde_yaml::Deserializer::from_str(&contents) {
+            let document = serde_yaml::Value::deserialize(document)?;
+            match (
+                document["kind"].as_str(),
+                document["metadata"]["name"].as_str(),
+            ) {
+                (Some(kind), Some(name)) if ARGOCD_KINDS.contains(&kind) => {
+                    resources.insert((kind.to_string(), name.to_string()));
+                }
+                _ => (),
+            }
+        }
+    }
+    return Ok(resources);
+}
+
+// (kind, name) of every Application and AppProject in the namespace, listed with kubectl
+fn list_live_resources(
+    namespace: &str,
+    context: Option<&str>,
+) -> Result<BTreeSet<(String, String)>> {
+    let mut command = std::process::Command::new("kubectl");
+    match context {
+        Some(context) => {
+            command.args(["--context", context]);
+        }
+        None => (),
+    }
+    let output = command
+        .args([
+            "get",
+            "applications.argoproj.io,appprojects.argoproj.io",
+            "--namespace",
+            namespace,
+            "--output",
+            "json",
+        ])
+        .output()
+        .map_err(|e| anyhow!("failed to run kubectl, drift needs it on the PATH: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "failed to list argocd resources: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+
+    let list: serde_json::Value = serde_json::from_slice(&output.stdout)?;
+    let mut resources = BTreeSet::new();
+    for item in list["items"].as_array().into_iter().flatten() {
+        match (item["kind"].as_str(), item["metadata"]["name"].as_str()) {
+            (Some(kind), Some(name)) => {
+                resources.insert((kind.to_string(), name.to_string()));
+            }
+            _ => (),
+        }
+    }
+    return Ok(resources);
+}
@@ -0,0 +1,101 @@
+use anyhow::{anyhow, Result};
+use std::{fs, path};
+use tracing::info;
+
+use crate::{snapshot::list_files, HelmChartOptions};
+
+// directory in each target's output the chart is written to
+pub const CHART_DIR_NAME: &str = "helm-chart";
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Chart {
+    api_version: &'static str,
+    name: String,
+    description: String,
+    #[serde(rename = "type")]
+    chart_type: &'static str,
+    version: String,
+}
+
+// wraps a target's generated argocd-config, and optionally its apps' manifests, in a helm chart
+// with every yaml file as a template. The files are already rendered so anything helm would
+// treat as a template action is escaped
+pub fn write_chart(
+    target_dir: &path::Path,
+    target_name: &str,
+    options: &HelmChartOptions,
+) -> Result<()> {
+    let chart_dir = target_dir.join(CHART_DIR_NAME);
+    if chart_dir.exists() {
+        return Err(anyhow!(
+            "can't write helm chart for target {} as {:?} already exists, is there a project named {}?",
+            target_name,
+            chart_dir,
+            CHART_DIR_NAME
+        ));
+    }
+    let templates_dir = chart_dir.join("templates");
+
+    let files = list_files(target_dir)?;
+    for file in files.iter() {
+        if !is_chart_manifest(file, &files, options.include_apps.unwrap_or(false)) {
+            continue;
+        }
+        let to_path = templates_dir.join(file);
+        match to_path.parent() {
+            Some(parent) => fs::create_dir_all(parent)?,
+            None => (),
+        }
+        let contents = fs::read_to_string(target_dir.join(file))
+            .map_err(|e| anyhow!("failed to read {:?} for the helm chart: {}", file, e))?;
+        fs::write(&to_path, escape_template_actions(&contents))?;
+    }
+
+    let chart = Chart {
+        api_version: "v2",
+        name: options
+            .name
+            .clone()
+            .unwrap_or_else(|| target_name.to_string()),
+        description: format!(
+            "argocd config for target {} generated by bargo",
+            target_name
+        ),
+        chart_type: "application",
+        version: options
+            .version
+            .clone()
+            .unwrap_or_else(|| "0.1.0".to_string()),
+    };
+    fs::write(chart_dir.join("Chart.yaml"), serde_yaml::to_string(&chart)?)?;
+    info!(target = target_name, ?chart_dir, "wrote helm chart");
+    return Ok(());
+}
+
+// yaml files in argocd-config (except the inventory, which isn't a manifest) and, when including
+// apps, the yaml files of apps without a helm chart of their own since those apps' files are chart
+// sources and values rather than manifests
+fn is_chart_manifest(file: &path::Path, files: &[path::PathBuf], include_apps: bool) -> bool {
+    let extension = file.extension().unwrap_or_default();
+    if extension != "yaml" && extension != "yml" {
+        return false;
+    }
+    if file.starts_with("argocd-config") {
+        return file != path::Path::new("argocd-config/inventory.yaml");
+    }
+    if !include_apps {
+        return false;
+    }
+    // apps are written to <project>/<app>
+    let app_dir = file.components().take(2).collect::<path::PathBuf>();
+    return !files
+        .iter()
+        .any(|f| f.starts_with(&app_dir) && f.file_name().unwrap_or_default() == "Chart.yaml");
+}
+
+// helm renders every file under templates/, so template delimiters in the generated files are
+// replaced with actions printing them literally
+fn escape_template_actions(contents: &str) -> String {
+    return contents.replace("{{", "{{ \"{{\" }}");
+}
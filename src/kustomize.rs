@@ -0,0 +1,200 @@
+use anyhow::{anyhow, Result};
+use std::{fs, path};
+use tracing::info;
+
+// directory in a kustomize app's output the generated component is written to
+const COMPONENT_DIR_NAME: &str = "bargo-patch";
+// var holding the images, replicas and labels set by the component
+const VARS_KEY: &str = "kustomize";
+
+const KUSTOMIZATION_FILE_NAMES: [&str; 3] =
+    ["kustomization.yaml", "kustomization.yml", "Kustomization"];
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Component {
+    api_version: &'static str,
+    kind: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    images: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    replicas: Option<serde_json::Value>,
+    labels: Vec<Labels>,
+}
+
+#[derive(serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Labels {
+    pairs: serde_json::Map<String, serde_json::Value>,
+    include_selectors: bool,
+}
+
+// writes a kustomize component to the app's output setting the images, replicas and labels from
+// the app's kustomize var, eg.
+//
+//   [targets.vars.kustomize]
+//   images = [{ name = "app", newTag = "v2" }]
+//   replicas = [{ name = "app", count = 3 }]
+//   labels = { team = "payments" }
+//
+// and adds it to the components of the app's kustomization, editing the file's text so comments
+// and formatting are kept. The bargo.dev/target label is always set. Labels aren't added to
+// selectors since those can't change on existing workloads. Apps without a kustomization file are
+// left alone
+pub fn write_patch(
+    out_dir: &path::Path,
+    target_name: &str,
+    vars: &serde_json::Value,
+) -> Result<()> {
+    let kustomization_path = match KUSTOMIZATION_FILE_NAMES
+        .iter()
+        .map(|name| out_dir.join(name))
+        .find(|path| path.exists())
+    {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+
+    let options = &vars[VARS_KEY];
+    let list = |key: &str| match &options[key] {
+        serde_json::Value::Null => Ok(None),
+        value @ serde_json::Value::Array(_) => Ok(Some(value.clone())),
+        _ => Err(anyhow!("var {}.{} must be an array", VARS_KEY, key)),
+    };
+    let mut pairs = serde_json::Map::new();
+    match &options["labels"] {
+        serde_json::Value::Null => (),
+        serde_json::Value::Object(labels) => pairs.extend(labels.clone()),
+        _ => return Err(anyhow!("var {}.labels must be a table", VARS_KEY)),
+    }
+    pairs.insert(
+        "bargo.dev/target".to_string(),
+        serde_json::Value::String(target_name.to_string()),
+    );
+
+    let component = Component {
+        api_version: "kustomize.config.k8s.io/v1alpha1",
+        kind: "Component",
+        images: list("images")?,
+        replicas: list("replicas")?,
+        labels: vec![Labels {
+            pairs,
+            include_selectors: false,
+        }],
+    };
+    let component_dir = out_dir.join(COMPONENT_DIR_NAME);
+    fs::create_dir_all(&component_dir)?;
+    fs::write(
+        component_dir.join("kustomization.yaml"),
+        serde_yaml::to_string(&component)?,
+    )?;
+
+    let contents = fs::read_to_string(&kustomization_path)?;
+    let kustomization: serde_yaml::Value = serde_yaml::from_str(&contents)
+        .map_err(|e| anyhow!("failed to parse {:?}: {}", kustomization_path, e))?;
+    if !kustomization.is_mapping() {
+        return Err(anyhow!("{:?} isn't a yaml mapping", kustomization_path));
+    }
+    let contents = match &kustomization["components"] {
+        serde_yaml::Value::Null if !has_components_key(&contents) => {
+            let separator = match contents.is_empty() || contents.ends_with('\n') {
+                true => "",
+                false => "\n",
+            };
+            format!(
+                "{}{}components:\n- {}\n",
+                contents, separator, COMPONENT_DIR_NAME
+            )
+        }
+        serde_yaml::Value::Null | serde_yaml::Value::Sequence(_) => add_component(&contents).ok_or_else(|| {
+            anyhow!(
+                "can't add {} to the components of {:?}, write components as a block or single line list",
+                COMPONENT_DIR_NAME,
+                kustomization_path
+            )
+        })?,
+        _ => {
+            return Err(anyhow!(
+                "components in {:?} isn't a list",
+                kustomization_path
+            ))
+        }
+    };
+    fs::write(&kustomization_path, contents)?;
+    info!(?out_dir, "wrote kustomize patch");
+    return Ok(());
+}
+
+fn is_components_key(line: &str) -> bool {
+    return line
+        .strip_prefix("components:")
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with([' ', '\t', '\r', '\n', '#']));
+}
+
+fn has_components_key(contents: &str) -> bool {
+    return contents.lines().any(is_components_key);
+}
+
+// adds the component to the existing top level components list, written either as a block list
+// or as a flow list on the key's line. None for other layouts
+fn add_component(contents: &str) -> Option<String> {
+    let lines = contents.split_inclusive('\n').collect::<Vec<_>>();
+    let key_line = lines.iter().position(|line| is_components_key(line))?;
+    let value = lines[key_line]["components:".len()..].trim();
+    let value = match value.find(" #") {
+        Some(comment) => value[..comment].trim_end(),
+        None => value,
+    };
+
+    let flow_items = match value {
+        "" => None,
+        "null" | "~" => Some(""),
+        value if value.starts_with('[') && value.ends_with(']') => {
+            Some(value[1..value.len() - 1].trim())
+        }
+        _ => return None,
+    };
+    if let Some(items) = flow_items {
+        let list = match items.is_empty() {
+            true => format!("[{}]", COMPONENT_DIR_NAME),
+            false => format!("[{}, {}]", items, COMPONENT_DIR_NAME),
+        };
+        let mut lines = lines
+            .iter()
+            .map(|line| line.to_string())
+            .collect::<Vec<_>>();
+        lines[key_line] = lines[key_line].replacen(value, &list, 1);
+        return Some(lines.concat());
+    }
+
+    // the list's items, and anything nested in them, run until the next top level key
+    let mut item_indent = None;
+    let mut last_item_line = key_line;
+    for (i, line) in lines.iter().enumerate().skip(key_line + 1) {
+        let trimmed = line.trim_start();
+        if trimmed.is_empty() || trimmed.starts_with('#') {
+            continue;
+        }
+        let indent = line.len() - trimmed.len();
+        if indent == 0 && !trimmed.starts_with('-') {
+            break;
+        }
+        if trimmed.starts_with('-') && item_indent.is_none() {
+            item_indent = Some(indent);
+        }
+        last_item_line = i;
+    }
+    let item = format!(
+        "{}- {}\n",
+        " ".repeat(item_indent.unwrap_or(0)),
+        COMPONENT_DIR_NAME
+    );
+
+    let mut contents = lines[..=last_item_line].concat();
+    if !contents.ends_with('\n') {
+        contents.push('\n');
+    }
+    contents.push_str(&item);
+    contents.push_str(&lines[last_item_line + 1..].concat());
+    return Some(contents);
+}
@@ -0,0 +1,90 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, path,
+};
+use tracing::info;
+
+use crate::prune::removed;
+
+// written next to bargo.toml, meant to be committed along with the apps
+pub const LOCKFILE_NAME: &str = "bargo.lock";
+
+const HEADER: &str = "# generated by bargo, lists the applications generated for each target. \
+Removing any of them needs --allow-prune or a tombstoned metadata file\n\n";
+
+#[derive(serde::Serialize, serde::Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct LockEntry {
+    // <project>/<app name>
+    pub application: String,
+    // relative to the app's input path
+    pub metadata_file: String,
+}
+
+// the applications generated by the last run for each target, used to catch a bad glob or a
+// deleted folder silently removing apps
+#[derive(serde::Serialize, serde::Deserialize, Default, Debug)]
+pub struct Lockfile {
+    #[serde(default)]
+    targets: BTreeMap<String, Vec<LockEntry>>,
+}
+
+impl Lockfile {
+    // an empty lockfile when the input path doesn't have one yet
+    pub fn read(input_path: &path::Path) -> Result<Lockfile> {
+        let path = input_path.join(LOCKFILE_NAME);
+        if !path.exists() {
+            return Ok(Lockfile::default());
+        }
+        let contents = fs::read_to_string(&path)?;
+        return toml::from_str(&contents).map_err(|e| anyhow!("failed to parse {:?}: {}", path, e));
+    }
+
+    pub fn write(&self, input_path: &path::Path) -> Result<()> {
+        let path = input_path.join(LOCKFILE_NAME);
+        fs::write(&path, format!("{}{}", HEADER, toml::to_string(self)?))
+            .map_err(|e| anyhow!("failed to write {:?}: {}", path, e))?;
+        info!(?path, "wrote lockfile");
+        return Ok(());
+    }
+
+    // replaces the entries of each target in generated, keeping the entries in_scope returns
+    // false for (eg. apps outside the path filters) and the entries of configured targets which
+    // weren't generated. Targets which are no longer configured lose their in scope entries.
+    // Returns the (target, entry) of every in scope entry which is no longer generated
+    pub fn update(
+        &mut self,
+        generated: BTreeMap<String, Vec<LockEntry>>,
+        configured_targets: &[String],
+        in_scope: impl Fn(&LockEntry) -> bool,
+    ) -> Vec<(String, LockEntry)> {
+        let previous = self
+            .targets
+            .iter()
+            .map(|(target_name, entries)| {
+                let entries = entries.iter().filter(|entry| in_scope(entry)).cloned();
+                (target_name.clone(), entries.collect::<BTreeSet<_>>())
+            })
+            .collect();
+        let generated_sets = generated
+            .iter()
+            .map(|(target_name, entries)| (target_name.clone(), entries.iter().cloned().collect()))
+            .collect();
+        let removed = removed(&previous, &generated_sets, configured_targets);
+
+        self.targets.retain(|target_name, entries| {
+            let configured = configured_targets.contains(target_name);
+            if generated.contains_key(target_name) || !configured {
+                entries.retain(|entry| !in_scope(entry));
+            }
+            return configured || !entries.is_empty();
+        });
+        for (target_name, entries) in generated {
+            let target_entries = self.targets.entry(target_name).or_default();
+            target_entries.extend(entries);
+            target_entries.sort();
+            target_entries.dedup();
+        }
+        return removed;
+    }
+}
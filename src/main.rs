@@ -1,71 +1,755 @@
+// the code base returns explicitly and matches single arms rather than using if let, which clippy
+// flags by default
+#![allow(clippy::needless_return, clippy::single_match)]
+mod app_files;
+mod app_lookup;
 mod app_project;
+mod aws_vars;
+mod clock;
+mod console;
+mod diagnostics;
+mod drift;
+mod helm_chart;
+mod kustomize;
+mod lockfile;
+mod oci;
+#[cfg(feature = "operator")]
+mod operator;
+mod ownership_report;
+mod plugin;
 mod processor;
+mod prune;
+mod registry;
+mod scripting;
+mod server;
+mod snapshot;
+mod stable_ids;
+#[cfg(feature = "otel")]
+mod telemetry;
+mod timing;
+mod upload;
+mod webhook;
 use anyhow::Result;
-use clap::Parser;
+use clap::{CommandFactory, Parser, Subcommand};
 use processor::ProjectProcessor;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path,
+};
 use tracing::error;
-use tracing_subscriber;
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 pub struct Args {
-    #[arg(short, long)]
-    input_path: Option<String>,
+    #[command(subcommand)]
+    command: Option<Command>,
+    /// Directory holding bargo.toml and the apps, defaults to the current directory. Can be
+    /// repeated to merge the apps of several directories into one output, the first one's
+    /// bargo.toml configures the run
+    #[arg(short, long, global = true)]
+    input_path: Vec<String>,
     #[arg(short, long)]
     output_path: Option<String>,
+    /// Max number of files copied/templated and written concurrently, defaults to the number of cpus
+    #[arg(short, long)]
+    jobs: Option<usize>,
+    /// Keep the temporary output directory used when no output path is given after uploading it
+    /// with --upload-url. Without an upload it's always kept
+    #[arg(long)]
+    keep_output: bool,
+    /// Only process the given targets, can be repeated. Defaults to all targets
+    #[arg(short, long = "target", global = true)]
+    targets: Vec<String>,
+    /// Override a var for every app, eg. --var image.tag=v2. Dotted keys set nested vars and values
+    /// are parsed as json when valid, otherwise used as strings. Can be repeated. Overrides are
+    /// merged on top of all other vars
+    #[arg(long = "var", value_name = "KEY=VALUE", global = true)]
+    vars: Vec<String>,
+    /// JSON object deep merged over every app's vars, eg. '{"image": {"tag": "abc123"}}'. Can be
+    /// repeated, --var overrides are applied after these
+    #[arg(long, value_name = "JSON", global = true)]
+    set_json: Vec<String>,
+    /// JSON object deep merged over the application options of every generated Application. Can
+    /// be repeated
+    #[arg(long, value_name = "JSON", global = true)]
+    set_application_options_json: Vec<String>,
+    /// Log more, -v for debug and -vv for trace logs. RUST_LOG directives are applied on top, eg.
+    /// RUST_LOG=bargo::processor=trace
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Log less, -q for only warnings and errors and -qq for only errors
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+    /// How logs are written
+    #[arg(long, value_enum, default_value_t = console::LogFormat::Auto, global = true)]
+    log_format: console::LogFormat,
+    /// Apply the named [profiles.<name>] section of bargo.toml over the rest of the config
+    #[arg(long, global = true)]
+    profile: Option<String>,
+    /// Only process apps whose directory (relative to its input path) matches the glob, eg.
+    /// "teams/payments/**". Can be repeated. The argocd-config output only holds the matching apps
+    #[arg(long, value_name = "GLOB", global = true)]
+    path_filter: Vec<String>,
+    /// Pin the time returned by the now() template function, and used for the generated-at
+    /// provenance annotation, to an RFC 3339 timestamp or unix seconds so output embedding it can
+    /// be reproduced. Defaults to the BARGO_FROZEN_TIME env var
+    #[arg(long, value_name = "TIME", global = true)]
+    frozen_time: Option<String>,
+    /// Write the generated argocd-config documents to stdout as a single yaml stream instead of
+    /// writing the output tree
+    #[arg(long)]
+    stdout: bool,
+    /// Reuse the previous output of apps whose inputs haven't changed since the last run instead of
+    /// templating them and running their scripts again
+    #[arg(long)]
+    cache: bool,
+    /// Report how long each phase took per target and app, listing the slowest N apps (default 10)
+    #[arg(long, value_name = "N", num_args = 0..=1, default_missing_value = "10")]
+    timing: Option<usize>,
+    /// Continue past failing apps and report every failure at the end instead of stopping at the
+    /// first one. No output is written if any app failed
+    #[arg(long)]
+    keep_going: bool,
+    /// Skip (with a warning) metadata targets which aren't defined in bargo.toml instead of failing
+    #[arg(long)]
+    allow_unknown_targets: bool,
+    /// OTLP/HTTP endpoint to export traces to, eg. http://localhost:4318/v1/traces. Traces are
+    /// also exported when the standard OTEL_EXPORTER_OTLP_ENDPOINT env var is set
+    #[cfg(feature = "otel")]
+    #[arg(long)]
+    otlp_endpoint: Option<String>,
+    /// Upload each target's generated output to <URL>/<target> in object storage (s3:// or gs://)
+    /// using the aws or gcloud cli, after writing it to the output path
+    #[arg(long, value_name = "URL", conflicts_with = "stdout")]
+    upload_url: Option<String>,
+    /// Server side encryption for s3 uploads, eg. AES256 or aws:kms
+    #[arg(long, value_name = "ALGORITHM", requires = "upload_url")]
+    upload_sse: Option<String>,
+    /// Write the contexts each app's application and files are rendered with to .bargo-context.json
+    /// in its output dir. When rendering fails they're left in the output path's .bargo-staging
+    /// dir
+    #[arg(long)]
+    debug_context: bool,
+    /// Write a report of every application's owner, team, cost center, project, targets and
+    /// namespaces to PATH, as csv when it ends with .csv and json otherwise
+    #[arg(long, value_name = "PATH")]
+    ownership_report: Option<std::path::PathBuf>,
+    /// Allow removing applications listed in bargo.lock which are no longer generated, without
+    /// tombstoning their metadata files first
+    #[arg(long)]
+    allow_prune: bool,
+    // directories within the input path which must not be searched for apps or templates
+    #[arg(skip)]
+    extra_ignore_dirs: Vec<std::path::PathBuf>,
+}
+
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Render into a temporary directory and compare the result against the expected output,
+    /// showing a diff of every mismatch
+    Test {
+        /// Directory holding the expected output for each target, defaults to expected/ in the
+        /// input path
+        #[arg(long)]
+        expected_path: Option<String>,
+        /// Replace the expected output of mismatched targets with the generated output
+        #[arg(long)]
+        update_snapshots: bool,
+    },
+    /// Render into a temporary directory and fail if the result differs from the already
+    /// generated output, printing every differing path
+    Verify {
+        /// Directory holding the committed output
+        output_path: String,
+    },
+    /// Render into a temporary directory and push each target's output as an OCI artifact to
+    /// <registry>/<repository>/<target>:<tag> using the oras cli
+    Push {
+        /// Where to push the artifacts, eg. oci://ghcr.io/example/rendered:v1
+        #[arg(long, value_name = "REFERENCE")]
+        oci: String,
+    },
+    /// Render into a temporary directory and list the Applications and AppProjects in the
+    /// previous output which would no longer be generated, failing if there are any. Targets
+    /// in the previous output which are no longer configured count as removed
+    PruneCheck {
+        /// Directory holding the previous output, eg. a checkout of the committed output. Without
+        /// it the applications listed in the committed bargo.lock are checked instead
+        output_path: Option<String>,
+        /// Accept output which removes applications or projects
+        #[arg(long)]
+        confirm_prune: bool,
+    },
+    /// Render into a temporary directory and compare the generated Applications and AppProjects
+    /// with the ones in the argocd namespace of the cluster, listing any that only exist on one
+    /// side. Use --target to select the targets deployed to the cluster. Lists the cluster's
+    /// resources with kubectl, which needs to be on the PATH with read access to the argocd
+    /// namespace
+    Drift {
+        /// kubectl context to use, defaults to the current context
+        #[arg(long)]
+        context: Option<String>,
+    },
+    /// Print the merged vars, application options and template context an app is rendered with
+    /// for each of its targets, showing whether each var and option was set globally, by the
+    /// target or by the app. Use --target to select targets
+    Explain {
+        /// Directory of the app, containing its metadata file
+        app_dir: String,
+    },
+    /// Print the targets and apps whose output may have changed since a git ref as a json array,
+    /// judged from the changed files without rendering. Use --target and --path-filter to narrow
+    /// the apps considered
+    Affected {
+        /// Git ref to compare the working tree against, eg. origin/main
+        #[arg(long, value_name = "REF")]
+        changed_since: String,
+    },
+    /// Render a temporary preview target, a copy of a base target under a new name, eg. for a pull
+    /// request. Apps are rendered with their settings for the base target, use --path-filter to
+    /// select apps and --var to override vars. Generated Applications and AppProjects are
+    /// annotated with the preview name and when it expires for a cleanup controller to prune
+    Preview {
+        /// Name of the preview target, eg. pr-1234
+        #[arg(long)]
+        name: String,
+        /// Target the preview is a copy of
+        #[arg(long)]
+        base: String,
+        /// How long the preview lives, eg. 30m, 72h or 7d
+        #[arg(long, default_value = "72h")]
+        ttl: String,
+    },
+    /// Print a shell completion script, eg. bargo completions zsh > ~/.zfunc/_bargo
+    Completions {
+        /// Shell to generate the completions for
+        shell: clap_complete::Shell,
+    },
+    /// Print the man page, or write one for bargo and each of its subcommands into a directory
+    Manpage {
+        /// Directory to write bargo.1 and the bargo-<subcommand>.1 pages to
+        #[arg(long)]
+        out_dir: Option<String>,
+    },
+    /// Print the JSON Schema of bargo.toml or of the metadata files, for editor autocomplete and
+    /// validation (eg. with taplo or the vscode even better toml extension)
+    Schema {
+        /// File to print the schema of
+        #[arg(value_enum)]
+        file: SchemaFile,
+    },
+    /// Listen for github and gitlab push webhooks, rendering the pushed repo each time its branch
+    /// is pushed to
+    Server {
+        /// Address to listen on
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+        /// Server config listing the repos to render
+        #[arg(long, default_value = "bargo-server.toml")]
+        config: String,
+    },
+    /// Run as a kubernetes controller rendering PreprocessorRepo resources into a git branch or
+    /// applying them to the cluster
+    #[cfg(feature = "operator")]
+    Operator {
+        /// Directory holding the source and destination checkouts between runs
+        #[arg(long, default_value = "/tmp/bargo-operator")]
+        work_dir: String,
+        /// Print the PreprocessorRepo CustomResourceDefinition and exit
+        #[arg(long)]
+        print_crd: bool,
+    },
+}
+
+#[derive(clap::ValueEnum, Debug, Clone, Copy)]
+enum SchemaFile {
+    Config,
+    Metadata,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct Config {
     application_template: String,
     argocd_namespace: String,
     argocd_source_repo: String,
     targets: Vec<ConfigTarget>,
     vars: Option<serde_json::Value>,
+    /// yaml, json or toml files (relative to the input path) or https urls merged in order into the
+    /// global vars, with vars merged on top. Urls are fetched with curl on every run
+    vars_files: Option<Vec<VarsFile>>,
     default_application_options: Option<serde_json::Value>,
     argocd_config_application_options: Option<serde_json::Value>,
+    /// globs (relative to the input path) of directories to skip when looking for metadata and
+    /// template files, eg. "docs/**"
+    ignore_dirs: Option<Vec<String>>,
+    /// search hidden directories (eg. .git or .direnv) for metadata and template files, they're
+    /// skipped by default
+    include_hidden_dirs: Option<bool>,
+    /// how many directories below the input path app dirs can be, eg. 2 for teams/web/metadata.toml.
+    /// Deeper directories aren't searched for metadata files, unlimited by default
+    max_depth: Option<usize>,
+    /// name of the per app metadata file, defaults to metadata.toml
+    metadata_file_name: Option<String>,
+    copy_strategy: Option<CopyStrategy>,
+    /// fail on unknown keys in bargo.toml and metadata files instead of only warning about them
+    deny_unknown_fields: Option<bool>,
+    /// what to do when an app's generated output has no manifests, defaults to warn
+    on_empty_app: Option<EmptyAppAction>,
+    /// write an inventory.yaml listing every application into each target's argocd-config dir. The
+    /// argocd-config application should exclude it (eg. with directory.exclude) since it isn't a
+    /// kubernetes manifest
+    write_inventory: Option<bool>,
+    /// default for the metadata option of the same name
+    write_generated_values: Option<bool>,
+    /// default for the metadata option of the same name
+    kustomize_patch: Option<bool>,
+    /// separator used to join the source_dir and output_path template context values, defaults to /
+    path_separator: Option<String>,
+    /// render metadata files with tera before parsing them, with the app's vars available under vars
+    /// (eg. namespace = "{{ vars.team }}-{{ app_name }}")
+    template_metadata: Option<bool>,
+    /// namespace the generated Application manifests live in (exposed to the application template
+    /// as application_namespace), defaults to argocd_namespace. Other namespaces need argocd's apps
+    /// in any namespace feature and must be listed in the project's source_namespaces
+    application_namespace: Option<String>,
+    /// argocd notification subscriptions added to every generated Application, keyed by
+    /// <trigger>.<service> (eg. "on-sync-failed.slack") with the recipients as the value
+    notification_subscriptions: Option<BTreeMap<String, String>>,
+    /// sync settings applied to targets by their env, eg. env_policies.nonprod.sync_policy
+    env_policies: Option<HashMap<String, EnvPolicy>>,
+    argocd_config_layout: Option<ArgoCDConfigLayout>,
+    /// how project and app directory names are turned into project, application and default
+    /// namespace names
+    sanitize: Option<SanitizeOptions>,
+    /// annotate generated Applications and AppProjects with the source commit and path, the bargo
+    /// version and when they were generated
+    provenance: Option<ProvenanceOptions>,
+    /// how the metadata's owner, team and cost_center are labelled
+    ownership_labels: Option<OwnershipLabelOptions>,
+    /// regexes the generated names of every app must match
+    naming_policy: Option<NamingPolicy>,
+    /// keep a bargo.lock in the input path listing every generated application per target. Apps
+    /// listed in it which are no longer generated fail the run unless --allow-prune is given or
+    /// their metadata file is tombstoned
+    lockfile: Option<bool>,
+    /// rhai script (relative to the input path) whose functions are registered as tera filters
+    filters_script: Option<String>,
+    /// commands metadata scripts may run, eg. ["helm", "kustomize"]. Scripts get a PATH holding only
+    /// these commands so anything else fails with command not found. Bash builtins and commands run
+    /// by their full path aren't restricted, so this guards against mistakes rather than being a
+    /// sandbox
+    script_allowed_commands: Option<Vec<String>>,
+    /// gpg key ids every project's sources must be signed with, for argocd installs enforcing
+    /// signed commits on the rendered repo
+    signature_keys: Option<Vec<String>>,
+    /// WASI modules (relative to the input path) run over every generated app, see plugin.rs.
+    /// Needs bargo to be built with the wasm feature
+    plugins: Option<Vec<String>>,
+    /// commands run with bash over templated files by output extension (eg. yaml = "yq -P"). The
+    /// rendered contents are passed on stdin and replaced with stdout, with the template and output
+    /// paths in the $in and $out env vars
+    post_processors: Option<BTreeMap<String, String>>,
+    /// re-serialize templated yaml files and generated applications so the output formatting
+    /// doesn't depend on how the templates are written. Comments in the templates are dropped
+    canonical_yaml: Option<bool>,
+    /// start every templated yaml file and argocd-config file with a comment naming the source
+    /// template and warning against editing the generated file
+    generated_header: Option<bool>,
+    /// whether AppProject and Application destinations identify the cluster by name or by server,
+    /// defaults to setting both on AppProjects and leaving Applications as templated
+    destination_by: Option<DestinationBy>,
+    /// also package each target's output as a helm chart in <target>/helm-chart, for consumers
+    /// which can only install helm charts
+    helm_chart: Option<HelmChartOptions>,
+    /// annotate generated Applications with bargo.dev/content-hash, a hash of the app's generated
+    /// files, so changes to an app's output can be detected without diffing it. Not set in stdout
+    /// mode since no files are generated
+    content_hash_annotation: Option<bool>,
+    /// named overrides of the config selected with --profile, eg. a lightweight local preview
+    profiles: Option<BTreeMap<String, ConfigProfile>>,
+    /// urls posted a summary of each run (applications changed, failures and duration) once it
+    /// finishes, eg. to page the owning team when the nightly render fails
+    webhooks: Option<Vec<WebhookConfig>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, schemars::JsonSchema)]
+#[serde(untagged)]
+#[schemars(deny_unknown_fields)]
+enum VarsFile {
+    Path(String),
+    Url {
+        url: String,
+        /// env var holding a bearer token sent with the request
+        token_env: Option<String>,
+    },
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct WebhookConfig {
+    /// https url to post to, or url_env naming an env var holding it since slack webhook urls
+    /// contain a secret
+    url: Option<String>,
+    url_env: Option<String>,
+    /// defaults to json
+    format: Option<WebhookFormat>,
+    /// defaults to always
+    on: Option<WebhookOn>,
+}
+
+#[derive(
+    serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum WebhookFormat {
+    /// the run summary as a json object
+    #[default]
+    Json,
+    /// a slack incoming webhook message
+    Slack,
+}
+
+#[derive(
+    serde::Deserialize, serde::Serialize, Debug, Default, Clone, Copy, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum WebhookOn {
+    #[default]
+    Always,
+    Failure,
+    Success,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct ConfigProfile {
+    /// replaces the config's application_template
+    application_template: Option<String>,
+    /// replaces the config's targets
+    targets: Option<Vec<ConfigTarget>>,
+    /// merged over the config's vars
+    vars: Option<serde_json::Value>,
+    /// merged over the config's default_application_options
+    default_application_options: Option<serde_json::Value>,
+}
+
+/// each pattern is rendered with tera before being compiled so it can reference the app's project,
+/// app_name, team, owner, cost_center and target_name, eg. application = "^{{ team }}-". Unset
+/// metadata values render as empty strings
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct NamingPolicy {
+    /// the normalized application name
+    application: Option<String>,
+    /// the normalized project name
+    project: Option<String>,
+    /// each of the app's namespaces
+    namespace: Option<String>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct OwnershipLabelOptions {
+    /// prefix of the owner, team and cost-center label keys, defaults to bargo.dev/
+    prefix: Option<String>,
+    /// also label the app's rendered namespaced manifests, the yaml files are rewritten so their
+    /// comments and formatting aren't kept
+    manifests: Option<bool>,
 }
 
-#[derive(serde::Deserialize, serde::Serialize, Debug)]
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct ProvenanceOptions {
+    /// take the generation time from SOURCE_DATE_EPOCH or the source commit instead of the clock,
+    /// so rendering the same commit twice gives the same output. --frozen-time takes precedence
+    reproducible: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct HelmChartOptions {
+    /// chart name, defaults to the target name
+    name: Option<String>,
+    /// chart version, defaults to 0.1.0
+    version: Option<String>,
+    /// include the apps' yaml manifests as well as argocd-config. Apps which are helm charts
+    /// themselves are left out
+    include_apps: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Default, Clone, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct SanitizeOptions {
+    /// names are truncated to this length, defaults to 253 and is capped at 63 for namespaces
+    max_length: Option<usize>,
+    /// replace the end of truncated names with a hash of the full name so they stay unique
+    hash_suffix: Option<bool>,
+    /// replaces invalid characters, defaults to -
+    replacement: Option<String>,
+    /// keep dots in project and application names, defaults to true. Dots are always replaced in
+    /// namespaces
+    allow_dots: Option<bool>,
+}
+
+/// how the AppProjects and Applications are laid out in each target's argocd-config dir
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum ArgoCDConfigLayout {
+    /// one file per project holding the AppProject followed by its Applications
+    #[default]
+    Combined,
+    /// AppProjects in projects/ and Applications in apps/, so they can be synced by separate
+    /// Applications. The argocd-config application needs directory.recurse to pick up both
+    Split,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
+struct EnvPolicy {
+    /// syncPolicy set on generated Applications which don't set one
+    sync_policy: Option<serde_json::Value>,
+    /// syncWindows set on the target's app projects
+    sync_windows: Option<Vec<serde_json::Value>>,
+}
+
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum EmptyAppAction {
+    Ignore,
+    #[default]
+    Warn,
+    Error,
+}
+
+/// which field identifies the destination cluster. Argo CD gets confused when destinations mix both
+#[derive(
+    serde::Deserialize, serde::Serialize, Debug, Clone, Copy, PartialEq, schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum DestinationBy {
+    Name,
+    Server,
+}
+
+/// how non template files are copied into the output directory
+#[derive(
+    serde::Deserialize,
+    serde::Serialize,
+    Debug,
+    Default,
+    Clone,
+    Copy,
+    PartialEq,
+    schemars::JsonSchema,
+)]
+#[serde(rename_all = "snake_case")]
+enum CopyStrategy {
+    Copy,
+    /// copy on write clone where the filesystem supports it, falling back to a normal copy
+    #[default]
+    Reflink,
+    /// hardlink the input file when on the same filesystem, falling back to a normal copy. Scripts
+    /// must not modify copied files in place when using this as it would change the input file
+    Hardlink,
+}
+
+// bargo.toml of each input path after the first. Only its vars and ignore_dirs are used, other
+// keys are ignored so inputs which are also rendered on their own can keep their full config
+#[derive(serde::Deserialize, Debug, Default)]
+struct InputConfig {
+    vars: Option<serde_json::Value>,
+    #[serde(default)]
+    targets: Vec<ConfigTarget>,
+    ignore_dirs: Option<Vec<String>>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Debug, Clone, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct ConfigTarget {
     name: String,
+    /// free form environment class (eg. prod or nonprod) exposed to templates as target_env
+    env: Option<String>,
     vars: Option<serde_json::Value>,
+    /// overrides the config's destination_by for this target
+    destination_by: Option<DestinationBy>,
 }
 
-#[derive(serde::Deserialize, Debug, Default)]
+#[derive(serde::Deserialize, Debug, Default, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct Metadata {
     namespace: Option<String>,
+    /// other namespaces the app deploys into, each gets an app project destination
+    namespaces: Option<Vec<String>>,
     script: Option<String>,
     application_options: Option<serde_json::Value>,
     targets: Vec<MetadataTarget>,
     project_options: Option<MetadataProjectOptions>,
+    /// treat every text file in the app as a template, not just .tera files
+    template_all_files: Option<bool>,
+    /// write the app's merged vars to generated-values.yaml in its output directory so helm based
+    /// applications can use them as a values file
+    write_generated_values: Option<bool>,
+    /// for apps with a kustomization file, generate a kustomize component setting the images,
+    /// replicas and labels from the kustomize var and add it to the kustomization's components
+    kustomize_patch: Option<bool>,
+    /// false or a tera expression evaluated against the app's vars for each target, eg.
+    /// "feature_x and replicas > 1". The app isn't generated for targets where it's false
+    enabled: Option<AppEnabled>,
+    /// merged over the config's notification subscriptions, an empty value removes a default one
+    notification_subscriptions: Option<BTreeMap<String, String>>,
+    /// entries added to the generated Applications' spec.info, shown in the argocd ui, eg.
+    /// owner = "team-payments"
+    info: Option<BTreeMap<String, String>>,
+    /// links shown in the argocd ui, added as link.argocd.argoproj.io/<name> annotations on the
+    /// generated Applications, eg. docs = "https://docs.example.com/payments"
+    links: Option<BTreeMap<String, String>>,
+    /// ownership of the app, added as labels to its Applications and AppProject (when every app of
+    /// the project agrees) and optionally its manifests, see ownership_labels in bargo.toml
+    owner: Option<String>,
+    team: Option<String>,
+    cost_center: Option<String>,
+    /// the app is being removed, nothing is generated for it and it's dropped from bargo.lock
+    /// without needing --allow-prune. The app dir can be deleted once the lockfile no longer lists
+    /// it
+    tombstone: Option<bool>,
 }
 
-#[derive(serde::Deserialize, Debug)]
+#[derive(serde::Deserialize, Debug, Clone, schemars::JsonSchema)]
+#[serde(untagged)]
+enum AppEnabled {
+    Bool(bool),
+    Expression(String),
+}
+
+#[derive(serde::Deserialize, Debug, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct MetadataTarget {
+    /// a target name, a glob pattern (eg. "prod-*") or a regex wrapped in slashes (eg. "/^prod-/")
     name: String,
+    /// overrides the app's namespace for this target
+    namespace: Option<String>,
     vars: Option<serde_json::Value>,
 }
 
-#[derive(serde::Deserialize, Debug, Default)]
+#[derive(serde::Deserialize, Debug, Default, schemars::JsonSchema)]
+#[schemars(deny_unknown_fields)]
 struct MetadataProjectOptions {
     additional_namespaces: Option<Vec<String>>,
+    /// namespace or glob (eg. "*" or "team-*") used for the project's destination instead of adding
+    /// one per app namespace, for projects trusted to manage arbitrary namespaces
+    destination_namespace: Option<String>,
+    /// overrides the config's application_namespace for the app
+    application_namespace: Option<String>,
+    /// namespaces (or globs) the project's Applications may live in
+    source_namespaces: Option<Vec<String>>,
+    /// repos the project's Applications may use as sources besides argocd_source_repo, eg. an
+    /// external helm chart repo
+    source_repos: Option<Vec<String>>,
     cluster_resource_whitelist: Option<Vec<crate::app_project::AppProjectClusterResourceWhitelist>>,
+    /// cluster scoped kinds the project may never deploy, argocd applies these even when the
+    /// whitelist allows the kind (eg. a "*" whitelist)
+    cluster_resource_blacklist: Option<Vec<crate::app_project::AppProjectClusterResourceWhitelist>>,
+    /// gpg key ids the project's sources must be signed with, added to the config's signature_keys
+    signature_keys: Option<Vec<String>>,
+}
+
+#[derive(serde::Serialize, Debug)]
+struct InventoryEntry {
+    name: String,
+    project: String,
+    namespace: String,
+    path: String,
+    metadata_file: String,
 }
 
 #[derive(serde::Serialize, Debug)]
-struct TemplateContext {
+pub struct TemplateContext {
     namespace: String,
+    // the app's namespace followed by any additional namespaces from its metadata
+    namespaces: Vec<String>,
+    // namespace the Application manifest itself lives in
+    application_namespace: String,
     project: String,
     app_name: String,
     normalized_project: String,
     normalized_app_name: String,
     path: String,
+    // the app's directory relative to the input path
+    source_dir: String,
+    // same as path but always joined with the configured path separator
+    output_path: String,
     target_name: String,
+    // the target's env, empty when it doesn't set one
+    target_env: String,
+    config: ConfigContext,
+}
+
+// bargo.toml values available to application templates and, under the config key, to file
+// templates
+#[derive(serde::Serialize, Debug, Clone)]
+struct ConfigContext {
+    argocd_namespace: String,
+    argocd_source_repo: String,
+    target: ConfigContextTarget,
+}
+
+#[derive(serde::Serialize, Debug, Clone)]
+struct ConfigContextTarget {
+    name: String,
+    env: String,
 }
 
 fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
-    let run_result = run();
+    let args = Args::parse();
+
+    // keep stdout clean for the generated documents
+    let log_to_stderr = args.stdout
+        || matches!(
+            args.command,
+            Some(Command::Explain { .. })
+                | Some(Command::Affected { .. })
+                | Some(Command::Completions { .. })
+                | Some(Command::Manpage { .. })
+                | Some(Command::Schema { .. })
+        );
+    let registry = tracing_subscriber::registry()
+        .with(console::layer(args.log_format, log_to_stderr))
+        .with(console::filter(args.verbose, args.quiet));
+
+    #[cfg(feature = "otel")]
+    let (registry, tracer_provider) = {
+        let (layer, provider) = telemetry::init(args.otlp_endpoint.clone())?.unzip();
+        (registry.with(layer), provider)
+    };
+    registry.init();
+
+    let run_result = run(args);
+
+    #[cfg(feature = "otel")]
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            error!("failed to flush traces: {e}");
+        }
+    }
 
     return match run_result {
         Ok(v) => Ok(v),
@@ -76,8 +760,110 @@ fn main() -> Result<()> {
     };
 }
 
-fn run() -> Result<()> {
-    let args = Args::parse();
-    let mut project_processor = ProjectProcessor::new(args)?;
-    return project_processor.process();
+fn run(args: Args) -> Result<()> {
+    match args.command.clone() {
+        Some(Command::Test {
+            expected_path,
+            update_snapshots,
+        }) => {
+            let expected_path = match expected_path {
+                Some(p) => std::path::PathBuf::from(p),
+                None => {
+                    std::path::PathBuf::from(args.input_path.first().map_or(".", |p| p.as_str()))
+                        .join("expected")
+                }
+            };
+            return snapshot::test(args, &expected_path, update_snapshots);
+        }
+        Some(Command::Verify { output_path }) => {
+            return snapshot::verify(args, path::Path::new(&output_path));
+        }
+        Some(Command::PruneCheck {
+            output_path,
+            confirm_prune,
+        }) => {
+            return prune::prune_check(
+                args,
+                output_path.as_ref().map(path::Path::new),
+                confirm_prune,
+            );
+        }
+        Some(Command::Drift { context }) => {
+            return drift::drift(args, context.as_deref());
+        }
+        Some(Command::Push { oci }) => {
+            return oci::push(args, &oci);
+        }
+        Some(Command::Explain { app_dir }) => {
+            let mut project_processor = ProjectProcessor::new(args)?;
+            return project_processor.explain(path::Path::new(&app_dir));
+        }
+        Some(Command::Affected { changed_since }) => {
+            let project_processor = ProjectProcessor::new(args)?;
+            return project_processor.affected(&changed_since);
+        }
+        Some(Command::Completions { shell }) => {
+            clap_complete::generate(shell, &mut Args::command(), "bargo", &mut std::io::stdout());
+            return Ok(());
+        }
+        Some(Command::Manpage { out_dir }) => {
+            return write_manpages(out_dir.as_deref());
+        }
+        Some(Command::Schema { file }) => {
+            let schema = match file {
+                SchemaFile::Config => schemars::schema_for!(Config),
+                SchemaFile::Metadata => schemars::schema_for!(Metadata),
+            };
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+            return Ok(());
+        }
+        // renders like the default command, the processor sets up the preview target
+        Some(Command::Preview { .. }) => (),
+        Some(Command::Server { listen, config }) => {
+            return server::serve(args, &listen, path::Path::new(&config));
+        }
+        #[cfg(feature = "operator")]
+        Some(Command::Operator {
+            work_dir,
+            print_crd,
+        }) => {
+            if print_crd {
+                return operator::print_crd();
+            }
+            return operator::run(args, path::Path::new(&work_dir));
+        }
+        None => (),
+    }
+
+    let input_path = match args.input_path.first() {
+        Some(input_path) => input_path.clone(),
+        None => ".".to_string(),
+    };
+    let started_at = std::time::Instant::now();
+    let mut project_processor = match ProjectProcessor::new(args) {
+        Ok(project_processor) => project_processor,
+        Err(e) => {
+            webhook::notify_setup_failure(&input_path, &e, started_at.elapsed());
+            return Err(e);
+        }
+    };
+    let result = project_processor
+        .process()
+        .and_then(|()| project_processor.finish());
+    project_processor.notify_webhooks(&result);
+    return result;
+}
+
+// prints bargo's man page, or with an out_dir writes it along with a page per subcommand for
+// packaging
+fn write_manpages(out_dir: Option<&str>) -> Result<()> {
+    let command = Args::command();
+    match out_dir {
+        Some(out_dir) => {
+            std::fs::create_dir_all(out_dir)?;
+            clap_mangen::generate_to(command, out_dir)?;
+        }
+        None => clap_mangen::Man::new(command).render(&mut std::io::stdout())?,
+    }
+    return Ok(());
 }
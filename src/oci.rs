@@ -0,0 +1,53 @@
+use anyhow::{anyhow, Result};
+use tracing::info;
+
+use crate::{snapshot::render_to_temp_dir, Args};
+
+// renders the input and pushes each target's output as an OCI artifact to
+// <registry>/<repository>/<target>:<tag> using the oras cli, so argocd can use the artifact as an
+// oci source instead of a rendered git repo
+pub fn push(args: Args, reference: &str) -> Result<()> {
+    let (repository, tag) = parse_reference(reference)?;
+    let (project_processor, _output_dir) = render_to_temp_dir(args, &[])?;
+
+    for target_name in project_processor.processed_targets() {
+        let target_dir = project_processor.output_path().join(&target_name);
+        let target_reference = format!("{}/{}:{}", repository, target_name, tag);
+        info!(from=?target_dir, to=target_reference, "pushing target");
+
+        // pushed from within the target dir so the artifact's paths are relative to it
+        let output = std::process::Command::new("oras")
+            .args(["push", &target_reference, "."])
+            .current_dir(&target_dir)
+            .output()
+            .map_err(|e| anyhow!("failed to run oras: {}", e))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "failed to push {:?} to {}: {}",
+                target_dir,
+                target_reference,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        println!("pushed {}", target_reference);
+    }
+    return Ok(());
+}
+
+// splits oci://registry/repository:tag into the repository and tag
+fn parse_reference(reference: &str) -> Result<(&str, &str)> {
+    let invalid_reference = || {
+        anyhow!(
+            "invalid oci reference {:?}, expected oci://registry/repository:tag",
+            reference
+        )
+    };
+    let reference = reference
+        .strip_prefix("oci://")
+        .ok_or_else(invalid_reference)?;
+    let (repository, tag) = reference.rsplit_once(':').ok_or_else(invalid_reference)?;
+    if tag.contains('/') || !repository.contains('/') || tag.is_empty() {
+        return Err(invalid_reference());
+    }
+    return Ok((repository, tag));
+}
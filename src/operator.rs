@@ -0,0 +1,401 @@
+use anyhow::{anyhow, Result};
+use futures::StreamExt;
+use kube::{
+    api::{Api, Patch, PatchParams},
+    runtime::{
+        controller::{Action, Controller},
+        watcher,
+    },
+    Client, CustomResource, CustomResourceExt, ResourceExt,
+};
+use schemars::JsonSchema;
+use std::{fs, path, sync::Arc, time::Duration};
+use tracing::{info, info_span, warn};
+
+use crate::{
+    server::{git, render_repo, RepoConfig},
+    Args,
+};
+
+// a source repo rendered by the operator, re-rendered every interval_seconds and whenever the
+// resource changes (eg. bumping an annotation from a webhook)
+#[derive(CustomResource, serde::Deserialize, serde::Serialize, Clone, Debug, JsonSchema)]
+#[kube(
+    group = "bargo.dev",
+    version = "v1alpha1",
+    kind = "PreprocessorRepo",
+    namespaced,
+    status = "PreprocessorRepoStatus",
+    shortname = "ppr"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PreprocessorRepoSpec {
+    pub url: String,
+    // branch of the source repo to render, defaults to main
+    #[serde(rename = "ref")]
+    pub git_ref: Option<String>,
+    // only render these targets, defaults to all targets
+    pub targets: Option<Vec<String>>,
+    pub destination: PreprocessorRepoDestination,
+    // defaults to 5 minutes
+    pub interval_seconds: Option<u64>,
+}
+
+// exactly one of git or apply should be set
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreprocessorRepoDestination {
+    // commit the output to a branch of the rendered repo and push it
+    pub git: Option<PreprocessorRepoGitDestination>,
+    // kubectl apply each target's output to the cluster the operator runs in, pruning resources
+    // it no longer renders. Needs kubectl 1.27 or later for applysets
+    pub apply: Option<bool>,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, JsonSchema)]
+pub struct PreprocessorRepoGitDestination {
+    pub url: String,
+    pub branch: String,
+}
+
+#[derive(serde::Deserialize, serde::Serialize, Clone, Debug, Default, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PreprocessorRepoStatus {
+    // source commit of the last successful render
+    pub last_rendered_commit: Option<String>,
+    pub message: Option<String>,
+    // metadata.generation and rfc3339 time of the last render, successful or not. Patching the
+    // status triggers another reconcile which is skipped when neither changed
+    pub observed_generation: Option<i64>,
+    pub last_render_time: Option<String>,
+}
+
+struct Context {
+    client: Client,
+    args: Args,
+    work_dir: path::PathBuf,
+}
+
+#[derive(Debug)]
+struct ReconcileError(anyhow::Error);
+
+impl std::fmt::Display for ReconcileError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        return write!(f, "{:#}", self.0);
+    }
+}
+
+impl std::error::Error for ReconcileError {}
+
+pub fn print_crd() -> Result<()> {
+    print!("{}", serde_yaml::to_string(&PreprocessorRepo::crd())?);
+    return Ok(());
+}
+
+// watches PreprocessorRepo resources in every namespace, rendering each into its destination.
+// Source and destination checkouts are kept under work_dir between runs
+pub fn run(args: Args, work_dir: &path::Path) -> Result<()> {
+    fs::create_dir_all(work_dir)?;
+    let work_dir = work_dir.canonicalize()?;
+
+    let runtime = tokio::runtime::Runtime::new()?;
+    return runtime.block_on(async move {
+        let client = Client::try_default()
+            .await
+            .map_err(|e| anyhow!("failed to create kubernetes client: {}", e))?;
+        let repos = Api::<PreprocessorRepo>::all(client.clone());
+        let context = Arc::new(Context {
+            client,
+            args,
+            work_dir,
+        });
+
+        info!("watching PreprocessorRepo resources");
+        Controller::new(repos, watcher::Config::default())
+            .run(reconcile, error_policy, context)
+            .for_each(|result| async move {
+                match result {
+                    Ok((repo, _)) => info!(repo = repo.name, "reconciled"),
+                    Err(e) => warn!(error = %e, "reconcile failed"),
+                }
+            })
+            .await;
+        return Ok(());
+    });
+}
+
+async fn reconcile(
+    repo: Arc<PreprocessorRepo>,
+    context: Arc<Context>,
+) -> Result<Action, ReconcileError> {
+    let namespace = repo.namespace().unwrap_or_default();
+    let name = repo.name_any();
+    let interval = Duration::from_secs(repo.spec.interval_seconds.unwrap_or(300));
+
+    match next_render_in(&repo, interval) {
+        Some(wait) => return Ok(Action::requeue(wait)),
+        None => (),
+    }
+
+    let render_repo = repo.clone();
+    let render_context = context.clone();
+    let result = tokio::task::spawn_blocking(move || {
+        let _repo_span = info_span!(
+            "repo",
+            namespace = render_repo.namespace(),
+            name = render_repo.name_any()
+        )
+        .entered();
+        render(&render_context, &render_repo)
+    })
+    .await
+    .map_err(|e| ReconcileError(anyhow!("render task failed: {}", e)))?;
+
+    let observed_generation = repo.metadata.generation;
+    let last_render_time = Some(chrono::Utc::now().to_rfc3339());
+    let status = match result.as_ref() {
+        Ok(commit) => PreprocessorRepoStatus {
+            last_rendered_commit: Some(commit.clone()),
+            message: Some("rendered".to_string()),
+            observed_generation,
+            last_render_time,
+        },
+        Err(e) => PreprocessorRepoStatus {
+            last_rendered_commit: repo
+                .status
+                .as_ref()
+                .and_then(|status| status.last_rendered_commit.clone()),
+            message: Some(format!("{:#}", e)),
+            observed_generation,
+            last_render_time,
+        },
+    };
+    Api::<PreprocessorRepo>::namespaced(context.client.clone(), &namespace)
+        .patch_status(
+            &name,
+            &PatchParams::default(),
+            &Patch::Merge(serde_json::json!({ "status": status })),
+        )
+        .await
+        .map_err(|e| ReconcileError(anyhow!("failed to update status: {}", e)))?;
+
+    result.map_err(ReconcileError)?;
+    return Ok(Action::requeue(interval));
+}
+
+// how long until the repo is due for a render, None when it should render now. A repo is due once
+// its spec changed or interval passed since the last render
+fn next_render_in(repo: &PreprocessorRepo, interval: Duration) -> Option<Duration> {
+    let status = repo.status.as_ref()?;
+    if status.observed_generation.is_none()
+        || status.observed_generation != repo.metadata.generation
+    {
+        return None;
+    }
+    let last_render_time = status.last_render_time.as_ref()?;
+    let last_render_time = chrono::DateTime::parse_from_rfc3339(last_render_time).ok()?;
+    let elapsed = (chrono::Utc::now() - last_render_time.to_utc())
+        .to_std()
+        .unwrap_or_default();
+    return interval.checked_sub(elapsed).filter(|wait| !wait.is_zero());
+}
+
+fn error_policy(
+    repo: Arc<PreprocessorRepo>,
+    _error: &ReconcileError,
+    _context: Arc<Context>,
+) -> Action {
+    return Action::requeue(Duration::from_secs(
+        repo.spec.interval_seconds.unwrap_or(300),
+    ));
+}
+
+// renders the repo into its destination returning the rendered source commit
+fn render(context: &Context, repo: &PreprocessorRepo) -> Result<String> {
+    let id = format!(
+        "{}-{}",
+        repo.namespace().unwrap_or_default(),
+        repo.name_any()
+    );
+    let output_path = context.work_dir.join(format!("{}-output", id));
+
+    let destination = &repo.spec.destination;
+    match destination.git.as_ref() {
+        Some(git_destination) => {
+            checkout_git_destination(&context.work_dir, &output_path, git_destination)?
+        }
+        None if destination.apply.unwrap_or(false) => fs::create_dir_all(&output_path)?,
+        None => return Err(anyhow!("destination must set either git or apply")),
+    }
+
+    let mut args = context.args.clone();
+    args.targets = repo.spec.targets.clone().unwrap_or_default();
+    render_repo(
+        &args,
+        &context.work_dir,
+        &RepoConfig {
+            name: format!("{}-source", id),
+            url: repo.spec.url.clone(),
+            branch: repo.spec.git_ref.clone(),
+            output_path: output_path.display().to_string(),
+            secret_env: None,
+            insecure: None,
+            after_script: None,
+        },
+    )?;
+    let commit = git(
+        &context.work_dir.join(format!("{}-source", id)),
+        &["rev-parse", "HEAD"],
+    )?;
+
+    match destination.git.as_ref() {
+        Some(git_destination) => {
+            git(&output_path, &["add", "--all"])?;
+            if git(&output_path, &["status", "--porcelain"])?.is_empty() {
+                info!(commit, "rendered output unchanged");
+                return Ok(commit);
+            }
+            git(
+                &output_path,
+                &[
+                    "-c",
+                    "user.name=argocd-preprocessor",
+                    "-c",
+                    "user.email=argocd-preprocessor@localhost",
+                    "commit",
+                    "--message",
+                    &format!("Render {}", commit),
+                ],
+            )?;
+            git(
+                &output_path,
+                &[
+                    "push",
+                    "origin",
+                    &format!("HEAD:refs/heads/{}", git_destination.branch),
+                ],
+            )?;
+        }
+        None => apply(&id, &repo.namespace().unwrap_or_default(), &output_path)?,
+    }
+    return Ok(commit);
+}
+
+// checks out the destination branch into output_path, starting an empty branch when it doesn't
+// exist yet. A checkout whose fetch fails is cloned again, eg. when the first push never happened
+fn checkout_git_destination(
+    work_dir: &path::Path,
+    output_path: &path::Path,
+    git_destination: &PreprocessorRepoGitDestination,
+) -> Result<()> {
+    let branch = git_destination.branch.as_str();
+    if output_path.join(".git").exists() {
+        match git(output_path, &["fetch", "--depth", "1", "origin", branch]) {
+            Ok(_) => {
+                git(output_path, &["checkout", "--force", "FETCH_HEAD"])?;
+                return Ok(());
+            }
+            Err(e) => {
+                warn!(
+                    "failed to fetch destination branch, cloning it again: {:#}",
+                    e
+                );
+                fs::remove_dir_all(output_path)?;
+            }
+        }
+    }
+
+    let output_path_arg = output_path.display().to_string();
+    let remote_heads = git(
+        work_dir,
+        &[
+            "ls-remote",
+            "--heads",
+            &git_destination.url,
+            &format!("refs/heads/{}", branch),
+        ],
+    )?;
+    if !remote_heads.is_empty() {
+        git(
+            work_dir,
+            &[
+                "clone",
+                "--depth",
+                "1",
+                "--branch",
+                branch,
+                &git_destination.url,
+                &output_path_arg,
+            ],
+        )?;
+        return Ok(());
+    }
+
+    info!(branch, "destination branch doesn't exist, creating it");
+    if output_path.exists() {
+        fs::remove_dir_all(output_path)?;
+    }
+    git(
+        work_dir,
+        &[
+            "clone",
+            "--depth",
+            "1",
+            &git_destination.url,
+            &output_path_arg,
+        ],
+    )?;
+    git(output_path, &["checkout", "--orphan", branch])?;
+    git(
+        output_path,
+        &["rm", "-r", "--force", "--quiet", "--ignore-unmatch", "."],
+    )?;
+    return Ok(());
+}
+
+// applies every target's output as one applyset, its parent secret living in the repo's
+// namespace, so resources no longer rendered are pruned
+fn apply(id: &str, namespace: &str, output_path: &path::Path) -> Result<()> {
+    let mut target_dirs = Vec::new();
+    for entry in fs::read_dir(output_path)? {
+        let target_dir = entry?.path();
+        let hidden = target_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().starts_with('.'))
+            .unwrap_or(true);
+        if hidden || !target_dir.is_dir() {
+            continue;
+        }
+        target_dirs.push(target_dir);
+    }
+    if target_dirs.is_empty() {
+        // kubectl refuses to apply nothing, so an empty render leaves the cluster as is
+        warn!("no targets rendered, skipping apply");
+        return Ok(());
+    }
+    target_dirs.sort();
+
+    let mut command = std::process::Command::new("kubectl");
+    command
+        .env("KUBECTL_APPLYSET", "true")
+        .arg("apply")
+        .arg("--recursive")
+        .arg("--prune")
+        .arg(format!("--applyset=bargo-{}", id))
+        .arg("--namespace")
+        .arg(namespace);
+    for target_dir in target_dirs.iter() {
+        command.arg("--filename").arg(target_dir);
+    }
+    let output = command
+        .output()
+        .map_err(|e| anyhow!("failed to run kubectl: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "failed to apply {:?}: {}",
+            output_path,
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    return Ok(());
+}
@@ -0,0 +1,107 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs, path,
+};
+use tracing::info;
+
+use crate::{Metadata, TemplateContext};
+
+#[derive(serde::Serialize, Default)]
+struct ReportEntry {
+    application: String,
+    project: String,
+    source_dir: String,
+    owner: Option<String>,
+    team: Option<String>,
+    cost_center: Option<String>,
+    targets: BTreeSet<String>,
+    namespaces: BTreeSet<String>,
+}
+
+// every generated application with its ownership metadata, the targets it's generated for and the
+// namespaces it deploys into across them
+#[derive(Default)]
+pub struct OwnershipReport {
+    // by project and application name
+    entries: BTreeMap<(String, String), ReportEntry>,
+}
+
+impl OwnershipReport {
+    pub fn add(&mut self, metadata: &Metadata, app_context: &TemplateContext) {
+        let entry = self
+            .entries
+            .entry((
+                app_context.normalized_project.clone(),
+                app_context.normalized_app_name.clone(),
+            ))
+            .or_insert_with(|| ReportEntry {
+                application: app_context.normalized_app_name.clone(),
+                project: app_context.normalized_project.clone(),
+                source_dir: app_context.source_dir.clone(),
+                owner: metadata.owner.clone(),
+                team: metadata.team.clone(),
+                cost_center: metadata.cost_center.clone(),
+                ..Default::default()
+            });
+        entry.targets.insert(app_context.target_name.clone());
+        entry
+            .namespaces
+            .extend(app_context.namespaces.iter().cloned());
+    }
+
+    // writes the report as csv when path ends with .csv and as json otherwise
+    pub fn write(&self, path: &path::Path) -> Result<()> {
+        let contents = match path.extension().and_then(|extension| extension.to_str()) {
+            Some("csv") => self.to_csv(),
+            _ => serde_json::to_string_pretty(&self.entries.values().collect::<Vec<_>>())? + "\n",
+        };
+        fs::write(path, contents)
+            .map_err(|e| anyhow!("failed to write ownership report {:?}: {}", path, e))?;
+        info!(
+            ?path,
+            applications = self.entries.len(),
+            "wrote ownership report"
+        );
+        return Ok(());
+    }
+
+    // lists are joined with ;
+    fn to_csv(&self) -> String {
+        let mut csv = "application,project,source_dir,owner,team,cost_center,targets,namespaces\n"
+            .to_string();
+        for entry in self.entries.values() {
+            let fields = [
+                entry.application.clone(),
+                entry.project.clone(),
+                entry.source_dir.clone(),
+                entry.owner.clone().unwrap_or_default(),
+                entry.team.clone().unwrap_or_default(),
+                entry.cost_center.clone().unwrap_or_default(),
+                entry.targets.iter().cloned().collect::<Vec<_>>().join(";"),
+                entry
+                    .namespaces
+                    .iter()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(";"),
+            ];
+            csv.push_str(
+                &fields
+                    .iter()
+                    .map(|field| csv_field(field))
+                    .collect::<Vec<_>>()
+                    .join(","),
+            );
+            csv.push('\n');
+        }
+        return csv;
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n']) {
+        return format!("\"{}\"", field.replace('"', "\"\""));
+    }
+    return field.to_string();
+}
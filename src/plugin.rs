@@ -0,0 +1,186 @@
+use anyhow::{anyhow, Result};
+use std::{collections::BTreeMap, fs, path};
+use tracing::debug;
+
+use crate::{snapshot::list_files, TemplateContext};
+
+// a WASI command module run once for each generated app after its files are templated and its
+// script has run. The module is passed a json request on stdin:
+//
+//   {"target": "staging", "app": <template context>, "files": {"deploy.yaml": "..."}}
+//
+// with the contents of every utf-8 file in the app's output, and writes a json response to stdout:
+//
+//   {"files": {"extra.yaml": "...", "deploy.yaml": null}, "errors": ["..."]}
+//
+// files set to a string are created or replaced and files set to null are removed. Any errors veto
+// the app's output, failing it. The module has no access to the filesystem or network
+pub struct Plugin {
+    path: path::PathBuf,
+    #[cfg(feature = "wasm")]
+    engine: wasmtime::Engine,
+    #[cfg(feature = "wasm")]
+    module: wasmtime::Module,
+}
+
+#[derive(serde::Serialize)]
+struct PluginRequest<'a> {
+    target: &'a str,
+    app: &'a TemplateContext,
+    files: BTreeMap<String, String>,
+}
+
+#[derive(serde::Deserialize, Default)]
+#[serde(default)]
+struct PluginResponse {
+    files: BTreeMap<String, Option<String>>,
+    errors: Vec<String>,
+}
+
+// stdout of a plugin run is kept in memory, larger responses fail the run
+#[cfg(feature = "wasm")]
+const MAX_RESPONSE_SIZE: usize = 64 * 1024 * 1024;
+
+// fuel given to each plugin run, roughly one unit per wasm instruction. Enough for plugins doing
+// seconds of work while failing ones stuck in a loop
+#[cfg(feature = "wasm")]
+const FUEL: u64 = 10_000_000_000;
+
+impl Plugin {
+    #[cfg(feature = "wasm")]
+    pub fn load(path: &path::Path) -> Result<Plugin> {
+        let mut config = wasmtime::Config::new();
+        config.consume_fuel(true);
+        let engine = wasmtime::Engine::new(&config)?;
+        let module = wasmtime::Module::from_file(&engine, path)
+            .map_err(|e| anyhow!("failed to load plugin {:?}: {:#}", path, e))?;
+        tracing::info!(plugin=?path, "loaded plugin");
+        return Ok(Plugin {
+            path: path.to_path_buf(),
+            engine,
+            module,
+        });
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    pub fn load(path: &path::Path) -> Result<Plugin> {
+        return Err(anyhow!(
+            "can't load plugin {:?}, bargo was built without the wasm feature",
+            path
+        ));
+    }
+
+    // runs the plugin over an app's output dir, applying the files it returns
+    pub fn run(&self, target: &str, app: &TemplateContext, app_dir: &path::Path) -> Result<()> {
+        let mut files = BTreeMap::new();
+        for file in list_files(app_dir)? {
+            match fs::read_to_string(app_dir.join(&file)) {
+                Ok(contents) => {
+                    files.insert(file.display().to_string(), contents);
+                }
+                // binary files aren't passed to plugins
+                Err(e) if e.kind() == std::io::ErrorKind::InvalidData => (),
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let request = serde_json::to_vec(&PluginRequest { target, app, files })?;
+
+        let stdout = self.execute(request)?;
+        let response: PluginResponse = serde_json::from_slice(&stdout)
+            .map_err(|e| anyhow!("plugin {:?} returned an invalid response: {}", self.path, e))?;
+        if !response.errors.is_empty() {
+            return Err(anyhow!(
+                "plugin {:?} rejected the output: {}",
+                self.path,
+                response.errors.join(", ")
+            ));
+        }
+
+        for (file, contents) in response.files {
+            let file_path = path::Path::new(&file);
+            if file_path.is_absolute()
+                || file_path
+                    .components()
+                    .any(|c| c == path::Component::ParentDir)
+            {
+                return Err(anyhow!(
+                    "plugin {:?} returned a file outside the app dir: {:?}",
+                    self.path,
+                    file
+                ));
+            }
+            let to_path = app_dir.join(file_path);
+            match contents {
+                Some(contents) => {
+                    debug!(plugin=?self.path, path=?to_path, "writing plugin file");
+                    match to_path.parent() {
+                        Some(parent) => fs::create_dir_all(parent)?,
+                        None => (),
+                    }
+                    fs::write(&to_path, contents)?;
+                }
+                None => {
+                    debug!(plugin=?self.path, path=?to_path, "removing file for plugin");
+                    if to_path.exists() {
+                        fs::remove_file(&to_path)?;
+                    }
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    // runs the module's _start export with the request on stdin, returning its stdout
+    #[cfg(feature = "wasm")]
+    fn execute(&self, stdin: Vec<u8>) -> Result<Vec<u8>> {
+        use wasmtime_wasi::{
+            pipe::{MemoryInputPipe, MemoryOutputPipe},
+            preview1, I32Exit, WasiCtxBuilder,
+        };
+
+        let stdout = MemoryOutputPipe::new(MAX_RESPONSE_SIZE);
+        let wasi = WasiCtxBuilder::new()
+            .stdin(MemoryInputPipe::new(stdin))
+            .stdout(stdout.clone())
+            .inherit_stderr()
+            .build_p1();
+        let mut store = wasmtime::Store::new(&self.engine, wasi);
+        store.set_fuel(FUEL)?;
+        let mut linker = wasmtime::Linker::new(&self.engine);
+        preview1::add_to_linker_sync(&mut linker, |wasi| wasi)?;
+
+        let instance = linker
+            .instantiate(&mut store, &self.module)
+            .map_err(|e| anyhow!("failed to instantiate plugin {:?}: {:#}", self.path, e))?;
+        let start = instance
+            .get_typed_func::<(), ()>(&mut store, "_start")
+            .map_err(|e| anyhow!("plugin {:?} isn't a WASI command: {:#}", self.path, e))?;
+        match start.call(&mut store, ()) {
+            Ok(()) => (),
+            Err(e) => match e.downcast_ref::<I32Exit>() {
+                Some(I32Exit(0)) => (),
+                Some(I32Exit(code)) => {
+                    return Err(anyhow!("plugin {:?} exited with {}", self.path, code))
+                }
+                None if e.downcast_ref::<wasmtime::Trap>() == Some(&wasmtime::Trap::OutOfFuel) => {
+                    return Err(anyhow!(
+                        "plugin {:?} ran out of fuel after {} instructions",
+                        self.path,
+                        FUEL
+                    ))
+                }
+                None => return Err(anyhow!("plugin {:?} failed: {:#}", self.path, e)),
+            },
+        }
+        drop(store);
+        return Ok(stdout.contents().to_vec());
+    }
+
+    #[cfg(not(feature = "wasm"))]
+    fn execute(&self, _stdin: Vec<u8>) -> Result<Vec<u8>> {
+        return Err(anyhow!(
+            "can't run plugin {:?}, bargo was built without the wasm feature",
+            self.path
+        ));
+    }
+}
@@ -1,494 +1,4816 @@
 use anyhow::{anyhow, Result};
-use std::{collections::HashMap, error::Error, fs, io::Write, path};
-use tracing::{debug, error, info, warn};
+use rayon::prelude::*;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    error::Error,
+    fs,
+    io::{self, Read, Write},
+    path,
+    sync::{LazyLock, Mutex},
+    time::{Instant, SystemTime},
+};
+use tracing::{debug, error, info, info_span, warn};
 
-use crate::{app_project::*, Args, Config, Metadata, TemplateContext};
+use crate::{
+    app_files,
+    app_lookup::LookupApp,
+    app_project::*,
+    aws_vars::AwsVars,
+    clock::Clock,
+    diagnostics, helm_chart, kustomize,
+    lockfile::{LockEntry, Lockfile},
+    ownership_report::OwnershipReport,
+    plugin::Plugin,
+    registry, scripting,
+    server::git,
+    snapshot::{self, list_files},
+    stable_ids,
+    timing::{Phase, Timings},
+    upload::Upload,
+    webhook::{self, RunSummary},
+    AppEnabled, ArgoCDConfigLayout, Args, Command, Config, ConfigContext, ConfigContextTarget,
+    ConfigTarget, CopyStrategy, DestinationBy, EmptyAppAction, EnvPolicy, InputConfig,
+    InventoryEntry, Metadata, MetadataTarget, ProvenanceOptions, SanitizeOptions, TemplateContext,
+    VarsFile,
+};
 
 pub struct ProjectProcessor {
     input_path: path::PathBuf,
     output_path: path::PathBuf,
+    // targets are generated here and only moved into the output path once complete so a failed
+    // run never leaves a partially written target behind
+    staging_path: path::PathBuf,
     application_template_name: String,
     config: Config,
     targets: HashMap<String, HashMap<String, ArgoCDProject>>,
     tera: tera::Tera,
+    // contexts of every app by project, name and target for the lookup_app template function
+    app_lookup: LookupApp,
+    // every input path, the first is input_path
+    inputs: Vec<Input>,
+    // globs app dirs (relative to their input path) must match to be processed, all apps are
+    // processed when empty
+    path_filters: Vec<glob::Pattern>,
+    thread_pool: rayon::ThreadPool,
+    // guard for the output directory when no output path was given. A successful run keeps it
+    // unless the output was uploaded (and keep_output isn't set), otherwise it's removed when this
+    // is dropped
+    temp_output_dir: Option<tempdir::TempDir>,
+    keep_output: bool,
+    selected_targets: Vec<String>,
+    stdout: bool,
+    use_cache: bool,
+    // hash of the filters script and plugins, included in every app's input hash
+    scripts_hash: String,
+    // each loaded template's hash and the templates it depends on, an app's input hash covers the
+    // templates its files include
+    template_deps: HashMap<String, TemplateDeps>,
+    // non .tera files added to tera as templates, named by their path
+    raw_templates: HashSet<path::PathBuf>,
+    timings: Timings,
+    slowest_apps_to_report: Option<usize>,
+    keep_going: bool,
+    allow_unknown_targets: bool,
+    // targets removed by the selected profile or by the preview command, apps targeting them are
+    // skipped rather than failing as unknown targets
+    excluded_targets: Vec<String>,
+    // vars set with --set-json and --var, merged over every app's vars
+    var_overrides: serde_json::Value,
+    // set with --set-application-options-json, merged over every application's options
+    application_option_overrides: serde_json::Value,
+    // global vars merged with each target's vars, see target_vars for the vars of apps in other
+    // input paths
+    vars_by_target: HashMap<String, serde_json::Value>,
+    previous_cache: Cache,
+    cache: Cache,
+    // (target, app, path) of every generated application source pointing at the rendered repo,
+    // checked against the output once everything is generated
+    application_source_paths: Vec<(String, String, String)>,
+    // (target, app, project, namespace) of every generated application, checked against the
+    // project's source namespaces once every app has been processed
+    application_namespaces: Vec<(String, String, String, String)>,
+    inventory: HashMap<String, Vec<InventoryEntry>>,
+    upload: Option<Upload>,
+    provenance: Option<Provenance>,
+    plugins: Vec<Plugin>,
+    debug_context: bool,
+    script_path: Option<ScriptPath>,
+    ownership_report_path: Option<path::PathBuf>,
+    ownership_report: OwnershipReport,
+    preview: Option<Preview>,
+    // whether bargo.lock is checked and updated, only when rendering into the output path
+    lockfile: bool,
+    allow_prune: bool,
+    // metadata files (relative to their input path) of tombstoned apps
+    tombstones: HashSet<String>,
+    // for the run summary posted to webhooks
+    started_at: Instant,
+    failed_apps: usize,
+    changed_apps: Vec<String>,
 }
 
+// a temporary target rendered by the preview command, apps are rendered with their settings for
+// the base target
+struct Preview {
+    target: String,
+    base: String,
+    expires_at: String,
+}
+
+impl Preview {
+    fn new(target: &str, base: &str, ttl: &str, clock: Clock) -> Result<Preview> {
+        if !is_dns_label(target) {
+            return Err(anyhow!(
+                "preview name {:?} must be a lowercase rfc 1123 label",
+                target
+            ));
+        }
+        let expires_at = clock
+            .now()
+            .checked_add_signed(parse_ttl(ttl)?)
+            .ok_or_else(|| anyhow!("preview ttl {:?} is too long", ttl))?;
+        return Ok(Preview {
+            target: target.to_string(),
+            base: base.to_string(),
+            expires_at: expires_at.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        });
+    }
+
+    // replaces the config's targets with a copy of the base target named after the preview,
+    // returning the names of the other targets
+    fn replace_targets(&self, config: &mut Config) -> Result<Vec<String>> {
+        let mut target = config
+            .targets
+            .iter()
+            .find(|t| t.name == self.base)
+            .cloned()
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown preview base target {:?}, expected one of {:?}",
+                    self.base,
+                    config.targets.iter().map(|t| &t.name).collect::<Vec<_>>()
+                )
+            })?;
+        target.name = self.target.clone();
+        let removed = std::mem::replace(&mut config.targets, vec![target]);
+        return Ok(removed
+            .into_iter()
+            .map(|t| t.name)
+            .filter(|name| *name != self.base)
+            .collect());
+    }
+
+    fn annotations(&self) -> BTreeMap<String, String> {
+        return BTreeMap::from([
+            ("bargo.dev/preview".to_string(), self.target.clone()),
+            (
+                "bargo.dev/preview-expires-at".to_string(),
+                self.expires_at.clone(),
+            ),
+        ]);
+    }
+}
+
+struct TemplateDeps {
+    hash: String,
+    // templates included, imported or extended, None when any of them isn't a string literal
+    dependencies: Option<Vec<String>>,
+    // functions the template calls whose results depend on more than the app's own inputs
+    dynamic_calls: BTreeSet<String>,
+}
+
+// the templates an app's files depend on and the dynamic functions they or those templates call
+struct AppTemplateReferences {
+    // None when a dependency can't be resolved without rendering
+    dependencies: Option<BTreeSet<String>>,
+    dynamic_calls: BTreeSet<String>,
+}
+
+// a directory apps are discovered in
+struct Input {
+    path: path::PathBuf,
+    // prepended to the names of the input's templates so they don't clash with other inputs',
+    // empty for the first input
+    template_prefix: String,
+    ignore_dirs: Vec<glob::Pattern>,
+    // the input's own bargo.toml, never set for the first input whose bargo.toml is the config
+    config: Option<InputConfig>,
+    // names of the input's templates without the prefix
+    template_names: HashSet<String>,
+}
+
+impl Input {
+    fn template_name(&self, path: &path::Path) -> Result<String> {
+        return Ok(format!(
+            "{}{}",
+            self.template_prefix,
+            path.strip_prefix(&self.path)?.display()
+        ));
+    }
+
+    // prefixes the names of the input's own templates included, imported or extended by one of
+    // its templates, so they resolve within the input. Names the input doesn't have a template for
+    // are left to resolve against the first input's templates
+    fn prefix_template_references(&self, contents: &str) -> String {
+        if self.template_prefix.is_empty() {
+            return contents.to_string();
+        }
+        return TEMPLATE_REFERENCE_TAG
+            .replace_all(contents, |tag: &regex::Captures| {
+                // unwrap since both groups always participate in a match
+                let (whole, arguments) = (tag.get(0).unwrap(), tag.get(1).unwrap());
+                let (arguments_start, arguments_end) = (
+                    arguments.start() - whole.start(),
+                    arguments.end() - whole.start(),
+                );
+                let arguments = TEMPLATE_NAME_LITERAL.replace_all(
+                    arguments.as_str(),
+                    |literal: &regex::Captures| {
+                        let name = literal.iter().skip(1).flatten().next().unwrap().as_str();
+                        match self.template_names.contains(name) {
+                            true => literal[0].replacen(
+                                name,
+                                &format!("{}{}", self.template_prefix, name),
+                                1,
+                            ),
+                            false => literal[0].to_string(),
+                        }
+                    },
+                );
+                return format!(
+                    "{}{}{}",
+                    &whole.as_str()[..arguments_start],
+                    arguments,
+                    &whole.as_str()[arguments_end..]
+                );
+            })
+            .into_owned();
+    }
+
+    fn read_template(&self, path: &path::Path) -> Result<String> {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow!("failed to read template {:?}: {}", path, e))?;
+        return Ok(self.prefix_template_references(&contents));
+    }
+}
+
+// a tag including, importing or extending other templates, capturing its arguments
+static TEMPLATE_REFERENCE_TAG: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new(r"\{%-?\s*(?:include|import|extends)\s+((?s:.)*?)-?%\}").unwrap()
+});
+// a call of a function whose result can change without any of the app's inputs changing, eg.
+// resolve_tag returns the newest tag in the registry and lookup_app another app's context
+static DYNAMIC_FUNCTION_CALL: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"\b(lookup_app|resolve_tag)\s*\(").unwrap());
+// a string literal in a tag, capturing its contents in whichever group matches its quotes
+static TEMPLATE_NAME_LITERAL: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r#""([^"]*)"|'([^']*)'|`([^`]*)`"#).unwrap());
+
+// a directory of links to the commands metadata scripts are allowed to run, used as their PATH
+struct ScriptPath {
+    dir: tempdir::TempDir,
+    // resolved up front since it can't be looked up in the restricted PATH
+    bash: path::PathBuf,
+}
+
+// what the explain subcommand prints for each of an app's targets
+#[derive(serde::Serialize)]
+struct Explanation {
+    target: String,
+    enabled: bool,
+    vars: serde_json::Value,
+    application_options: serde_json::Value,
+    template_context: TemplateContext,
+}
+
+struct Provenance {
+    // added to every generated Application and AppProject
+    annotations: BTreeMap<String, String>,
+    // the input path relative to the root of its git repo, prefixed to each app's source path
+    source_prefix: String,
+}
+
+// stored in the output directory, maps target name to app output path to the hash of the app's
+// inputs from the last run
+const CACHE_FILE_NAME: &str = ".bargo-cache.json";
+// written to each app's output dir with --debug-context
+const DEBUG_CONTEXT_FILE_NAME: &str = ".bargo-context.json";
+type Cache = BTreeMap<String, BTreeMap<String, String>>;
+// config options which only affect discovery, the generated Applications and AppProjects or
+// validation, or which reach an app's output through its vars and context, so they're left out of
+// app input hashes
+const NON_OUTPUT_CONFIG_KEYS: [&str; 25] = [
+    "application_template",
+    "argocd_namespace",
+    "argocd_source_repo",
+    "targets",
+    "vars",
+    "vars_files",
+    "default_application_options",
+    "argocd_config_application_options",
+    "ignore_dirs",
+    "include_hidden_dirs",
+    "max_depth",
+    "deny_unknown_fields",
+    "write_inventory",
+    "application_namespace",
+    "notification_subscriptions",
+    "env_policies",
+    "argocd_config_layout",
+    "provenance",
+    "naming_policy",
+    "lockfile",
+    "signature_keys",
+    "destination_by",
+    "helm_chart",
+    "profiles",
+    "webhooks",
+];
+
 pub struct ArgoCDProject {
     project: AppProject,
-    applications: Vec<String>,
+    // rendered applications are appended to this file as they are generated instead of being held
+    // in memory, it gets merged into the project file once every app has been processed
+    applications_path: path::PathBuf,
+    // ownership labels the project's apps disagree on, which are left off the project
+    conflicting_labels: HashSet<String>,
 }
 
 impl ProjectProcessor {
     pub fn new(args: Args) -> Result<ProjectProcessor> {
-        let input_path = match args.input_path {
-            Some(p) => std::path::PathBuf::from(p),
-            None => std::path::PathBuf::from("."),
+        let input_paths = match args.input_path.is_empty() {
+            true => vec![".".to_string()],
+            false => args.input_path.clone(),
         };
 
-        let output_path = match args.output_path {
-            Some(v) => std::path::PathBuf::from(v),
-            None => tempdir::TempDir::new("argocd-preprocessor")?
-                .path()
-                .to_path_buf(),
+        let (output_path, temp_output_dir) = match args.output_path {
+            Some(v) => (std::path::PathBuf::from(v), None),
+            None => {
+                let temp_output_dir = tempdir::TempDir::new("argocd-preprocessor")?;
+                (temp_output_dir.path().to_path_buf(), Some(temp_output_dir))
+            }
         };
-        let input_path = input_path.canonicalize()?;
+        let input_paths = input_paths
+            .iter()
+            .map(|p| {
+                path::Path::new(p)
+                    .canonicalize()
+                    .map_err(|e| anyhow!("failed to find input path {:?}: {}", p, e))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        // apps are found by walking each input so they can't overlap
+        for (i, a) in input_paths.iter().enumerate() {
+            for b in input_paths.iter().skip(i + 1) {
+                if a.starts_with(b) || b.starts_with(a) {
+                    return Err(anyhow!("input paths {:?} and {:?} overlap", a, b));
+                }
+            }
+        }
+        let input_path = input_paths[0].clone();
         // make the output directory before calling canonicalize to avoid the not exist erro
         fs::create_dir_all(&output_path)?;
         let output_path = output_path.canonicalize()?;
 
         info!(input_path=?input_path, output_path=?output_path, "resolved input and output paths");
-        let config = read_config(&input_path)?;
+        let (mut config, mut excluded_targets) = read_config(&input_path, args.profile.as_deref())?;
+        match config.sanitize.as_ref() {
+            Some(options) => validate_sanitize_options(options)?,
+            None => (),
+        }
+        match config.vars_files.as_ref() {
+            Some(vars_files) => {
+                let mut vars = default_serde_object();
+                for vars_file in vars_files {
+                    merge(&mut vars, read_vars_file(&input_path, vars_file)?);
+                }
+                merge(
+                    &mut vars,
+                    config.vars.take().unwrap_or_else(default_serde_object),
+                );
+                config.vars = Some(vars);
+            }
+            None => (),
+        }
+        let mut aws_vars = AwsVars::default();
+        for vars in config
+            .vars
+            .iter_mut()
+            .chain(config.targets.iter_mut().flat_map(|t| t.vars.iter_mut()))
+        {
+            aws_vars.resolve(vars)?;
+        }
 
-        let template_path = input_path.join(&config.application_template);
+        let template_path =
+            check_within_input(&input_path, &input_path.join(&config.application_template))?;
         let template_name = template_path.strip_prefix(&input_path);
         let template_name = template_name?.display().to_string();
 
-        let tera_template_path = input_path.join("**/*.tera").to_string_lossy().to_string();
-        let mut tera = tera::Tera::new(&tera_template_path)?;
+        let mut inputs = Vec::new();
+        for (i, path) in input_paths.into_iter().enumerate() {
+            let mut input_config = match i {
+                0 => None,
+                _ => read_input_config(&path, &config)?,
+            };
+            for input_config in input_config.iter_mut() {
+                for vars in input_config.vars.iter_mut().chain(
+                    input_config
+                        .targets
+                        .iter_mut()
+                        .flat_map(|t| t.vars.iter_mut()),
+                ) {
+                    aws_vars.resolve(vars)?;
+                }
+            }
+            let ignore_dirs = input_ignore_dirs(
+                &path,
+                match input_config.as_ref() {
+                    Some(input_config) => &input_config.ignore_dirs,
+                    None => &config.ignore_dirs,
+                },
+                std::iter::once(&output_path).chain(args.extra_ignore_dirs.iter()),
+            )?;
+            inputs.push(Input {
+                template_prefix: match i {
+                    0 => String::new(),
+                    _ => format!("input-{}/", i),
+                },
+                path,
+                ignore_dirs,
+                config: input_config,
+                template_names: HashSet::new(),
+            });
+        }
+
+        let path_filters = args
+            .path_filter
+            .iter()
+            .map(|filter| glob::Pattern::new(filter.trim_end_matches('/')))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| anyhow!("invalid path filter: {}", e))?;
+
+        let mut tera = tera::Tera::default();
+        // templated files are added under their own path, html and xml files among them mustn't be
+        // escaped
+        tera.autoescape_on(vec![]);
+        let mut template_files = Vec::new();
+        for input in inputs.iter() {
+            let walk_options = WalkOptions {
+                include_hidden_dirs: config.include_hidden_dirs.unwrap_or(false),
+                // app templates can be nested anywhere within an app dir
+                max_depth: None,
+            };
+            template_files.push(discover_files(
+                &input.path,
+                &input.ignore_dirs,
+                walk_options,
+                &|p| p.extension().unwrap_or_default() == "tera",
+            )?);
+        }
+        // the filters script and plugins are hashed into every app's input hash since they change
+        // the output
+        let filters_script = config
+            .filters_script
+            .as_ref()
+            .map(|script| input_path.join(script));
+        let plugin_paths = config
+            .plugins
+            .iter()
+            .flatten()
+            .map(|plugin| input_path.join(plugin))
+            .collect::<Vec<_>>();
+        for path in filters_script.iter().chain(plugin_paths.iter()) {
+            check_within_input(&input_path, path)?;
+        }
+        let scripts_hash = hash_files(
+            &input_path,
+            &filters_script
+                .iter()
+                .chain(plugin_paths.iter())
+                .cloned()
+                .collect::<Vec<_>>(),
+        )?;
+        for (input, files) in inputs.iter_mut().zip(template_files.iter()) {
+            for file in files {
+                input
+                    .template_names
+                    .insert(file.strip_prefix(&input.path)?.display().to_string());
+            }
+        }
+        let mut template_deps = HashMap::new();
+        let mut raw_templates = Vec::new();
+        for (input, files) in inputs.iter().zip(template_files.iter()) {
+            for file in files {
+                let contents = input.read_template(file)?;
+                let name = input.template_name(file)?;
+                template_deps.insert(
+                    name.clone(),
+                    TemplateDeps {
+                        hash: format!("{:x}", Sha256::digest(&contents)),
+                        dependencies: template_dependencies(&contents),
+                        dynamic_calls: dynamic_calls(&contents),
+                    },
+                );
+                raw_templates.push((name, contents));
+            }
+        }
+        let plugins = plugin_paths
+            .iter()
+            .map(|plugin| Plugin::load(plugin))
+            .collect::<Result<Vec<_>>>()?;
+        tera.add_raw_templates(raw_templates)?;
         tera.register_filter("yaml_encode", yaml_encode_filter);
         tera.register_filter("nindent", nindent_filter);
+        tera.register_filter("merge", merge_filter);
+        tera.register_filter("deep_merge", merge_filter);
+        tera.register_filter("from_yaml", from_yaml_filter);
+        tera.register_filter("from_json", from_json_filter);
+        tera.register_filter("semver_compare", semver_compare_filter);
+        tera.register_filter("semver_matches", semver_matches_filter);
+        tera.register_filter("regex_replace", regex_replace_filter);
+        tera.register_filter("to_toml", to_toml_filter);
+        tera.register_filter(
+            "k8s_name",
+            k8s_name_filter(config.sanitize.clone().unwrap_or_default()),
+        );
+        tera.register_function("resolve_tag", registry::ResolveTag::default());
+        let app_lookup = LookupApp::default();
+        tera.register_function("lookup_app", app_lookup.clone());
+        tera.register_function("dir_hash", app_files::DirHash::default());
+        tera.register_function("glob", app_files::glob_function);
+        let clock = Clock::new(args.frozen_time.as_deref())?;
+        tera.register_function("now", clock);
+
+        let preview = match args.command.as_ref() {
+            Some(Command::Preview { name, base, ttl }) => {
+                let preview = Preview::new(name, base, ttl, clock)?;
+                excluded_targets.extend(preview.replace_targets(&mut config)?);
+                Some(preview)
+            }
+            _ => None,
+        };
+        tera.register_function("uuid_v5", stable_ids::uuid_v5_function);
+        tera.register_function("seeded_int", stable_ids::seeded_int_function);
+        tera.register_function("seeded_choice", stable_ids::seeded_choice_function);
+        match filters_script {
+            Some(filters_script) => scripting::register_filters(&mut tera, &filters_script)?,
+            None => (),
+        }
+
+        let provenance = match config.provenance.as_ref() {
+            Some(options) => Some(provenance(&input_path, options, clock)?),
+            None => None,
+        };
+
+        let script_path = match config.script_allowed_commands.as_ref() {
+            Some(commands) => Some(script_path(commands)?),
+            None => None,
+        };
+
+        let upload = match args.upload_url {
+            Some(url) => Some(Upload::new(url, args.upload_sse)?),
+            None => None,
+        };
+
+        let lockfile = config.lockfile.unwrap_or(false) && args.command.is_none() && !args.stdout;
+
+        let thread_pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(args.jobs.unwrap_or_default())
+            .build()?;
 
         return Ok(ProjectProcessor {
             input_path,
+            staging_path: output_path.join(".bargo-staging"),
             output_path,
             application_template_name: template_name,
             targets: HashMap::new(),
             config,
             tera,
+            app_lookup,
+            inputs,
+            path_filters,
+            thread_pool,
+            temp_output_dir,
+            keep_output: args.keep_output,
+            selected_targets: args.targets,
+            stdout: args.stdout,
+            use_cache: args.cache,
+            scripts_hash,
+            template_deps,
+            raw_templates: HashSet::new(),
+            timings: Timings::new(args.timing.is_some()),
+            slowest_apps_to_report: args.timing,
+            keep_going: args.keep_going,
+            allow_unknown_targets: args.allow_unknown_targets,
+            excluded_targets,
+            var_overrides: parse_var_overrides(&args.set_json, &args.vars)?,
+            application_option_overrides: parse_json_overrides(
+                "--set-application-options-json",
+                &args.set_application_options_json,
+            )?,
+            vars_by_target: HashMap::new(),
+            previous_cache: Cache::new(),
+            cache: Cache::new(),
+            application_source_paths: Vec::new(),
+            application_namespaces: Vec::new(),
+            inventory: HashMap::new(),
+            upload,
+            provenance,
+            plugins,
+            debug_context: args.debug_context,
+            ownership_report_path: args.ownership_report,
+            lockfile,
+            allow_prune: args.allow_prune,
+            tombstones: HashSet::new(),
+            started_at: Instant::now(),
+            failed_apps: 0,
+            changed_apps: Vec::new(),
+            preview,
+            ownership_report: OwnershipReport::default(),
+            script_path,
         });
     }
 
     pub fn process(&mut self) -> Result<()> {
-        let mut vars_by_target: HashMap<String, serde_json::Value> = HashMap::new();
+        let _process_span = info_span!("process", input_path=?self.input_path).entered();
+        if self.use_cache {
+            self.previous_cache = self.read_cache()?;
+        }
+
+        // clean up anything left behind by a previous failed run
+        if self.staging_path.exists() {
+            fs::remove_dir_all(&self.staging_path)?;
+        }
 
         for target in &self.config.targets {
-            let target_dir = self.output_path.join(&target.name);
-            if target_dir.exists() {
-                fs::remove_dir_all(&target_dir)?;
+            if !self.is_selected_target(&target.name) {
+                continue;
             }
-            fs::create_dir_all(target_dir)?;
 
-            self.targets.insert(target.name.clone(), HashMap::new());
+            let target_dir = self.staging_path.join(&target.name);
+            fs::create_dir_all(target_dir.join("argocd-config"))?;
 
-            let mut merged_vars = self
-                .config
-                .vars
-                .clone()
-                .unwrap_or_else(default_serde_object);
-            merge(
-                &mut merged_vars,
-                target.vars.clone().unwrap_or_else(default_serde_object),
+            self.targets.insert(target.name.clone(), HashMap::new());
+            self.vars_by_target.insert(
+                target.name.clone(),
+                global_and_target_vars(&self.config, target),
             );
-            vars_by_target.insert(target.name.clone(), merged_vars);
         }
 
-        for metadata_file in glob::glob(self.input_path.join("**/metadata.toml").to_str().unwrap())?
-        {
-            let metadata_file = metadata_file
-                .or_else(|e| Err(anyhow!("failed to glob for metadata.toml files: {}", e)))?;
+        let metadata_files = self
+            .timings
+            .time(Phase::Discovery, None, None, || self.find_metadata_files())?;
+        self.timings.time(Phase::Discovery, None, None, || {
+            self.register_apps(&metadata_files);
+            Ok::<_, anyhow::Error>(())
+        })?;
 
-            info!(file = ?metadata_file, "processing file");
-            let metadata = read_metadata(metadata_file.as_path())?;
-
-            let app_dir = metadata_file.parent().ok_or(anyhow!(
-                "unable to find parent associated with metadata.toml file ({:?})",
-                metadata_file
-            ))?;
+        let mut failures = Vec::new();
+        for metadata_file in metadata_files {
+            let result = self
+                .process_metadata_file(&metadata_file, &mut failures)
+                .or_else(|e| self.record_failure(&mut failures, e));
+            if result.is_err() {
+                // without --keep-going the run stops at the first failure
+                self.failed_apps = failures.len() + 1;
+                return result;
+            }
+        }
 
-            for target in metadata.targets.iter() {
-                if !self.targets.contains_key(&target.name) {
-                    warn!(target=target.name, path=?app_dir, "skipping unknown target");
-                    continue;
-                }
+        if !failures.is_empty() {
+            self.failed_apps = failures.len();
+            return Err(anyhow!(
+                "{} app(s) failed, no output was written:\n{}",
+                failures.len(),
+                failures
+                    .iter()
+                    .map(|e| format!("  {:#}", e))
+                    .collect::<Vec<_>>()
+                    .join("\n")
+            ));
+        }
 
-                let app_context =
-                    self.template_context_for_dir(app_dir, &target.name, &metadata)?;
+        self.validate_application_namespaces()?;
+        let lockfile = match self.lockfile {
+            true => Some(self.update_lockfile()?),
+            false => None,
+        };
 
-                self.create_or_update_app_project_for_dir(&target.name, &metadata, &app_context);
-                let argo_application = self.generate_argo_application_for_dir(
-                    &metadata.application_options,
-                    &app_context,
-                )?;
+        match self.ownership_report_path.as_ref() {
+            Some(path) => self.ownership_report.write(path)?,
+            None => (),
+        }
 
-                let target_project = self
-                    .targets
-                    .get_mut(&target.name)
-                    .unwrap()
-                    .get_mut(&app_context.project)
-                    .unwrap();
+        if self.stdout {
+            self.timings.time(Phase::Writing, None, None, || {
+                self.write_argocd_config_to_stdout()
+            })?;
+            fs::remove_dir_all(&self.staging_path)?;
+            return Ok(());
+        }
 
-                target_project.applications.push(argo_application);
+        self.validate_application_source_paths()?;
 
-                let out_folder_path = self.output_path.join(&app_context.path);
-                // debug!(from_path=?self.input_path, to_path=?out_folder_path, "copying");
+        self.thread_pool.install(|| {
+            self.targets
+                .par_iter()
+                .try_for_each(|(target_name, target)| {
+                    self.timings
+                        .time(Phase::Writing, Some(target_name), None, || {
+                            self.write_argocd_config(target_name, target)?;
+                            match self.config.helm_chart.as_ref() {
+                                Some(options) => helm_chart::write_chart(
+                                    &self.staging_path.join(target_name),
+                                    target_name,
+                                    options,
+                                ),
+                                None => Ok(()),
+                            }
+                        })
+                })
+        })?;
 
-                let mut target_vars = vars_by_target.get(&target.name).unwrap().clone(); // unwrap since the value should always be there due to the above for loop
-                merge(
-                    &mut target_vars,
-                    target.vars.clone().unwrap_or_else(default_serde_object),
-                );
+        if self.config.webhooks.is_some() {
+            self.changed_apps = self.changed_apps()?;
+        }
+        for target_name in self.targets.keys() {
+            self.timings
+                .time(Phase::Writing, Some(target_name), None, || {
+                    self.swap_staged_target(target_name)
+                })?;
+        }
+        fs::remove_dir_all(&self.staging_path)?;
 
-                self.copy_and_template_folder(
-                    &target_vars,
-                    &app_dir.to_path_buf(),
-                    &out_folder_path,
-                )?;
+        match self.upload.as_ref() {
+            Some(upload) => {
+                for target_name in self.targets.keys() {
+                    self.timings
+                        .time(Phase::Writing, Some(target_name), None, || {
+                            upload.upload_target(&self.output_path.join(target_name), target_name)
+                        })?;
+                }
+            }
+            None => (),
+        }
 
-                self.write_bargo_values(&target_vars, &app_context, &out_folder_path)?;
-
-                match metadata.script.as_ref() {
-                    Some(script) => {
-                        let output = std::process::Command::new("bash")
-                            .arg("-c")
-                            .arg(script)
-                            .env("in", app_dir)
-                            .env("out", out_folder_path)
-                            .output()?;
-                        info!(output=?output, "script output");
-                        if !output.status.success() {
-                            return Err(anyhow!(
-                                "script exited with a non zero: {:?}",
-                                output.stdout
-                            ));
-                        }
-                    }
-                    None => (),
-                };
+        if self.use_cache {
+            // keep the entries of targets which weren't processed this run
+            let mut cache = std::mem::take(&mut self.cache);
+            for (target_name, hashes) in std::mem::take(&mut self.previous_cache) {
+                if !self.targets.contains_key(&target_name) {
+                    cache.insert(target_name, hashes);
+                }
             }
+            self.write_cache(&cache)?;
         }
 
-        for (target_name, target) in self.targets.iter() {
-            let config_dir = self.output_path.join(target_name).join("argocd-config");
-            fs::create_dir_all(&config_dir)?;
+        match lockfile {
+            Some(lockfile) => lockfile.write(&self.input_path)?,
+            None => (),
+        }
 
-            // write application file for argo_cd
+        return Ok(());
+    }
 
-            let mut file = fs::File::create(config_dir.join("argocd-config.yaml"))?;
-            let app = self.generate_argo_application_for_dir(
-                &self.config.argocd_config_application_options,
-                &TemplateContext {
-                    namespace: self.config.argocd_namespace.clone(),
-                    project: "default".to_string(),
-                    app_name: "argocd-config".to_string(),
-                    normalized_project: "default".to_string(),
-                    normalized_app_name: "argocd-config".to_string(),
-                    path: format!("{}/argocd-config", target_name),
-                    target_name: target_name.to_string(),
-                },
-            )?;
-            file.write_all(app.as_bytes())?;
-            // write application files for all folders
-            for (project_name, project) in target.iter() {
-                let mut file =
-                    fs::File::create(config_dir.join(format!("{:}.yaml", project_name)))?;
+    // moves a fully generated target from the staging directory into the output directory. The
+    // previous output is first renamed out of the way since a directory can't be renamed over a
+    // non empty one, both renames stay within the output directory so each is atomic
+    fn swap_staged_target(&self, target_name: &str) -> Result<()> {
+        let target_dir = self.output_path.join(target_name);
+        let staged_target_dir = self.staging_path.join(target_name);
+        let previous_target_dir = self.staging_path.join(format!("{}.previous", target_name));
 
-                file.write_all(serde_yaml::to_string(&project.project)?.as_bytes())?;
-                for app in project.applications.iter() {
-                    file.write_all(b"\n---\n")?;
-                    file.write_all(app.as_bytes())?;
-                }
-            }
+        if target_dir.exists() {
+            fs::rename(&target_dir, &previous_target_dir)?;
+        }
+        fs::rename(&staged_target_dir, &target_dir).map_err(|e| {
+            anyhow!(
+                "failed to move staged target {:?} into place at {:?}: {}",
+                staged_target_dir,
+                target_dir,
+                e
+            )
+        })?;
+        if previous_target_dir.exists() {
+            fs::remove_dir_all(&previous_target_dir)?;
         }
 
+        info!(target = target_name, ?target_dir, "wrote target");
         return Ok(());
     }
 
-    fn generate_argo_application_for_dir(
+    fn read_cache(&self) -> Result<Cache> {
+        let cache_path = self.output_path.join(CACHE_FILE_NAME);
+        if !cache_path.exists() {
+            return Ok(Cache::new());
+        }
+
+        let cache = fs::read(&cache_path)
+            .map_err(|e| anyhow!("failed to read cache file {:?}: {}", cache_path, e))?;
+        return match serde_json::from_slice(&cache) {
+            Ok(cache) => Ok(cache),
+            Err(e) => {
+                warn!(?cache_path, error=?e, "ignoring invalid cache file");
+                Ok(Cache::new())
+            }
+        };
+    }
+
+    fn write_cache(&self, cache: &Cache) -> Result<()> {
+        let cache_path = self.output_path.join(CACHE_FILE_NAME);
+        fs::write(&cache_path, serde_json::to_vec_pretty(cache)?)
+            .map_err(|e| anyhow!("failed to write cache file {:?}: {}", cache_path, e))?;
+        return Ok(());
+    }
+
+    // hashes everything that affects an app's generated files: the app directory (including its
+    // metadata file), the templates its files depend on, the merged vars and the template context,
+    // plus every app's context when it calls lookup_app. None when the app can't be cached since it
+    // calls resolve_tag, whose result depends on the tags in the registry
+    fn app_input_hash(
         &self,
-        application_options: &Option<serde_json::Value>,
+        app_dir: &path::Path,
+        vars: &serde_json::Value,
         app_context: &TemplateContext,
-    ) -> Result<String> {
-        let mut template_context = self
-            .config
-            .default_application_options
-            .clone()
-            .unwrap_or_else(default_serde_object);
+    ) -> Result<Option<String>> {
+        let app_files = find_files(app_dir, &[], &|_| true)?;
+        let references = self.app_template_references(&app_files)?;
+        if references.dynamic_calls.contains("resolve_tag") {
+            return Ok(None);
+        }
 
-        merge(
-            &mut template_context,
-            application_options
-                .clone()
-                .unwrap_or_else(default_serde_object),
+        let mut hasher = Sha256::new();
+        hasher.update(env!("CARGO_PKG_VERSION").as_bytes());
+        hasher.update(self.scripts_hash.as_bytes());
+        hasher.update(
+            self.dependent_templates_hash(references.dependencies.as_ref())
+                .as_bytes(),
         );
-
-        merge(&mut template_context, serde_json::to_value(app_context)?);
-
-        return self.render_template(&self.application_template_name, template_context);
+        hasher.update(serde_json::to_vec(vars)?);
+        hasher.update(serde_json::to_vec(app_context)?);
+        hasher.update(self.output_options_hash()?.as_bytes());
+        hasher.update(hash_files(app_dir, &app_files)?.as_bytes());
+        if references.dynamic_calls.contains("lookup_app") {
+            hasher.update(self.app_lookup.hash()?.as_bytes());
+        }
+        return Ok(Some(format!("{:x}", hasher.finalize())));
     }
 
-    fn create_or_update_app_project_for_dir(
-        &mut self,
-        target_name: &str,
-        metadata: &Metadata,
-        app_context: &TemplateContext,
-    ) {
-        let project = self
-            .targets
-            .get_mut(target_name)
-            .unwrap()
-            .entry(app_context.normalized_project.clone())
-            .or_insert(ArgoCDProject {
-                project: AppProject::new(
-                    app_context.normalized_project.clone(),
-                    self.config.argocd_namespace.clone(),
-                ),
-                applications: Vec::new(),
-            });
-        // set all the array like things are using hashsets we can ruthleslsly add everything and
-        // duplicates will get auto dedupped
-        project
-            .project
-            .spec
-            .source_repos
-            .insert(self.config.argocd_source_repo.clone());
+    // hashes the config options which shape an app's output dir. Options are included unless they're
+    // known not to, so new options invalidate the cache rather than leaving stale output behind
+    fn output_options_hash(&self) -> Result<String> {
+        let mut config = serde_json::to_value(&self.config)?;
+        let options = config
+            .as_object_mut()
+            .ok_or_else(|| anyhow!("config didn't serialize to an object"))?;
+        for key in NON_OUTPUT_CONFIG_KEYS {
+            options.remove(key);
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(serde_json::to_vec(options)?);
+        return Ok(format!("{:x}", hasher.finalize()));
+    }
 
-        project
-            .project
-            .spec
-            .destinations
-            .insert(AppProjectDestination {
-                name: "in-cluster".to_string(),
-                namespace: app_context.namespace.clone(),
-                server: "https://kubernetes.devault.svc".to_string(),
-            });
+    // hashes every template the app's files include, import or extends, following the templates'
+    // own dependencies. All templates are hashed when a dependency isn't named by a string literal
+    // since it can't be resolved without rendering
+    fn dependent_templates_hash(&self, dependencies: Option<&BTreeSet<String>>) -> String {
+        let dependencies = match dependencies {
+            Some(dependencies) => dependencies,
+            None => return self.all_templates_hash(),
+        };
 
-        project.project.spec.cluster_resource_whitelist.insert(
-            AppProjectClusterResourceWhitelist {
-                group: "".to_string(),
-                kind: "Namespace".to_string(),
-            },
-        );
+        let mut hasher = Sha256::new();
+        for name in dependencies {
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(self.template_deps[name].hash.as_bytes());
+        }
+        return format!("{:x}", hasher.finalize());
+    }
 
-        match metadata.project_options.as_ref() {
-            Some(options) => {
-                match options.additional_namespaces.as_ref() {
-                    Some(additional_namespaces) => {
-                        for namespace in additional_namespaces.iter() {
-                            project
-                                .project
-                                .spec
-                                .destinations
-                                .insert(AppProjectDestination {
-                                    name: "in-cluster".to_string(),
-                                    namespace: namespace.to_string(),
-                                    server: "https://kubernetes.devault.svc".to_string(),
-                                });
-                        }
-                    }
-                    None => (),
+    // the templates the app's files depend on and the dynamic functions called by the files or
+    // those templates, every template counts when the dependencies can't be resolved
+    fn app_template_references(
+        &self,
+        app_files: &[path::PathBuf],
+    ) -> Result<AppTemplateReferences> {
+        let mut names = Some(Vec::new());
+        let mut calls = BTreeSet::new();
+        for file in app_files {
+            // binary files can't include templates
+            let contents = match fs::read_to_string(file) {
+                Ok(contents) => contents,
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(anyhow!("failed to read file {:?}: {}", file, e)),
+            };
+            calls.extend(dynamic_calls(&contents));
+            match (names.as_mut(), template_dependencies(&contents)) {
+                (Some(names), Some(dependencies)) => names.extend(dependencies),
+                _ => names = None,
+            }
+        }
+        let dependencies = names.and_then(|names| self.template_closure(names));
+        match dependencies.as_ref() {
+            Some(dependencies) => {
+                for name in dependencies {
+                    calls.extend(self.template_deps[name].dynamic_calls.iter().cloned());
                 }
-
-                match options.cluster_resource_whitelist.as_ref() {
-                    Some(cluster_resource_whitelist) => {
-                        for allow_list_item in cluster_resource_whitelist.iter() {
-                            project
-                                .project
-                                .spec
-                                .cluster_resource_whitelist
-                                .insert(allow_list_item.clone());
-                        }
-                    }
-                    None => (),
+            }
+            None => {
+                for deps in self.template_deps.values() {
+                    calls.extend(deps.dynamic_calls.iter().cloned());
                 }
             }
-            None => (),
         }
+        return Ok(AppTemplateReferences {
+            dependencies,
+            dynamic_calls: calls,
+        });
     }
 
-    fn template_context_for_dir(
-        &self,
-        app_dir: &path::Path,
-        target_name: &str,
-        metadata: &Metadata,
-    ) -> Result<crate::TemplateContext> {
-        let project = app_dir
-            .parent()
-            .ok_or(anyhow!(
-                "unable to determine project name from folder structure for {:?}",
-                app_dir
-            ))?
-            .file_name()
-            .ok_or(anyhow!(
-                "unable to determine project name from folder structure for {:?}",
-                app_dir
-            ))?
-            .to_string_lossy()
-            .to_string();
+    // the given templates along with every template they depend on, None when a dependency can't
+    // be resolved
+    fn template_closure(&self, mut pending: Vec<String>) -> Option<BTreeSet<String>> {
+        let mut dependencies = BTreeSet::new();
+        while let Some(name) = pending.pop() {
+            // unknown templates fail when rendering
+            let deps = match self.template_deps.get(&name) {
+                Some(deps) => deps,
+                None => continue,
+            };
+            if !dependencies.insert(name) {
+                continue;
+            }
+            pending.extend(deps.dependencies.as_ref()?.iter().cloned());
+        }
+        return Some(dependencies);
+    }
 
-        let app_name = app_dir
-            .file_name()
-            .ok_or(anyhow!(
-                "unable to determine app name from folder structure for {:?}",
-                app_dir
-            ))?
-            .to_string_lossy()
-            .to_string();
+    fn all_templates_hash(&self) -> String {
+        let mut templates = self.template_deps.iter().collect::<Vec<_>>();
+        templates.sort_by(|a, b| a.0.cmp(b.0));
+        let mut hasher = Sha256::new();
+        for (name, deps) in templates {
+            hasher.update(name.as_bytes());
+            hasher.update([0]);
+            hasher.update(deps.hash.as_bytes());
+        }
+        return format!("{:x}", hasher.finalize());
+    }
 
-        let out_path = path::PathBuf::new()
-            .join(&target_name)
-            .join(&project)
-            .join(&app_name);
+    // in keep going mode failures are logged and collected so every failing app can be reported at
+    // the end, otherwise the first failure is returned
+    fn record_failure(
+        &self,
+        failures: &mut Vec<anyhow::Error>,
+        error: anyhow::Error,
+    ) -> Result<()> {
+        if !self.keep_going {
+            return Err(error);
+        }
 
-        return Ok(TemplateContext {
-            namespace: metadata
-                .namespace
-                .clone()
-                .unwrap_or(sanitize_name(&project)),
-            normalized_project: sanitize_name(&project),
-            normalized_app_name: sanitize_name(&app_name),
-            project,
-            app_name,
-            path: out_path.display().to_string(),
-            target_name: target_name.to_string(),
+        error!("{:#}", error);
+        failures.push(error);
+        return Ok(());
+    }
+
+    fn process_metadata_file(
+        &mut self,
+        metadata_file: &path::Path,
+        failures: &mut Vec<anyhow::Error>,
+    ) -> Result<()> {
+        info!(file = ?metadata_file, "processing file");
+        check_within_input(&self.input_for(metadata_file).path, metadata_file)?;
+        let app_dir = metadata_file.parent().ok_or(anyhow!(
+            "unable to find parent associated with metadata file ({:?})",
+            metadata_file
+        ))?;
+
+        // templated metadata is first rendered with the global vars to find its targets, then again
+        // with each target's vars
+        let template_metadata = self.config.template_metadata.unwrap_or(false);
+        let metadata = self.timings.time(Phase::Discovery, None, None, || {
+            self.read_discovery_metadata(metadata_file, app_dir)
+        })?;
+
+        if metadata.tombstone.unwrap_or(false) {
+            info!(path=?app_dir, "app is tombstoned, skipping");
+            let metadata_file = metadata_file
+                .strip_prefix(&self.input_for(metadata_file).path)?
+                .display()
+                .to_string();
+            self.tombstones.insert(metadata_file);
+            return Ok(());
+        }
+
+        for target in self.expand_metadata_targets(metadata_file, &metadata, Some(failures))? {
+            if !self.targets.contains_key(&target.name) {
+                debug!(target=target.name, path=?app_dir, "skipping unselected target");
+                continue;
+            }
+
+            let result = match template_metadata {
+                true => self
+                    .render_target_metadata(metadata_file, app_dir, &target.name)
+                    .and_then(|rendered| match rendered {
+                        Some((metadata, target)) => {
+                            self.process_app_target(app_dir, &metadata, &target)
+                        }
+                        None => Ok(()),
+                    }),
+                false => self.process_app_target(app_dir, &metadata, &target),
+            };
+            if let Err(e) = result {
+                self.record_failure(
+                    failures,
+                    anyhow!("{} (target {}): {:#}", app_dir.display(), target.name, e),
+                )?;
+            }
+        }
+        return Ok(());
+    }
+
+    // registers the context of every app for each of its selected targets with lookup_app before
+    // any app is rendered. Apps which fail to load are skipped, they fail again when processed
+    fn register_apps(&self, metadata_files: &[path::PathBuf]) {
+        for metadata_file in metadata_files {
+            if let Err(e) = self.register_app(metadata_file) {
+                debug!(file=?metadata_file, error=%e, "skipping app lookup registration");
+            }
+        }
+    }
+
+    fn register_app(&self, metadata_file: &path::Path) -> Result<()> {
+        let app_dir = metadata_file
+            .parent()
+            .ok_or(anyhow!("no parent for {:?}", metadata_file))?;
+        let template_metadata = self.config.template_metadata.unwrap_or(false);
+        let metadata = self.read_discovery_metadata(metadata_file, app_dir)?;
+
+        for target in self.expand_metadata_targets(metadata_file, &metadata, None)? {
+            if !self.targets.contains_key(&target.name) {
+                continue;
+            }
+            let rendered;
+            let (metadata, target) = match template_metadata {
+                true => match self.render_target_metadata(metadata_file, app_dir, &target.name)? {
+                    Some(target_metadata) => {
+                        rendered = target_metadata;
+                        (&rendered.0, &rendered.1)
+                    }
+                    None => continue,
+                },
+                false => (&metadata, &target),
+            };
+
+            let vars = self.app_target_vars(app_dir, target);
+            if !app_enabled(&metadata.enabled, &vars)? {
+                continue;
+            }
+            let app_context = self.template_context_for_dir(app_dir, target, metadata)?;
+            let mut context = serde_json::to_value(&app_context)?;
+            context["vars"] = vars;
+            self.app_lookup.register(
+                &app_context.project,
+                &app_context.app_name,
+                &target.name,
+                context,
+            );
+        }
+        return Ok(());
+    }
+
+    // prints the vars, application options and template context the app in app_dir is rendered
+    // with for each of its selected targets, along with where each var and option was set
+    pub fn explain(&mut self, app_dir: &path::Path) -> Result<()> {
+        let app_dir = app_dir
+            .canonicalize()
+            .map_err(|e| anyhow!("failed to find app dir {:?}: {}", app_dir, e))?;
+        check_within_input(&self.input_for(&app_dir).path, &app_dir)?;
+        let metadata_file = app_dir.join(self.metadata_file_name());
+        if !metadata_file.exists() {
+            return Err(anyhow!("{:?} has no metadata file", app_dir));
+        }
+        for target in &self.config.targets {
+            self.vars_by_target.insert(
+                target.name.clone(),
+                global_and_target_vars(&self.config, target),
+            );
+        }
+
+        let template_metadata = self.config.template_metadata.unwrap_or(false);
+        let metadata = self.read_discovery_metadata(&metadata_file, &app_dir)?;
+
+        let mut documents = Vec::new();
+        for target in self.expand_metadata_targets(&metadata_file, &metadata, None)? {
+            if !self.is_selected_target(&target.name) {
+                continue;
+            }
+            let explained = match template_metadata {
+                true => {
+                    match self.render_target_metadata(&metadata_file, &app_dir, &target.name)? {
+                        Some((metadata, target)) => {
+                            self.explain_target(&app_dir, &metadata, &target)?
+                        }
+                        None => continue,
+                    }
+                }
+                false => self.explain_target(&app_dir, &metadata, &target)?,
+            };
+            documents.push(serde_yaml::to_string(&explained)?);
+        }
+
+        if documents.is_empty() {
+            return Err(anyhow!(
+                "{:?} isn't generated for any of the selected targets",
+                app_dir
+            ));
+        }
+        print!("{}", documents.join("---\n"));
+        return Ok(());
+    }
+
+    fn explain_target(
+        &self,
+        app_dir: &path::Path,
+        metadata: &Metadata,
+        target: &MetadataTarget,
+    ) -> Result<Explanation> {
+        // unwrap since expanded targets always exist in the config
+        let config_target = self
+            .config
+            .targets
+            .iter()
+            .find(|t| t.name == target.name)
+            .unwrap();
+        let input_config = self.input_for(app_dir).config.as_ref();
+        let input_target = input_config
+            .and_then(|input_config| input_config.targets.iter().find(|t| t.name == target.name));
+        let mut vars = self.target_vars(app_dir, &target.name).unwrap_or_default();
+        merge(
+            &mut vars,
+            target.vars.clone().unwrap_or_else(default_serde_object),
+        );
+        merge(&mut vars, self.var_overrides.clone());
+
+        return Ok(Explanation {
+            target: target.name.clone(),
+            enabled: app_enabled(&metadata.enabled, &vars)?,
+            vars: explain_layers(&[
+                ("global", self.config.vars.as_ref()),
+                ("target", config_target.vars.as_ref()),
+                (
+                    "input",
+                    input_config.and_then(|input_config| input_config.vars.as_ref()),
+                ),
+                (
+                    "input target",
+                    input_target.and_then(|target| target.vars.as_ref()),
+                ),
+                ("app", target.vars.as_ref()),
+                ("overrides", Some(&self.var_overrides)),
+            ]),
+            application_options: explain_layers(&[
+                ("global", self.config.default_application_options.as_ref()),
+                ("app", metadata.application_options.as_ref()),
+                ("overrides", Some(&self.application_option_overrides)),
+            ]),
+            template_context: self.template_context_for_dir(app_dir, target, metadata)?,
         });
     }
 
-    fn render_template(
+    // prints the target, project, app and source dir of every app whose output may differ between
+    // the changed_since git ref and the working tree as a json array, without rendering anything.
+    // Apps are affected by changes to files in their dir or to templates they include, and every
+    // app is affected by changes to bargo.toml, the application template, the filters script or
+    // plugins. Apps removed since the ref aren't listed
+    pub fn affected(&self, changed_since: &str) -> Result<()> {
+        let mut changed_files = Vec::new();
+        for input in self.inputs.iter() {
+            let diff = git(
+                &input.path,
+                &["diff", "--name-only", "--relative", changed_since],
+            )?;
+            let untracked = git(&input.path, &["ls-files", "--others", "--exclude-standard"])?;
+            changed_files.extend(
+                diff.lines()
+                    .chain(untracked.lines())
+                    .filter(|file| !file.is_empty())
+                    .map(|file| input.path.join(file)),
+            );
+        }
+        let changed_templates = changed_files
+            .iter()
+            .filter_map(|file| self.input_for(file).template_name(file).ok())
+            .filter(|name| self.template_deps.contains_key(name))
+            .collect::<BTreeSet<_>>();
+        debug!(?changed_files, ?changed_templates, "found changed files");
+
+        let global_files = std::iter::once(self.input_path.join("bargo.toml"))
+            .chain(
+                self.config
+                    .filters_script
+                    .iter()
+                    .chain(self.config.plugins.iter().flatten())
+                    .map(|file| self.input_path.join(file)),
+            )
+            .collect::<Vec<_>>();
+        let all_affected = changed_files.iter().any(|file| global_files.contains(file))
+            || match self.template_closure(vec![self.application_template_name.clone()]) {
+                Some(templates) => !templates.is_disjoint(&changed_templates),
+                None => !changed_templates.is_empty(),
+            };
+
+        let mut affected = Vec::new();
+        for metadata_file in self.find_metadata_files()? {
+            // unwrap since metadata files are always found within a dir
+            let app_dir = metadata_file.parent().unwrap();
+            let input = self.input_for(app_dir);
+            let app_affected = all_affected
+                || changed_files.iter().any(|file| {
+                    file.starts_with(app_dir) || *file == input.path.join("bargo.toml")
+                })
+                || match self
+                    .app_template_references(&find_files(app_dir, &[], &|_| true)?)?
+                    .dependencies
+                {
+                    Some(templates) => !templates.is_disjoint(&changed_templates),
+                    None => !changed_templates.is_empty(),
+                };
+            if !app_affected {
+                continue;
+            }
+
+            let metadata = self.read_discovery_metadata(&metadata_file, app_dir)?;
+            let (project, app) = project_and_app_name(app_dir)?;
+            for target in self.expand_metadata_targets(&metadata_file, &metadata, None)? {
+                if !self.is_selected_target(&target.name) {
+                    continue;
+                }
+                affected.push(serde_json::json!({
+                    "target": target.name,
+                    "project": project,
+                    "app": app,
+                    "source_dir": app_dir.strip_prefix(&input.path)?,
+                }));
+            }
+        }
+        println!("{}", serde_json::to_string_pretty(&affected)?);
+        return Ok(());
+    }
+
+    // resolves the metadata targets against the config's targets. Names starting and ending with /
+    // are regexes and names containing glob characters are glob patterns, both expand to every
+    // matching target. A target matched by more than one entry gets the vars of each entry merged
+    // in the order they are listed. Unknown targets are only reported when failures is given
+    fn expand_metadata_targets(
         &self,
-        template_name: &str,
-        template_context: serde_json::Value,
-    ) -> Result<String> {
-        self.tera
-            .render(template_name, &tera::Context::from_value(template_context)?)
-            .map_err(|e| match e.source() {
-                Some(err_source) => anyhow!("{:#}", err_source),
-                None => anyhow!("{}", e),
-            })
+        metadata_file: &path::Path,
+        metadata: &Metadata,
+        mut failures: Option<&mut Vec<anyhow::Error>>,
+    ) -> Result<Vec<MetadataTarget>> {
+        let mut expanded: Vec<MetadataTarget> = Vec::new();
+        for target in metadata.targets.iter() {
+            let matcher = TargetMatcher::new(&target.name)
+                .map_err(|e| anyhow!("{}: {:#}", metadata_file.display(), e))?;
+            let matched_targets = self
+                .config
+                .targets
+                .iter()
+                .filter(|t| matcher.matches(self.base_target_name(&t.name)))
+                .collect::<Vec<_>>();
+
+            if matched_targets.is_empty() {
+                if self
+                    .excluded_targets
+                    .iter()
+                    .any(|name| matcher.matches(name))
+                {
+                    debug!(target=target.name, file=?metadata_file, "skipping target removed by the profile or preview");
+                    continue;
+                }
+                let failures = match failures.as_deref_mut() {
+                    Some(failures) => failures,
+                    None => continue,
+                };
+                if self.allow_unknown_targets {
+                    warn!(target=target.name, file=?metadata_file, "skipping unknown target");
+                    continue;
+                }
+                self.record_failure(
+                    failures,
+                    anyhow!(
+                        "{}: unknown target {:?}, expected one of {:?} (use --allow-unknown-targets to skip unknown targets)",
+                        metadata_file.display(),
+                        target.name,
+                        self.config.targets.iter().map(|t| &t.name).collect::<Vec<_>>()
+                    ),
+                )?;
+                continue;
+            }
+
+            for config_target in matched_targets {
+                match expanded.iter_mut().find(|t| t.name == config_target.name) {
+                    Some(existing) => {
+                        let vars = existing.vars.get_or_insert_with(default_serde_object);
+                        merge(
+                            vars,
+                            target.vars.clone().unwrap_or_else(default_serde_object),
+                        );
+                        if target.namespace.is_some() {
+                            existing.namespace = target.namespace.clone();
+                        }
+                    }
+                    None => expanded.push(MetadataTarget {
+                        name: config_target.name.clone(),
+                        namespace: target.namespace.clone(),
+                        vars: target.vars.clone(),
+                    }),
+                }
+            }
+        }
+        return Ok(expanded);
+    }
+
+    // reads a metadata file to find its targets. Templated metadata is rendered with the global vars,
+    // metadata which references vars only some targets set fails to render with them so it's then
+    // rendered with the vars of each target, its targets being every target listed by any of the
+    // renders
+    fn read_discovery_metadata(
+        &self,
+        metadata_file: &path::Path,
+        app_dir: &path::Path,
+    ) -> Result<Metadata> {
+        let deny_unknown_fields = self.config.deny_unknown_fields.unwrap_or(false);
+        if !self.config.template_metadata.unwrap_or(false) {
+            return read_metadata(metadata_file, deny_unknown_fields, None);
+        }
+        let global_error = match self
+            .metadata_template_context(app_dir, None)
+            .and_then(|context| read_metadata(metadata_file, deny_unknown_fields, Some(&context)))
+        {
+            Ok(metadata) => return Ok(metadata),
+            Err(e) => e,
+        };
+
+        let mut discovered: Option<Metadata> = None;
+        for target in self.config.targets.iter() {
+            let metadata = match self
+                .metadata_template_context(app_dir, Some(&target.name))
+                .and_then(|context| {
+                    read_metadata(metadata_file, deny_unknown_fields, Some(&context))
+                }) {
+                Ok(metadata) => metadata,
+                Err(e) => {
+                    debug!(target=target.name, file=?metadata_file, error=%format!("{:#}", e), "metadata doesn't render with the target's vars");
+                    continue;
+                }
+            };
+            match discovered.as_mut() {
+                Some(discovered) => {
+                    for metadata_target in metadata.targets {
+                        if !discovered
+                            .targets
+                            .iter()
+                            .any(|t| t.name == metadata_target.name)
+                        {
+                            discovered.targets.push(metadata_target);
+                        }
+                    }
+                }
+                None => discovered = Some(metadata),
+            }
+        }
+        return discovered.ok_or(global_error);
+    }
+
+    // renders the metadata file with the target's vars, returning the rendered metadata and its
+    // entry for the target. None if the rendered metadata no longer lists the target
+    fn render_target_metadata(
+        &self,
+        metadata_file: &path::Path,
+        app_dir: &path::Path,
+        target_name: &str,
+    ) -> Result<Option<(Metadata, MetadataTarget)>> {
+        let metadata = read_metadata(
+            metadata_file,
+            self.config.deny_unknown_fields.unwrap_or(false),
+            Some(&self.metadata_template_context(app_dir, Some(target_name))?),
+        )?;
+        let target = self
+            .expand_metadata_targets(metadata_file, &metadata, None)?
+            .into_iter()
+            .find(|t| t.name == target_name);
+        return Ok(target.map(|target| (metadata, target)));
+    }
+
+    // context metadata files are rendered with when template_metadata is enabled: the global or
+    // target vars under vars along with the app's name, project and target name
+    fn metadata_template_context(
+        &self,
+        app_dir: &path::Path,
+        target_name: Option<&str>,
+    ) -> Result<serde_json::Value> {
+        let (project, app_name) = project_and_app_name(app_dir)?;
+        let vars = match target_name {
+            Some(target_name) => self.target_vars(app_dir, target_name),
+            None => {
+                let mut vars = default_serde_object();
+                merge(
+                    &mut vars,
+                    self.config
+                        .vars
+                        .clone()
+                        .unwrap_or_else(default_serde_object),
+                );
+                match self.input_for(app_dir).config.as_ref() {
+                    Some(input_config) => merge(
+                        &mut vars,
+                        input_config
+                            .vars
+                            .clone()
+                            .unwrap_or_else(default_serde_object),
+                    ),
+                    None => (),
+                }
+                Some(vars)
+            }
+        };
+        let mut vars = vars.unwrap_or_else(default_serde_object);
+        merge(&mut vars, self.var_overrides.clone());
+        return Ok(serde_json::json!({
+            "vars": vars,
+            "project": project,
+            "app_name": app_name,
+            "target_name": target_name.unwrap_or_default(),
+        }));
+    }
+
+    fn process_app_target(
+        &mut self,
+        app_dir: &path::Path,
+        metadata: &Metadata,
+        target: &MetadataTarget,
+    ) -> Result<()> {
+        let target_vars = self.app_target_vars(app_dir, target);
+
+        if !app_enabled(&metadata.enabled, &target_vars)? {
+            info!(target=target.name, path=?app_dir, "app disabled for target, skipping");
+            return Ok(());
+        }
+
+        let app_context = self.template_context_for_dir(app_dir, target, metadata)?;
+        // apps from different input paths can have the same project and app dir names
+        match self
+            .inventory
+            .get(&target.name)
+            .and_then(|entries| entries.iter().find(|e| e.path == app_context.path))
+        {
+            Some(entry) => {
+                return Err(anyhow!(
+                    "output path {} is already generated from {}",
+                    app_context.path,
+                    entry.metadata_file
+                ))
+            }
+            None => (),
+        }
+        let app_label = app_label(&app_context);
+        let timing_scope = (Some(target.name.as_str()), Some(app_label.as_str()));
+        let _app_span = info_span!("app", target = target.name, app = app_label).entered();
+
+        if self.debug_context && !self.stdout {
+            self.write_debug_context(
+                metadata,
+                &target_vars,
+                &app_context,
+                &self.staging_path.join(&app_context.path),
+            )?;
+        }
+
+        self.check_naming_policy(app_dir, metadata, &app_context)?;
+        self.create_or_update_app_project_for_dir(&target.name, metadata, &app_context)?;
+        self.ownership_report.add(metadata, &app_context);
+        let argo_application =
+            self.timings
+                .time(Phase::Templating, timing_scope.0, timing_scope.1, || {
+                    self.generate_argo_application_for_dir(
+                        &metadata.application_options,
+                        &app_context,
+                    )
+                })?;
+        let argo_application = self.add_notification_subscriptions(metadata, argo_application)?;
+        let argo_application = add_info_and_links(metadata, argo_application)?;
+        let argo_application = self.add_ownership_labels(metadata, argo_application)?;
+        let argo_application = self.add_env_sync_policy(&target.name, argo_application)?;
+        let argo_application = self.set_destination_by(&target.name, argo_application)?;
+        let argo_application = self.add_provenance_annotations(Some(app_dir), argo_application)?;
+
+        let metadata_file = app_dir.join(self.metadata_file_name());
+        let metadata_file = metadata_file
+            .strip_prefix(&self.input_for(app_dir).path)?
+            .display()
+            .to_string();
+        self.inventory
+            .entry(target.name.clone())
+            .or_default()
+            .push(InventoryEntry {
+                name: app_context.normalized_app_name.clone(),
+                project: app_context.normalized_project.clone(),
+                namespace: app_context.namespace.clone(),
+                path: app_context.path.clone(),
+                metadata_file,
+            });
+        for source_path in
+            application_source_paths(&argo_application, &self.config.argocd_source_repo)?
+        {
+            self.application_source_paths.push((
+                target.name.clone(),
+                app_label.clone(),
+                source_path,
+            ));
+        }
+
+        validate_kubernetes_names(&app_context, &argo_application)?;
+        for namespace in application_namespaces(&argo_application)? {
+            self.application_namespaces.push((
+                target.name.clone(),
+                app_label.clone(),
+                app_context.normalized_project.clone(),
+                namespace,
+            ));
+        }
+
+        // only the argocd-config documents are written in stdout mode
+        if self.stdout {
+            return self.append_application(&target.name, &app_context, &argo_application);
+        }
+
+        let out_folder_path = self.staging_path.join(&app_context.path);
+        // debug!(from_path=?self.input_path, to_path=?out_folder_path, "copying");
+
+        let previous_out_folder_path = self.output_path.join(&app_context.path);
+        let mut unchanged = false;
+        // hashing reads every file of the app, only do it when the hashes are used
+        if self.use_cache {
+            match self.app_input_hash(app_dir, &target_vars, &app_context)? {
+                Some(input_hash) => {
+                    unchanged = self
+                        .previous_cache
+                        .get(&target.name)
+                        .and_then(|hashes| hashes.get(&app_context.path))
+                        == Some(&input_hash);
+                    self.cache
+                        .entry(target.name.clone())
+                        .or_default()
+                        .insert(app_context.path.clone(), input_hash);
+                }
+                None => info!("app calls resolve_tag, not caching its output"),
+            }
+        }
+
+        if unchanged && previous_out_folder_path.exists() {
+            info!(path=?previous_out_folder_path, "app inputs unchanged, reusing previous output");
+            self.timings
+                .time(Phase::Copying, timing_scope.0, timing_scope.1, || {
+                    self.copy_folder(&previous_out_folder_path, &out_folder_path)
+                })?;
+        } else {
+            self.register_raw_templates(app_dir, metadata.template_all_files.unwrap_or(false))?;
+            self.write_app_output(
+                app_dir,
+                metadata,
+                &target.name,
+                &target_vars,
+                &app_context,
+                &out_folder_path,
+            )?;
+        }
+
+        // the application is only written once the app's output is complete so it can be
+        // annotated with the output's hash
+        let argo_application =
+            self.add_content_hash_annotation(&out_folder_path, argo_application)?;
+        return self.append_application(&target.name, &app_context, &argo_application);
+    }
+
+    // templates and copies the app's files into its output dir, runs its script and the plugins
+    // over them and then patches, labels and post processes the result
+    fn write_app_output(
+        &self,
+        app_dir: &path::Path,
+        metadata: &Metadata,
+        target_name: &str,
+        target_vars: &serde_json::Value,
+        app_context: &TemplateContext,
+        out_folder_path: &path::Path,
+    ) -> Result<()> {
+        let app_label = app_label(app_context);
+        let timing_scope = (Some(target_name), Some(app_label.as_str()));
+
+        self.copy_and_template_folder(target_vars, app_context, app_dir, out_folder_path)?;
+
+        self.timings
+            .time(Phase::Writing, timing_scope.0, timing_scope.1, || {
+                self.write_bargo_values(target_vars, app_context, out_folder_path)?;
+                self.write_generated_values(metadata, target_vars, out_folder_path)
+            })?;
+
+        match metadata.script.as_ref() {
+            Some(script) => {
+                let _script_span = info_span!("script").entered();
+                let output =
+                    self.timings
+                        .time(Phase::Scripts, timing_scope.0, timing_scope.1, || {
+                            self.script_command()
+                                .arg("-c")
+                                .arg(script)
+                                .env("in", app_dir)
+                                .env("out", out_folder_path)
+                                .output()
+                        })?;
+                info!(output=?output, "script output");
+                if !output.status.success() {
+                    return Err(anyhow!(
+                        "script exited with a non zero: {:?}",
+                        output.stdout
+                    ));
+                }
+            }
+            None => (),
+        };
+
+        for plugin in self.plugins.iter() {
+            self.timings
+                .time(Phase::Scripts, timing_scope.0, timing_scope.1, || {
+                    plugin.run(target_name, app_context, out_folder_path)
+                })?;
+        }
+        check_symlinks(out_folder_path, &self.staging_path)?;
+        self.timings
+            .time(Phase::Writing, timing_scope.0, timing_scope.1, || {
+                // patches and labels whatever the script or plugins generated too
+                self.write_kustomize_patch(metadata, target_name, target_vars, out_folder_path)?;
+                self.label_manifests(metadata, out_folder_path)?;
+                self.post_process_output(app_dir, out_folder_path)
+            })?;
+
+        return self.check_app_not_empty(out_folder_path);
+    }
+
+    // argo reports an app without any manifests as healthy, so catch apps where nothing was
+    // generated (eg. a script that produced no output)
+    fn check_app_not_empty(&self, out_folder_path: &path::Path) -> Result<()> {
+        let action = self.config.on_empty_app.unwrap_or_default();
+        if action == EmptyAppAction::Ignore {
+            return Ok(());
+        }
+
+        let manifests = find_files(out_folder_path, &[], &|p| {
+            let extension = p.extension().unwrap_or_default();
+            (extension == "yaml" || extension == "yml" || extension == "json")
+                && p.file_name().unwrap_or_default() != self.metadata_file_name().as_str()
+                && p.file_name().unwrap_or_default() != DEBUG_CONTEXT_FILE_NAME
+        })?;
+        if !manifests.is_empty() {
+            return Ok(());
+        }
+
+        let app_path = out_folder_path.strip_prefix(&self.staging_path)?;
+        if action == EmptyAppAction::Error {
+            return Err(anyhow!(
+                "no kubernetes manifests were generated in {:?}",
+                app_path
+            ));
+        }
+        warn!(path=?app_path, "no kubernetes manifests were generated for app");
+        return Ok(());
+    }
+
+    // bash, restricted to the allowed commands when script_allowed_commands is set
+    fn script_command(&self) -> std::process::Command {
+        match self.script_path.as_ref() {
+            Some(script_path) => {
+                let mut command = std::process::Command::new(&script_path.bash);
+                command.env("PATH", script_path.dir.path());
+                return command;
+            }
+            None => return std::process::Command::new("bash"),
+        }
+    }
+
+    fn metadata_file_name(&self) -> String {
+        return self
+            .config
+            .metadata_file_name
+            .clone()
+            .unwrap_or_else(|| "metadata.toml".to_string());
+    }
+
+    fn is_selected_target(&self, target_name: &str) -> bool {
+        return self.selected_targets.is_empty()
+            || self.selected_targets.iter().any(|t| t == target_name);
+    }
+
+    pub fn output_path(&self) -> &path::Path {
+        return &self.output_path;
+    }
+
+    pub fn input_path(&self) -> &path::Path {
+        return &self.input_path;
+    }
+
+    pub fn argocd_namespace(&self) -> &str {
+        return &self.config.argocd_namespace;
+    }
+
+    // every target in the config, whether or not it was selected
+    pub fn configured_targets(&self) -> Vec<String> {
+        return self.config.targets.iter().map(|t| t.name.clone()).collect();
+    }
+
+    // names of the targets generated by process, in config order
+    pub fn processed_targets(&self) -> Vec<String> {
+        return self
+            .config
+            .targets
+            .iter()
+            .filter(|t| self.targets.contains_key(&t.name))
+            .map(|t| t.name.clone())
+            .collect();
+    }
+
+    // posts a summary of the run to the configured webhooks
+    pub fn notify_webhooks(&self, result: &Result<()>) {
+        let webhooks = match self.config.webhooks.as_ref() {
+            Some(webhooks) => webhooks,
+            None => return,
+        };
+        let mut targets = self.targets.keys().cloned().collect::<Vec<_>>();
+        targets.sort();
+        let summary = RunSummary {
+            success: result.is_ok(),
+            input_path: self.input_path.display().to_string(),
+            targets,
+            applications: self.inventory.values().map(|entries| entries.len()).sum(),
+            changed_applications: self.changed_apps.clone(),
+            failed_applications: self.failed_apps,
+            error: result.as_ref().err().map(|e| format!("{:#}", e)),
+            duration_seconds: self.started_at.elapsed().as_secs_f64(),
+        };
+        webhook::notify(webhooks, &summary);
+    }
+
+    // output paths of the staged applications whose output differs from the current output
+    fn changed_apps(&self) -> Result<Vec<String>> {
+        let mut changed = Vec::new();
+        for entries in self.inventory.values() {
+            for entry in entries {
+                let diff = snapshot::compare_dirs(
+                    &self.output_path.join(&entry.path),
+                    &self.staging_path.join(&entry.path),
+                )?;
+                if !diff.is_empty() {
+                    changed.push(entry.path.clone());
+                }
+            }
+        }
+        changed.sort();
+        return Ok(changed);
+    }
+
+    // reports where the output was written, persisting the temporary output directory if
+    // requested
+    pub fn finish(&mut self) -> Result<()> {
+        if let Some(slowest_apps) = self.slowest_apps_to_report {
+            eprint!("{}", self.timings.report(slowest_apps));
+        }
+
+        if self.stdout {
+            return Ok(());
+        }
+
+        match self.upload.as_ref() {
+            Some(upload) => println!("output uploaded to {}", upload.url()),
+            None => (),
+        }
+
+        match self.temp_output_dir.take() {
+            Some(temp_output_dir) if !self.keep_output && self.upload.is_some() => {
+                drop(temp_output_dir);
+            }
+            Some(temp_output_dir) => {
+                temp_output_dir.into_path();
+                println!("output written to {}", self.output_path.display());
+            }
+            None => println!("output written to {}", self.output_path.display()),
+        }
+        return Ok(());
+    }
+
+    fn write_argocd_config(
+        &self,
+        target_name: &str,
+        target: &HashMap<String, ArgoCDProject>,
+    ) -> Result<()> {
+        let config_dir = self.staging_path.join(target_name).join("argocd-config");
+        fs::create_dir_all(&config_dir)?;
+
+        // every argocd-config file is rendered from the application template
+        let header = self
+            .generated_header(&self.application_template_name)
+            .unwrap_or_default();
+
+        // write application file for argo_cd
+
+        let mut file = fs::File::create(config_dir.join("argocd-config.yaml"))?;
+        let app = self.generate_argocd_config_application(target_name)?;
+        file.write_all(header.as_bytes())?;
+        file.write_all(app.as_bytes())?;
+
+        if self.config.write_inventory.unwrap_or(false) {
+            self.write_inventory(target_name, &config_dir)?;
+        }
+
+        let layout = self.config.argocd_config_layout.unwrap_or_default();
+        if layout == ArgoCDConfigLayout::Split {
+            fs::create_dir_all(config_dir.join("projects"))?;
+            fs::create_dir_all(config_dir.join("apps"))?;
+        }
+
+        // write application files for all folders
+        target.par_iter().try_for_each(|(project_name, project)| {
+            let file_name = format!("{:}.yaml", project_name);
+            match layout {
+                ArgoCDConfigLayout::Combined => {
+                    let mut file = fs::File::create(config_dir.join(&file_name))?;
+                    file.write_all(header.as_bytes())?;
+                    return write_project(project, &mut file);
+                }
+                ArgoCDConfigLayout::Split => {
+                    let mut file = fs::File::create(config_dir.join("projects").join(&file_name))?;
+                    file.write_all(header.as_bytes())?;
+                    write_app_project(project, &mut file)?;
+                    let mut file = fs::File::create(config_dir.join("apps").join(&file_name))?;
+                    file.write_all(header.as_bytes())?;
+                    return write_project_applications(project, &mut file);
+                }
+            }
+        })
+    }
+
+    fn write_inventory(&self, target_name: &str, config_dir: &path::Path) -> Result<()> {
+        let mut applications = self
+            .inventory
+            .get(target_name)
+            .map(|entries| entries.iter().collect::<Vec<_>>())
+            .unwrap_or_default();
+        applications.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let mut inventory = serde_json::Map::new();
+        inventory.insert("target".to_string(), target_name.into());
+        inventory.insert(
+            "applications".to_string(),
+            serde_json::to_value(applications)?,
+        );
+        fs::write(
+            config_dir.join("inventory.yaml"),
+            serde_yaml::to_string(&inventory)?,
+        )?;
+        return Ok(());
+    }
+
+    // writes every selected target's argocd-config documents to stdout in a stable order, targets
+    // in config order and projects sorted by name
+    fn write_argocd_config_to_stdout(&self) -> Result<()> {
+        let mut stdout = io::stdout().lock();
+        let mut first_document = true;
+        for target in self.config.targets.iter() {
+            let projects = match self.targets.get(&target.name) {
+                Some(projects) => projects,
+                None => continue,
+            };
+
+            if !first_document {
+                stdout.write_all(b"\n---\n")?;
+            }
+            first_document = false;
+            stdout.write_all(
+                self.generate_argocd_config_application(&target.name)?
+                    .as_bytes(),
+            )?;
+
+            let mut project_names = projects.keys().collect::<Vec<_>>();
+            project_names.sort();
+            for project_name in project_names {
+                stdout.write_all(b"\n---\n")?;
+                write_project(&projects[project_name], &mut stdout)?;
+            }
+        }
+        stdout.write_all(b"\n")?;
+        stdout.flush()?;
+        return Ok(());
+    }
+
+    // a mismatch between the path the application template computes and where the app was
+    // actually generated results in argo syncing nothing, so make sure every source path exists
+    fn validate_application_source_paths(&self) -> Result<()> {
+        let mut source_paths = Vec::new();
+        for target_name in self.targets.keys() {
+            let application = self.generate_argocd_config_application(target_name)?;
+            for source_path in
+                application_source_paths(&application, &self.config.argocd_source_repo)?
+            {
+                source_paths.push((
+                    target_name.clone(),
+                    "argocd-config".to_string(),
+                    source_path,
+                ));
+            }
+        }
+
+        let mut errors = Vec::new();
+        for (target_name, app, source_path) in source_paths
+            .iter()
+            .chain(self.application_source_paths.iter())
+        {
+            let source_dir = self
+                .staging_path
+                .join(source_path.trim_start_matches("./").trim_start_matches('/'));
+            if !source_dir.is_dir() {
+                errors.push(format!(
+                    "  application {} in target {} has source path {:?} which wasn't generated",
+                    app, target_name, source_path
+                ));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "application source paths don't match the generated output:\n{}",
+                errors.join("\n")
+            ));
+        }
+        return Ok(());
+    }
+
+    // applications outside the argocd namespace are only picked up by argocd when their project
+    // lists the namespace in its sourceNamespaces
+    fn validate_application_namespaces(&self) -> Result<()> {
+        let mut errors = Vec::new();
+        for (target_name, app, project_name, namespace) in self.application_namespaces.iter() {
+            if *namespace == self.config.argocd_namespace {
+                continue;
+            }
+
+            let source_namespaces = self
+                .targets
+                .get(target_name)
+                .and_then(|projects| projects.get(project_name))
+                .map(|project| &project.project.spec.source_namespaces);
+            let allowed = source_namespaces
+                .into_iter()
+                .flatten()
+                .any(|source_namespace| {
+                    glob::Pattern::new(source_namespace)
+                        .map(|pattern| pattern.matches(namespace))
+                        .unwrap_or(source_namespace == namespace)
+                });
+            if !allowed {
+                errors.push(format!(
+                    "  application {} in target {} is in namespace {:?} which isn't in project {}'s source namespaces",
+                    app, target_name, namespace, project_name
+                ));
+            }
+        }
+
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "applications outside the argocd namespace must be allowed by their project (project_options.source_namespaces):\n{}",
+                errors.join("\n")
+            ));
+        }
+        return Ok(());
+    }
+
+    // bargo.lock updated with the applications generated for each processed target, along with
+    // the (target, entry) of every listed application which is no longer generated. Apps outside
+    // the path filters and targets which weren't processed are left as they are
+    pub fn updated_lockfile(&self) -> Result<(Lockfile, Vec<(String, LockEntry)>)> {
+        let mut lockfile = Lockfile::read(&self.input_path)?;
+        let generated = self
+            .targets
+            .keys()
+            .map(|target_name| {
+                let entries = self
+                    .inventory
+                    .get(target_name)
+                    .into_iter()
+                    .flatten()
+                    .map(|entry| LockEntry {
+                        application: format!("{}/{}", entry.project, entry.name),
+                        metadata_file: entry.metadata_file.clone(),
+                    })
+                    .collect();
+                (target_name.clone(), entries)
+            })
+            .collect();
+        let removed = lockfile.update(generated, &self.configured_targets(), |entry| {
+            self.inputs.iter().any(|input| {
+                self.matches_path_filters(&input.path, &input.path.join(&entry.metadata_file))
+            })
+        });
+        return Ok((lockfile, removed));
+    }
+
+    // reads bargo.lock and updates it with the applications generated for each processed target.
+    // Fails when a listed application is no longer generated, unless its metadata file is
+    // tombstoned or --allow-prune is set
+    fn update_lockfile(&self) -> Result<Lockfile> {
+        let (lockfile, removed) = self.updated_lockfile()?;
+
+        let mut errors = Vec::new();
+        for (target_name, entry) in removed {
+            if self.tombstones.contains(&entry.metadata_file) {
+                info!(
+                    target = target_name,
+                    application = entry.application,
+                    "pruning tombstoned application"
+                );
+            } else if self.allow_prune {
+                warn!(
+                    target = target_name,
+                    application = entry.application,
+                    "pruning application"
+                );
+            } else {
+                errors.push(format!(
+                    "  application {} in target {} (from {})",
+                    entry.application, target_name, entry.metadata_file
+                ));
+            }
+        }
+        if !errors.is_empty() {
+            return Err(anyhow!(
+                "{} application(s) listed in {} are no longer generated, pass --allow-prune or set tombstone = true in their metadata to remove them:\n{}",
+                errors.len(),
+                crate::lockfile::LOCKFILE_NAME,
+                errors.join("\n")
+            ));
+        }
+        return Ok(lockfile);
+    }
+
+    fn generate_argocd_config_application(&self, target_name: &str) -> Result<String> {
+        let application = self.generate_argo_application_for_dir(
+            &self.config.argocd_config_application_options,
+            &TemplateContext {
+                namespace: self.config.argocd_namespace.clone(),
+                namespaces: vec![self.config.argocd_namespace.clone()],
+                project: "default".to_string(),
+                app_name: "argocd-config".to_string(),
+                normalized_project: "default".to_string(),
+                normalized_app_name: "argocd-config".to_string(),
+                application_namespace: self.config.argocd_namespace.clone(),
+                path: format!("{}/argocd-config", target_name),
+                source_dir: String::new(),
+                output_path: self.join_template_path([target_name, "argocd-config"]),
+                target_name: target_name.to_string(),
+                target_env: self.target_env(target_name),
+                config: self.config_context(target_name),
+            },
+        )?;
+        let application = self.set_destination_by(target_name, application)?;
+        return self.add_provenance_annotations(None, application);
+    }
+
+    fn append_application(
+        &self,
+        target_name: &str,
+        app_context: &TemplateContext,
+        application: &str,
+    ) -> Result<()> {
+        // unwraps are safe since the project is always created before its applications
+        let project = self
+            .targets
+            .get(target_name)
+            .unwrap()
+            .get(&app_context.normalized_project)
+            .unwrap();
+
+        let mut file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&project.applications_path)?;
+        file.write_all(b"\n---\n")?;
+        file.write_all(application.as_bytes())?;
+        return Ok(());
+    }
+
+    fn generate_argo_application_for_dir(
+        &self,
+        application_options: &Option<serde_json::Value>,
+        app_context: &TemplateContext,
+    ) -> Result<String> {
+        let template_context =
+            self.application_template_context(application_options, app_context)?;
+        let application =
+            self.render_template(&self.application_template_name, template_context)?;
+        if self.config.canonical_yaml.unwrap_or(false) {
+            return canonical_yaml(&application).map_err(|e| {
+                anyhow!(
+                    "{}: generated application isn't valid yaml: {}",
+                    self.application_template_name,
+                    e
+                )
+            });
+        }
+        return Ok(application);
+    }
+
+    // writes the contexts the app's application and files are rendered with to its output dir,
+    // before anything is rendered so they're available in the staging dir when rendering fails
+    fn write_debug_context(
+        &self,
+        metadata: &Metadata,
+        target_vars: &serde_json::Value,
+        app_context: &TemplateContext,
+        out_folder_path: &path::Path,
+    ) -> Result<()> {
+        let mut files_context = target_vars.clone();
+        merge(
+            &mut files_context,
+            serde_json::json!({ "config": app_context.config }),
+        );
+        let context = serde_json::json!({
+            "application": self.application_template_context(&metadata.application_options, app_context)?,
+            "files": files_context,
+        });
+        fs::create_dir_all(out_folder_path)?;
+        fs::write(
+            out_folder_path.join(DEBUG_CONTEXT_FILE_NAME),
+            serde_json::to_string_pretty(&context)?,
+        )?;
+        return Ok(());
+    }
+
+    // the application template's context, the default application options merged with the app's
+    // and the template context
+    fn application_template_context(
+        &self,
+        application_options: &Option<serde_json::Value>,
+        app_context: &TemplateContext,
+    ) -> Result<serde_json::Value> {
+        let mut template_context = self
+            .config
+            .default_application_options
+            .clone()
+            .unwrap_or_else(default_serde_object);
+
+        merge(
+            &mut template_context,
+            application_options
+                .clone()
+                .unwrap_or_else(default_serde_object),
+        );
+        merge(
+            &mut template_context,
+            self.application_option_overrides.clone(),
+        );
+
+        merge(&mut template_context, serde_json::to_value(app_context)?);
+        return Ok(template_context);
+    }
+
+    // adds a notifications.argoproj.io/subscribe annotation for each of the config's default
+    // subscriptions merged with the app's to the generated Applications. Annotations set by the
+    // application template are kept as is
+    fn add_notification_subscriptions(
+        &self,
+        metadata: &Metadata,
+        application: String,
+    ) -> Result<String> {
+        let mut subscriptions = self
+            .config
+            .notification_subscriptions
+            .clone()
+            .unwrap_or_default();
+        for (subscription, recipients) in metadata.notification_subscriptions.iter().flatten() {
+            subscriptions.insert(subscription.clone(), recipients.clone());
+        }
+        // an empty value in the metadata unsubscribes the app from a default subscription
+        subscriptions.retain(|_, recipients| !recipients.is_empty());
+        if subscriptions.is_empty() {
+            return Ok(application);
+        }
+
+        return edit_applications(&application, |application| {
+            let annotations = application
+                .entry("metadata".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application metadata isn't a map"))?
+                .entry("annotations".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application annotations aren't a map"))?;
+            for (subscription, recipients) in subscriptions.iter() {
+                annotations
+                    .entry(format!("notifications.argoproj.io/subscribe.{}", subscription).into())
+                    .or_insert_with(|| recipients.clone().into());
+            }
+            return Ok(());
+        });
+    }
+
+    // sets the sync policy of the target's env policy on generated Applications which don't set
+    // one themselves
+    fn add_env_sync_policy(&self, target_name: &str, application: String) -> Result<String> {
+        let sync_policy = match self
+            .env_policy(target_name)
+            .and_then(|policy| policy.sync_policy.as_ref())
+        {
+            Some(sync_policy) => serde_yaml::to_value(sync_policy)?,
+            None => return Ok(application),
+        };
+
+        return edit_applications(&application, |application| {
+            application
+                .entry("spec".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application spec isn't a map"))?
+                .entry("syncPolicy".into())
+                .or_insert_with(|| sync_policy.clone());
+            return Ok(());
+        });
+    }
+
+    // drops the cluster field destination_by doesn't use from generated Application destinations,
+    // failing when a destination only sets the other one since a cluster's name and server can't
+    // be mapped to each other
+    fn set_destination_by(&self, target_name: &str, application: String) -> Result<String> {
+        let (keep, drop) = match self.destination_by(target_name) {
+            Some(DestinationBy::Name) => ("name", "server"),
+            Some(DestinationBy::Server) => ("server", "name"),
+            None => return Ok(application),
+        };
+
+        return edit_applications(&application, |application| {
+            let destination = match application
+                .get_mut("spec")
+                .and_then(|spec| spec.get_mut("destination"))
+                .and_then(|destination| destination.as_mapping_mut())
+            {
+                Some(destination) => destination,
+                None => return Ok(()),
+            };
+            if destination.contains_key(drop) && !destination.contains_key(keep) {
+                return Err(anyhow!(
+                    "generated application destination sets {} but target {} uses destination {}",
+                    drop,
+                    target_name,
+                    keep
+                ));
+            }
+            destination.remove(drop);
+            return Ok(());
+        });
+    }
+
+    // adds the bargo.dev/content-hash annotation to generated Applications when enabled
+    fn add_content_hash_annotation(
+        &self,
+        out_folder_path: &path::Path,
+        application: String,
+    ) -> Result<String> {
+        if !self.config.content_hash_annotation.unwrap_or(false) {
+            return Ok(application);
+        }
+        let files = find_files(out_folder_path, &[], &|p| {
+            p.file_name().unwrap_or_default() != DEBUG_CONTEXT_FILE_NAME
+        })?;
+        let content_hash = hash_files(out_folder_path, &files)?;
+
+        return edit_applications(&application, |application| {
+            application
+                .entry("metadata".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application metadata isn't a map"))?
+                .entry("annotations".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application annotations aren't a map"))?
+                .insert("bargo.dev/content-hash".into(), content_hash.clone().into());
+            return Ok(());
+        });
+    }
+
+    // adds the provenance annotations to generated Applications, with the app's source path when
+    // generated from an app dir. Annotations set by the application template are kept as is
+    fn add_provenance_annotations(
+        &self,
+        app_dir: Option<&path::Path>,
+        application: String,
+    ) -> Result<String> {
+        let mut annotations = self
+            .preview
+            .as_ref()
+            .map(|preview| preview.annotations())
+            .unwrap_or_default();
+        match self.provenance.as_ref() {
+            Some(provenance) => {
+                annotations.extend(provenance.annotations.clone());
+                match app_dir {
+                    Some(app_dir) => {
+                        annotations.insert(
+                            "bargo.dev/source-path".to_string(),
+                            format!(
+                                "{}{}",
+                                provenance.source_prefix,
+                                app_dir
+                                    .strip_prefix(&self.input_for(app_dir).path)?
+                                    .display()
+                            ),
+                        );
+                    }
+                    None => (),
+                }
+            }
+            None => (),
+        }
+        if annotations.is_empty() {
+            return Ok(application);
+        }
+
+        return edit_applications(&application, |application| {
+            let existing = application
+                .entry("metadata".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application metadata isn't a map"))?
+                .entry("annotations".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application annotations aren't a map"))?;
+            for (key, value) in annotations.iter() {
+                existing
+                    .entry(key.clone().into())
+                    .or_insert_with(|| value.clone().into());
+            }
+            return Ok(());
+        });
+    }
+
+    // the name metadata and input configs refer to a target by, the base target for a preview
+    fn base_target_name<'a>(&'a self, target_name: &'a str) -> &'a str {
+        match self.preview.as_ref() {
+            Some(preview) if preview.target == target_name => return &preview.base,
+            _ => return target_name,
+        }
+    }
+
+    fn env_policy(&self, target_name: &str) -> Option<&EnvPolicy> {
+        return self
+            .config
+            .env_policies
+            .as_ref()
+            .and_then(|policies| policies.get(&self.target_env(target_name)));
+    }
+
+    fn create_or_update_app_project_for_dir(
+        &mut self,
+        target_name: &str,
+        metadata: &Metadata,
+        app_context: &TemplateContext,
+    ) -> Result<()> {
+        let ownership_labels = self.ownership_labels(metadata)?;
+        let applications_path = self
+            .staging_path
+            .join(target_name)
+            .join("argocd-config")
+            .join(format!(
+                ".{}.applications.yaml",
+                app_context.normalized_project
+            ));
+        let sync_windows = self
+            .env_policy(target_name)
+            .and_then(|policy| policy.sync_windows.clone());
+        let preview_annotations = self.preview.as_ref().map(|preview| preview.annotations());
+        let provenance_annotations = self
+            .provenance
+            .as_ref()
+            .map(|provenance| provenance.annotations.clone());
+        let destination_by = self.destination_by(target_name);
+        let project = self
+            .targets
+            .get_mut(target_name)
+            .unwrap()
+            .entry(app_context.normalized_project.clone())
+            .or_insert(ArgoCDProject {
+                project: AppProject::new(
+                    app_context.normalized_project.clone(),
+                    self.config.argocd_namespace.clone(),
+                ),
+                applications_path,
+                conflicting_labels: HashSet::new(),
+            });
+        for (key, value) in ownership_labels {
+            if project.conflicting_labels.contains(&key) {
+                continue;
+            }
+            match project.project.metadata.labels.get(&key) {
+                Some(existing) if *existing != value => {
+                    project.project.metadata.labels.remove(&key);
+                    project.conflicting_labels.insert(key);
+                }
+                _ => {
+                    project.project.metadata.labels.insert(key, value);
+                }
+            }
+        }
+        for annotations in preview_annotations
+            .into_iter()
+            .chain(provenance_annotations)
+        {
+            project.project.metadata.annotations.extend(annotations);
+        }
+        // set all the array like things are using hashsets we can ruthleslsly add everything and
+        // duplicates will get auto dedupped
+        project
+            .project
+            .spec
+            .source_repos
+            .insert(self.config.argocd_source_repo.clone());
+        for source_repo in metadata
+            .project_options
+            .iter()
+            .flat_map(|options| options.source_repos.iter().flatten())
+        {
+            project
+                .project
+                .spec
+                .source_repos
+                .insert(source_repo.clone());
+        }
+
+        // a destination namespace pattern replaces the per app namespace destinations, any explicit
+        // destinations it covers are dropped when the project is written
+        let destination_namespace = metadata
+            .project_options
+            .as_ref()
+            .and_then(|options| options.destination_namespace.clone());
+        let namespaces = match destination_namespace {
+            Some(destination_namespace) => vec![destination_namespace],
+            None => app_context.namespaces.clone(),
+        };
+        for namespace in namespaces {
+            project
+                .project
+                .spec
+                .destinations
+                .insert(project_destination(namespace, destination_by));
+        }
+
+        match sync_windows {
+            Some(sync_windows) => project.project.spec.sync_windows = sync_windows,
+            None => (),
+        }
+
+        for source_namespace in metadata
+            .project_options
+            .iter()
+            .flat_map(|options| options.source_namespaces.iter().flatten())
+        {
+            project
+                .project
+                .spec
+                .source_namespaces
+                .insert(source_namespace.clone());
+        }
+
+        project.project.spec.cluster_resource_whitelist.insert(
+            AppProjectClusterResourceWhitelist {
+                group: "".to_string(),
+                kind: "Namespace".to_string(),
+            },
+        );
+
+        for key_id in self.config.signature_keys.iter().flatten().chain(
+            metadata
+                .project_options
+                .iter()
+                .flat_map(|options| options.signature_keys.iter().flatten()),
+        ) {
+            project
+                .project
+                .spec
+                .signature_keys
+                .insert(AppProjectSignatureKey {
+                    key_id: key_id.clone(),
+                });
+        }
+
+        match metadata.project_options.as_ref() {
+            Some(options) => {
+                match options.additional_namespaces.as_ref() {
+                    Some(additional_namespaces) => {
+                        for namespace in additional_namespaces.iter() {
+                            project
+                                .project
+                                .spec
+                                .destinations
+                                .insert(project_destination(namespace.to_string(), destination_by));
+                        }
+                    }
+                    None => (),
+                }
+
+                match options.cluster_resource_whitelist.as_ref() {
+                    Some(cluster_resource_whitelist) => {
+                        for allow_list_item in cluster_resource_whitelist.iter() {
+                            project
+                                .project
+                                .spec
+                                .cluster_resource_whitelist
+                                .insert(allow_list_item.clone());
+                        }
+                    }
+                    None => (),
+                }
+
+                match options.cluster_resource_blacklist.as_ref() {
+                    Some(cluster_resource_blacklist) => {
+                        for deny_list_item in cluster_resource_blacklist.iter() {
+                            project
+                                .project
+                                .spec
+                                .cluster_resource_blacklist
+                                .insert(deny_list_item.clone());
+                        }
+                    }
+                    None => (),
+                }
+            }
+            None => (),
+        }
+        return Ok(());
+    }
+
+    // checks the app's generated names against the naming policy, listing every violation
+    fn check_naming_policy(
+        &self,
+        app_dir: &path::Path,
+        metadata: &Metadata,
+        app_context: &TemplateContext,
+    ) -> Result<()> {
+        let policy = match self.config.naming_policy.as_ref() {
+            Some(policy) => policy,
+            None => return Ok(()),
+        };
+        let context = serde_json::json!({
+            "project": app_context.project,
+            "app_name": app_context.app_name,
+            "team": metadata.team.clone().unwrap_or_default(),
+            "owner": metadata.owner.clone().unwrap_or_default(),
+            "cost_center": metadata.cost_center.clone().unwrap_or_default(),
+            "target_name": app_context.target_name,
+        });
+
+        let mut violations = Vec::new();
+        for (kind, pattern, names) in [
+            (
+                "application",
+                &policy.application,
+                vec![&app_context.normalized_app_name],
+            ),
+            (
+                "project",
+                &policy.project,
+                vec![&app_context.normalized_project],
+            ),
+            (
+                "namespace",
+                &policy.namespace,
+                app_context.namespaces.iter().collect(),
+            ),
+        ] {
+            let pattern = match pattern {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+            let rendered =
+                tera::Tera::one_off(pattern, &tera::Context::from_value(context.clone())?, false)
+                    .map_err(|e| {
+                    anyhow!(
+                        "failed to render {} naming policy {:?}: {:?}",
+                        kind,
+                        pattern,
+                        e
+                    )
+                })?;
+            let regex = cached_regex(&rendered)
+                .map_err(|e| anyhow!("invalid {} naming policy {:?}: {}", kind, rendered, e))?;
+            for name in names {
+                if !regex.is_match(name) {
+                    violations.push(format!("{} {:?} doesn't match {:?}", kind, name, rendered));
+                }
+            }
+        }
+
+        if !violations.is_empty() {
+            return Err(anyhow!(
+                "{} violates the naming policy: {}",
+                app_dir.join(self.metadata_file_name()).display(),
+                violations.join(", ")
+            ));
+        }
+        return Ok(());
+    }
+
+    // the labels for the metadata's owner, team and cost_center
+    fn ownership_labels(&self, metadata: &Metadata) -> Result<BTreeMap<String, String>> {
+        let prefix = self
+            .config
+            .ownership_labels
+            .as_ref()
+            .and_then(|options| options.prefix.as_deref())
+            .unwrap_or("bargo.dev/");
+        let mut labels = BTreeMap::new();
+        for (key, value) in [
+            ("owner", &metadata.owner),
+            ("team", &metadata.team),
+            ("cost-center", &metadata.cost_center),
+        ] {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            if !is_label_value(value) {
+                return Err(anyhow!(
+                    "{} {:?} isn't a valid label value (at most 63 alphanumeric, -, _ or . characters starting and ending with an alphanumeric)",
+                    key.replace('-', "_"),
+                    value
+                ));
+            }
+            labels.insert(format!("{}{}", prefix, key), value.clone());
+        }
+        return Ok(labels);
+    }
+
+    fn add_ownership_labels(&self, metadata: &Metadata, application: String) -> Result<String> {
+        let labels = self.ownership_labels(metadata)?;
+        if labels.is_empty() {
+            return Ok(application);
+        }
+        return edit_applications(&application, |application| {
+            let application_labels = application
+                .entry("metadata".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application metadata isn't a map"))?
+                .entry("labels".into())
+                .or_insert_with(|| serde_yaml::Mapping::new().into())
+                .as_mapping_mut()
+                .ok_or(anyhow!("generated application labels aren't a map"))?;
+            for (key, value) in labels.iter() {
+                application_labels
+                    .entry(key.clone().into())
+                    .or_insert_with(|| value.clone().into());
+            }
+            return Ok(());
+        });
+    }
+
+    fn label_manifests(&self, metadata: &Metadata, out_folder_path: &path::Path) -> Result<()> {
+        let label_manifests = self
+            .config
+            .ownership_labels
+            .as_ref()
+            .and_then(|options| options.manifests)
+            .unwrap_or(false);
+        if !label_manifests {
+            return Ok(());
+        }
+        let labels = self.ownership_labels(metadata)?;
+        if labels.is_empty() {
+            return Ok(());
+        }
+        for file in list_files(out_folder_path)? {
+            let extension = file.extension().unwrap_or_default();
+            if extension != "yaml" && extension != "yml" {
+                continue;
+            }
+            let path = out_folder_path.join(&file);
+            match label_manifest_file(&path, &labels)? {
+                Some(contents) => {
+                    // removed first so a hardlinked copy doesn't write through to the input file
+                    fs::remove_file(&path)?;
+                    fs::write(&path, contents)?;
+                }
+                None => (),
+            }
+        }
+        return Ok(());
+    }
+
+    fn template_context_for_dir(
+        &self,
+        app_dir: &path::Path,
+        target: &MetadataTarget,
+        metadata: &Metadata,
+    ) -> Result<crate::TemplateContext> {
+        let target_name = target.name.as_str();
+        let (project, app_name) = project_and_app_name(app_dir)?;
+
+        let out_path = path::PathBuf::new()
+            .join(target_name)
+            .join(&project)
+            .join(&app_name);
+
+        let namespace = target
+            .namespace
+            .clone()
+            .or(metadata.namespace.clone())
+            .unwrap_or(self.sanitize_name(&project, true));
+        let mut namespaces = vec![namespace.clone()];
+        for extra_namespace in metadata.namespaces.iter().flatten() {
+            if !namespaces.contains(extra_namespace) {
+                namespaces.push(extra_namespace.clone());
+            }
+        }
+
+        let application_namespace = metadata
+            .project_options
+            .as_ref()
+            .and_then(|options| options.application_namespace.clone())
+            .or(self.config.application_namespace.clone())
+            .unwrap_or(self.config.argocd_namespace.clone());
+
+        return Ok(TemplateContext {
+            namespace,
+            namespaces,
+            application_namespace,
+            normalized_project: self.sanitize_name(&project, false),
+            normalized_app_name: self.sanitize_name(&app_name, false),
+            project,
+            app_name,
+            path: out_path.display().to_string(),
+            source_dir: self.join_template_path(
+                app_dir
+                    .strip_prefix(&self.input_for(app_dir).path)
+                    .unwrap_or(app_dir)
+                    .iter()
+                    .map(|c| c.to_string_lossy()),
+            ),
+            output_path: self.join_template_path(out_path.iter().map(|c| c.to_string_lossy())),
+            target_name: target_name.to_string(),
+            target_env: self.target_env(target_name),
+            config: self.config_context(target_name),
+        });
+    }
+
+    fn sanitize_name(&self, name: &str, namespace: bool) -> String {
+        let default_options = SanitizeOptions::default();
+        let options = self.config.sanitize.as_ref().unwrap_or(&default_options);
+        return sanitize_name(name, options, namespace);
+    }
+
+    fn join_template_path<S: AsRef<str>>(&self, components: impl IntoIterator<Item = S>) -> String {
+        let separator = self.config.path_separator.as_deref().unwrap_or("/");
+        return components
+            .into_iter()
+            .map(|c| c.as_ref().to_string())
+            .collect::<Vec<_>>()
+            .join(separator);
+    }
+
+    fn config_context(&self, target_name: &str) -> ConfigContext {
+        return ConfigContext {
+            argocd_namespace: self.config.argocd_namespace.clone(),
+            argocd_source_repo: self.config.argocd_source_repo.clone(),
+            target: ConfigContextTarget {
+                name: target_name.to_string(),
+                env: self.target_env(target_name),
+            },
+        };
+    }
+
+    // the metadata file of every app in the input paths matching the path filters
+    fn find_metadata_files(&self) -> Result<Vec<path::PathBuf>> {
+        let metadata_file_name = self.metadata_file_name();
+        let walk_options = WalkOptions {
+            include_hidden_dirs: self.config.include_hidden_dirs.unwrap_or(false),
+            max_depth: self.config.max_depth,
+        };
+        let mut metadata_files = Vec::new();
+        for input in self.inputs.iter() {
+            metadata_files.extend(discover_files(
+                &input.path,
+                &input.ignore_dirs,
+                walk_options,
+                &|p| {
+                    p.file_name().unwrap_or_default() == metadata_file_name.as_str()
+                        && self.matches_path_filters(&input.path, p)
+                },
+            )?);
+        }
+        return Ok(metadata_files);
+    }
+
+    // whether the app the metadata file belongs to matches any of the path filters
+    fn matches_path_filters(&self, input_path: &path::Path, metadata_file: &path::Path) -> bool {
+        if self.path_filters.is_empty() {
+            return true;
+        }
+        let app_dir = match metadata_file
+            .parent()
+            .and_then(|dir| dir.strip_prefix(input_path).ok())
+        {
+            Some(app_dir) => app_dir,
+            None => return false,
+        };
+        return self
+            .path_filters
+            .iter()
+            .any(|filter| filter.matches_path(app_dir));
+    }
+
+    // the input path an app or template is in
+    fn input_for(&self, path: &path::Path) -> &Input {
+        return self
+            .inputs
+            .iter()
+            .find(|input| path.starts_with(&input.path))
+            .unwrap_or(&self.inputs[0]);
+    }
+
+    // the global and target vars of an app in app_dir, with the vars of its input's own
+    // bargo.toml merged over them
+    fn target_vars(&self, app_dir: &path::Path, target_name: &str) -> Option<serde_json::Value> {
+        let mut vars = self.vars_by_target.get(target_name)?.clone();
+        match self.input_for(app_dir).config.as_ref() {
+            Some(input_config) => {
+                merge(
+                    &mut vars,
+                    input_config
+                        .vars
+                        .clone()
+                        .unwrap_or_else(default_serde_object),
+                );
+                match input_config
+                    .targets
+                    .iter()
+                    .find(|t| t.name == self.base_target_name(target_name))
+                {
+                    Some(target) => merge(
+                        &mut vars,
+                        target.vars.clone().unwrap_or_else(default_serde_object),
+                    ),
+                    None => (),
+                }
+            }
+            None => (),
+        }
+        return Some(vars);
+    }
+
+    // the vars an app is rendered with for a target: the target's vars, the app's vars for the
+    // target and then any overrides
+    fn app_target_vars(&self, app_dir: &path::Path, target: &MetadataTarget) -> serde_json::Value {
+        let mut vars = self.target_vars(app_dir, &target.name).unwrap(); // unwrap since the value is added for every processed target
+        merge(
+            &mut vars,
+            target.vars.clone().unwrap_or_else(default_serde_object),
+        );
+        merge(&mut vars, self.var_overrides.clone());
+        return vars;
+    }
+
+    fn destination_by(&self, target_name: &str) -> Option<DestinationBy> {
+        return self
+            .config
+            .targets
+            .iter()
+            .find(|target| target.name == target_name)
+            .and_then(|target| target.destination_by)
+            .or(self.config.destination_by);
+    }
+
+    fn target_env(&self, target_name: &str) -> String {
+        return self
+            .config
+            .targets
+            .iter()
+            .find(|target| target.name == target_name)
+            .and_then(|target| target.env.clone())
+            .unwrap_or_default();
+    }
+
+    fn render_template(
+        &self,
+        template_name: &str,
+        template_context: serde_json::Value,
+    ) -> Result<String> {
+        self.tera
+            .render(template_name, &tera::Context::from_value(template_context)?)
+            .map_err(|e| match e.source() {
+                Some(err_source) => anyhow!("{:#}", err_source),
+                None => anyhow!("{}", e),
+            })
+    }
+
+    // reads the contents of a non .tera file that should still be templated, either because it
+    // starts with the TEMPLATE_MARKER line (which is dropped) or because the app templates all
+    // files. Binary files are never templated
+    fn read_raw_template(
+        &self,
+        path: &path::Path,
+        template_all_files: bool,
+    ) -> Result<Option<String>> {
+        let has_marker = has_template_marker(path)?;
+        if !has_marker && !template_all_files {
+            return Ok(None);
+        }
+
+        let contents =
+            fs::read(path).map_err(|e| anyhow!("failed to read file {:?}: {}", path, e))?;
+        if contents.contains(&0) {
+            debug!(?path, "not templating binary file");
+            return Ok(None);
+        }
+        let contents = match String::from_utf8(contents) {
+            Ok(contents) => contents,
+            Err(_) => {
+                debug!(?path, "not templating non utf-8 file");
+                return Ok(None);
+            }
+        };
+
+        if !has_marker {
+            return Ok(Some(contents));
+        }
+        return Ok(Some(
+            contents
+                .split_once('\n')
+                .map(|(_, rest)| rest.to_string())
+                .unwrap_or_default(),
+        ));
+    }
+
+    // adds the app's non .tera files which should be templated to tera, under their path, so they
+    // can use shared macros and includes. Each file is only added once, for the first target it's
+    // rendered for
+    fn register_raw_templates(
+        &mut self,
+        app_dir: &path::Path,
+        template_all_files: bool,
+    ) -> Result<()> {
+        let input_path = self.input_for(app_dir).path.clone();
+        let mut raw_templates = Vec::new();
+        for file in find_files(app_dir, &[], &|p| {
+            p.extension().unwrap_or_default() != "tera"
+        })? {
+            if self.raw_templates.contains(&file) {
+                continue;
+            }
+            // files are read before they're copied, which is where symlinks are otherwise checked
+            check_within_input(&input_path, &file)?;
+            match self.read_raw_template(&file, template_all_files)? {
+                Some(contents) => {
+                    let contents = self.input_for(&file).prefix_template_references(&contents);
+                    raw_templates.push((file, contents));
+                }
+                None => (),
+            }
+        }
+        self.tera.add_raw_templates(
+            raw_templates
+                .iter()
+                .map(|(file, contents)| (file.display().to_string(), contents)),
+        )?;
+        self.raw_templates
+            .extend(raw_templates.into_iter().map(|(file, _)| file));
+        return Ok(());
+    }
+
+    // renders a non .tera file added by register_raw_templates
+    fn render_raw_template(
+        &self,
+        path: &path::Path,
+        template_context: serde_json::Value,
+    ) -> Result<String> {
+        self.tera
+            .render(
+                &path.display().to_string(),
+                &tera::Context::from_value(template_context)?,
+            )
+            .map_err(|e| match e.source() {
+                Some(err_source) => anyhow!("{:?}: {:#}", path, err_source),
+                None => anyhow!("{:?}: {}", path, e),
+            })
+    }
+
+    fn copy_and_template_folder(
+        &self,
+        tera_context: &serde_json::Value,
+        app_context: &TemplateContext,
+        from_dir: &path::Path,
+        to_dir: &path::Path,
+    ) -> Result<()> {
+        // the directory structure is created up front so the files themselves can be copied and
+        // templated concurrently
+        let mut files = Vec::new();
+        collect_files_to_copy(&self.input_for(from_dir).path, from_dir, to_dir, &mut files)?;
+
+        let mut tera_context = tera_context.clone();
+        merge(
+            &mut tera_context,
+            serde_json::json!({ "config": app_context.config }),
+        );
+        let tera_context = &tera_context;
+
+        self.thread_pool.install(|| {
+            files.par_iter().try_for_each(|(from_path, to_dir)| {
+                app_files::with_app_dir(&self.input_for(from_dir).path, from_dir, || {
+                    self.copy_or_template_file(tera_context, app_context, from_path, to_dir)
+                })
+            })
+        })
+    }
+
+    fn copy_folder(&self, from_dir: &path::Path, to_dir: &path::Path) -> Result<()> {
+        let mut files = Vec::new();
+        collect_files_to_copy(&self.output_path, from_dir, to_dir, &mut files)?;
+
+        self.thread_pool.install(|| {
+            files.par_iter().try_for_each(|(from_path, to_dir)| {
+                self.copy_file(
+                    from_path,
+                    &to_dir.join(from_path.file_name().unwrap_or_default()),
+                )
+            })
+        })
+    }
+
+    fn copy_or_template_file(
+        &self,
+        tera_context: &serde_json::Value,
+        app_context: &TemplateContext,
+        path: &path::Path,
+        to_dir: &path::Path,
+    ) -> Result<()> {
+        let start = Instant::now();
+        let app_label = app_label(app_context);
+        let file_name = path.file_name().unwrap_or_default();
+        let mut to_path = to_dir.join(file_name);
+
+        let is_tera_file = path.extension().unwrap_or_default() == "tera";
+        let is_raw_template = self.raw_templates.contains(path);
+
+        if is_tera_file || is_raw_template {
+            let file_name = render_file_name(&file_name.to_string_lossy(), tera_context)?;
+            to_path = to_dir.join(file_name);
+            info!(vars=?tera_context, to_path=?to_path, "templating file");
+            let contents = match is_raw_template {
+                true => self.render_raw_template(path, tera_context.clone())?,
+                false => {
+                    let tera_template_name = self.input_for(path).template_name(path)?;
+                    to_path.set_extension("");
+                    self.render_template(&tera_template_name, tera_context.clone())?
+                }
+            };
+            let own_file_name = to_path.file_name().unwrap_or_default().to_string_lossy();
+            match split_file_directives(&contents, &own_file_name)? {
+                Some(files) => {
+                    for (file_name, contents) in files {
+                        let split_path = match file_name {
+                            Some(file_name) => to_dir.join(file_name),
+                            None => to_path.clone(),
+                        };
+                        debug!(from_path=?path, to_path=?split_path, "writing split document");
+                        let contents = self.add_generated_header(path, &split_path, contents)?;
+                        fs::write(split_path, contents)?;
+                    }
+                }
+                None => {
+                    let contents = self.add_generated_header(path, &to_path, contents)?;
+                    fs::write(to_path, contents)?;
+                }
+            }
+            self.timings.record(
+                Phase::Templating,
+                Some(&app_context.target_name),
+                Some(&app_label),
+                start.elapsed(),
+            );
+            return Ok(());
+        }
+
+        debug!(from_path=?path, to_path=?to_path, "copying file");
+        self.copy_file(path, &to_path)?;
+        self.timings.record(
+            Phase::Copying,
+            Some(&app_context.target_name),
+            Some(&app_label),
+            start.elapsed(),
+        );
+        return Ok(());
+    }
+
+    // prefixes templated yaml with the generated header when generated_header is set
+    fn add_generated_header(
+        &self,
+        source_path: &path::Path,
+        to_path: &path::Path,
+        mut contents: String,
+    ) -> Result<String> {
+        if is_yaml_file(to_path) {
+            match self.generated_header(&self.input_for(source_path).template_name(source_path)?) {
+                Some(header) => contents.insert_str(0, &header),
+                None => (),
+            }
+        }
+        return Ok(contents);
+    }
+
+    // normalizes the yaml in an app's finished output when canonical_yaml is set and pipes each
+    // file through the post processor configured for its extension. Runs once the script and
+    // plugins are done so copied and generated files are handled like templated ones
+    fn post_process_output(
+        &self,
+        app_dir: &path::Path,
+        out_folder_path: &path::Path,
+    ) -> Result<()> {
+        let canonical = self.config.canonical_yaml.unwrap_or(false);
+        let post_processors = self.config.post_processors.clone().unwrap_or_default();
+        if !canonical && post_processors.is_empty() {
+            return Ok(());
+        }
+
+        for file in list_files(out_folder_path)? {
+            let to_path = out_folder_path.join(&file);
+            let post_processor = to_path
+                .extension()
+                .and_then(|extension| post_processors.get(extension.to_str()?));
+            let canonicalize = canonical && is_yaml_file(&to_path);
+            if post_processor.is_none() && !canonicalize {
+                continue;
+            }
+            let contents = match fs::read_to_string(&to_path) {
+                Ok(contents) => contents,
+                // binary files are left as is
+                Err(e) if e.kind() == io::ErrorKind::InvalidData => continue,
+                Err(e) => return Err(e.into()),
+            };
+            let source_path = match app_dir.join(&file) {
+                source_path if source_path.exists() => source_path,
+                _ => to_path.clone(),
+            };
+
+            // the generated header is a comment which canonical_yaml would drop, so it's kept
+            // aside and restored afterwards
+            let (header, mut contents) = match contents.strip_prefix(GENERATED_HEADER_PREFIX) {
+                Some(rest) => {
+                    let header_end = rest.find('\n').map(|i| i + 1).unwrap_or(rest.len());
+                    (
+                        format!("{}{}", GENERATED_HEADER_PREFIX, &rest[..header_end]),
+                        rest[header_end..].to_string(),
+                    )
+                }
+                None => (String::new(), contents),
+            };
+            if canonicalize {
+                contents = canonical_yaml(&contents)
+                    .map_err(|e| anyhow!("{:?}: invalid yaml: {}", to_path, e))?;
+            }
+            match post_processor {
+                Some(command) => {
+                    contents = run_post_processor(command, &source_path, &to_path, contents)?;
+                }
+                None => (),
+            }
+
+            // removed first so a hardlinked copy doesn't write through to the input file
+            fs::remove_file(&to_path)?;
+            fs::write(&to_path, format!("{}{}", header, contents))?;
+        }
+        return Ok(());
+    }
+
+    // the comment written at the top of generated yaml files when generated_header is set
+    fn generated_header(&self, source: &dyn std::fmt::Display) -> Option<String> {
+        if !self.config.generated_header.unwrap_or(false) {
+            return None;
+        }
+        return Some(format!(
+            "{}{}. DO NOT EDIT.\n",
+            GENERATED_HEADER_PREFIX, source
+        ));
+    }
+
+    fn copy_file(&self, from: &path::Path, to: &path::Path) -> Result<()> {
+        match self.config.copy_strategy.unwrap_or_default() {
+            CopyStrategy::Copy => {
+                fs::copy(from, to)?;
+            }
+            CopyStrategy::Reflink => {
+                reflink_copy::reflink_or_copy(from, to)?;
+            }
+            CopyStrategy::Hardlink => {
+                if let Err(e) = fs::hard_link(from, to) {
+                    debug!(?from, ?to, error=?e, "unable to hardlink file, copying instead");
+                    fs::copy(from, to)?;
+                }
+            }
+        }
+        return Ok(());
+    }
+
+    fn write_generated_values(
+        &self,
+        metadata: &Metadata,
+        tera_context: &serde_json::Value,
+        to_dir: &path::Path,
+    ) -> Result<()> {
+        let write_generated_values = metadata
+            .write_generated_values
+            .or(self.config.write_generated_values)
+            .unwrap_or(false);
+        if !write_generated_values {
+            return Ok(());
+        }
+
+        let values = yaml_encode(tera_context)?;
+        fs::write(to_dir.join("generated-values.yaml"), values + "\n")?;
+        return Ok(());
+    }
+
+    fn write_kustomize_patch(
+        &self,
+        metadata: &Metadata,
+        target_name: &str,
+        tera_context: &serde_json::Value,
+        to_dir: &path::Path,
+    ) -> Result<()> {
+        let kustomize_patch = metadata
+            .kustomize_patch
+            .or(self.config.kustomize_patch)
+            .unwrap_or(false);
+        if !kustomize_patch {
+            return Ok(());
+        }
+        return kustomize::write_patch(to_dir, target_name, tera_context);
+    }
+
+    fn write_bargo_values(
+        &self,
+        tera_context: &serde_json::Value,
+        template_context: &TemplateContext,
+        to_dir: &path::Path,
+    ) -> Result<()> {
+        if !to_dir.join("files/Chart.yaml").exists() {
+            info!(
+                ?to_dir,
+                "not writing bargo values file as didn't find Chart.yaml file in files subdir"
+            );
+            return Ok(());
+        }
+
+        let mut bargo_vars = serde_json::Map::new();
+        bargo_vars.insert("vars".to_string(), serde_json::to_value(tera_context)?);
+        bargo_vars.insert(
+            "metadata".to_string(),
+            serde_json::to_value(template_context)?,
+        );
+
+        let mut bargo_values_map = serde_json::Map::new();
+        bargo_values_map.insert("bargo".to_string(), serde_json::Value::Object(bargo_vars));
+        // put values under global namespace so that all subcharts have access to them
+        let mut values_map = serde_json::Map::new();
+        values_map.insert(
+            "global".to_string(),
+            serde_json::Value::Object(bargo_values_map),
+        );
+        let values = serde_json::Value::Object(values_map);
+
+        let s_values = yaml_encode(&values)?;
+        let mut file = fs::File::create(to_dir.join("files/bargo_values.yaml"))?;
+        file.write_all(s_values.as_bytes())?;
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+// adds the metadata's info entries and links to the generated Applications, entries and links
+// the application template already sets are kept
+fn add_info_and_links(metadata: &Metadata, application: String) -> Result<String> {
+    if metadata.info.is_none() && metadata.links.is_none() {
+        return Ok(application);
+    }
+
+    return edit_applications(&application, |application| {
+        match metadata.info.as_ref() {
+            Some(info) => {
+                let entries = application
+                    .entry("spec".into())
+                    .or_insert_with(|| serde_yaml::Mapping::new().into())
+                    .as_mapping_mut()
+                    .ok_or(anyhow!("generated application spec isn't a map"))?
+                    .entry("info".into())
+                    .or_insert_with(|| serde_yaml::Value::Sequence(Vec::new()))
+                    .as_sequence_mut()
+                    .ok_or(anyhow!("generated application info isn't a list"))?;
+                for (name, value) in info.iter() {
+                    if entries
+                        .iter()
+                        .any(|entry| entry["name"].as_str() == Some(name))
+                    {
+                        continue;
+                    }
+                    let mut entry = serde_yaml::Mapping::new();
+                    entry.insert("name".into(), name.clone().into());
+                    entry.insert("value".into(), value.clone().into());
+                    entries.push(entry.into());
+                }
+            }
+            None => (),
+        }
+
+        match metadata.links.as_ref() {
+            Some(links) => {
+                let annotations = application
+                    .entry("metadata".into())
+                    .or_insert_with(|| serde_yaml::Mapping::new().into())
+                    .as_mapping_mut()
+                    .ok_or(anyhow!("generated application metadata isn't a map"))?
+                    .entry("annotations".into())
+                    .or_insert_with(|| serde_yaml::Mapping::new().into())
+                    .as_mapping_mut()
+                    .ok_or(anyhow!("generated application annotations aren't a map"))?;
+                for (name, url) in links.iter() {
+                    annotations
+                        .entry(format!("link.argocd.argoproj.io/{}", name).into())
+                        .or_insert_with(|| url.clone().into());
+                }
+            }
+            None => (),
+        }
+        return Ok(());
+    });
+}
+
+// the paths of an application's sources (spec.source and spec.sources) which point at the
+// rendered repo, sources without a repoURL are assumed to point at it
+fn application_source_paths(application: &str, source_repo: &str) -> Result<Vec<String>> {
+    let normalize_repo = |repo: &str| {
+        repo.trim_end_matches('/')
+            .trim_end_matches(".git")
+            .to_string()
+    };
+    let source_repo = normalize_repo(source_repo);
+
+    let mut paths = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(application) {
+        let application = serde_yaml::Value::deserialize(document)
+            .map_err(|e| anyhow!("generated application isn't valid yaml: {}", e))?;
+        let spec = &application["spec"];
+        let sources = std::iter::once(&spec["source"])
+            .chain(spec["sources"].as_sequence().into_iter().flatten());
+        for source in sources {
+            let points_at_source_repo = match source["repoURL"].as_str() {
+                Some(repo) => normalize_repo(repo) == source_repo,
+                None => true,
+            };
+            match source["path"].as_str() {
+                Some(path) if points_at_source_repo => paths.push(path.to_string()),
+                _ => (),
+            }
+        }
+    }
+    return Ok(paths);
+}
+
+enum TargetMatcher {
+    Name(String),
+    Glob(glob::Pattern),
+    Regex(regex::Regex),
+}
+
+impl TargetMatcher {
+    fn new(name: &str) -> Result<TargetMatcher> {
+        if name.len() > 1 && name.starts_with('/') && name.ends_with('/') {
+            let regex = regex::Regex::new(&name[1..name.len() - 1])
+                .map_err(|e| anyhow!("invalid target regex {:?}: {}", name, e))?;
+            return Ok(TargetMatcher::Regex(regex));
+        }
+        if name.contains(['*', '?', '[']) {
+            let pattern = glob::Pattern::new(name)
+                .map_err(|e| anyhow!("invalid target pattern {:?}: {}", name, e))?;
+            return Ok(TargetMatcher::Glob(pattern));
+        }
+        return Ok(TargetMatcher::Name(name.to_string()));
+    }
+
+    fn matches(&self, target: &str) -> bool {
+        return match self {
+            TargetMatcher::Name(name) => name == target,
+            TargetMatcher::Glob(pattern) => pattern.matches(target),
+            TargetMatcher::Regex(regex) => regex.is_match(target),
+        };
+    }
+}
+
+// evaluates the metadata enabled option, expressions are rendered as a tera if condition against
+// the app's vars for the target
+fn app_enabled(enabled: &Option<AppEnabled>, vars: &serde_json::Value) -> Result<bool> {
+    let expression = match enabled {
+        None => return Ok(true),
+        Some(AppEnabled::Bool(enabled)) => return Ok(*enabled),
+        Some(AppEnabled::Expression(expression)) => expression,
+    };
+
+    let rendered = tera::Tera::one_off(
+        &format!(
+            "{{% if {} %}}true{{% else %}}false{{% endif %}}",
+            expression
+        ),
+        &tera::Context::from_value(vars.clone())?,
+        false,
+    )
+    .map_err(|e| {
+        anyhow!(
+            "failed to evaluate enabled expression {:?}: {:?}",
+            expression,
+            e
+        )
+    })?;
+    return Ok(rendered == "true");
+}
+
+// the project and app names are taken from the app's parent directory and directory names
+fn project_and_app_name(app_dir: &path::Path) -> Result<(String, String)> {
+    let project = app_dir
+        .parent()
+        .ok_or(anyhow!(
+            "unable to determine project name from folder structure for {:?}",
+            app_dir
+        ))?
+        .file_name()
+        .ok_or(anyhow!(
+            "unable to determine project name from folder structure for {:?}",
+            app_dir
+        ))?
+        .to_string_lossy()
+        .to_string();
+
+    let app_name = app_dir
+        .file_name()
+        .ok_or(anyhow!(
+            "unable to determine app name from folder structure for {:?}",
+            app_dir
+        ))?
+        .to_string_lossy()
+        .to_string();
+
+    return Ok((project, app_name));
+}
+
+// checks the names of the app's project, namespaces and generated Applications are valid
+// kubernetes names. Application names are limited to 63 characters since argocd uses them as the
+// value of the app.kubernetes.io/instance label
+fn validate_kubernetes_names(app_context: &TemplateContext, application: &str) -> Result<()> {
+    let mut errors = Vec::new();
+    let mut check = |kind: &str, name: &str, valid: bool| {
+        if !valid {
+            errors.push(format!("  {} {:?}", kind, name));
+        }
+    };
+
+    check(
+        "project name",
+        &app_context.normalized_project,
+        is_dns_subdomain(&app_context.normalized_project),
+    );
+    for namespace in app_context.namespaces.iter() {
+        check("namespace", namespace, is_dns_label(namespace));
+    }
+    for document in serde_yaml::Deserializer::from_str(application) {
+        let document = serde_yaml::Value::deserialize(document)
+            .map_err(|e| anyhow!("generated application isn't valid yaml: {}", e))?;
+        if document["kind"].as_str() != Some("Application") {
+            continue;
+        }
+        match document["metadata"]["name"].as_str() {
+            Some(name) => check(
+                "application name",
+                name,
+                is_dns_subdomain(name) && name.len() <= 63,
+            ),
+            None => (),
+        }
+        match document["metadata"]["namespace"].as_str() {
+            Some(namespace) => check("application namespace", namespace, is_dns_label(namespace)),
+            None => (),
+        }
+        match document["spec"]["destination"]["namespace"].as_str() {
+            Some(namespace) => check("destination namespace", namespace, is_dns_label(namespace)),
+            None => (),
+        }
+    }
+
+    if !errors.is_empty() {
+        return Err(anyhow!(
+            "invalid kubernetes names (must be lowercase rfc 1123 names, at most 63 characters for namespaces and applications):\n{}",
+            errors.join("\n")
+        ));
+    }
+    return Ok(());
+}
+
+const DNS_LABEL_PATTERN: &str = "[a-z0-9]([-a-z0-9]*[a-z0-9])?";
+
+// rfc 1123 label, used for namespaces
+static DNS_LABEL: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(&format!("^{}$", DNS_LABEL_PATTERN)).unwrap());
+
+fn is_dns_label(name: &str) -> bool {
+    return name.len() <= 63 && DNS_LABEL.is_match(name);
+}
+
+// a duration like 90s, 30m, 72h or 7d
+fn parse_ttl(ttl: &str) -> Result<chrono::TimeDelta> {
+    let invalid = || anyhow!("invalid ttl {:?}, expected eg. 30m, 72h or 7d", ttl);
+    let split = ttl
+        .find(|c: char| !c.is_ascii_digit())
+        .ok_or_else(invalid)?;
+    let amount = ttl[..split].parse::<i64>().map_err(|_| invalid())?;
+    let duration = match &ttl[split..] {
+        "s" => chrono::TimeDelta::try_seconds(amount),
+        "m" => chrono::TimeDelta::try_minutes(amount),
+        "h" => chrono::TimeDelta::try_hours(amount),
+        "d" => chrono::TimeDelta::try_days(amount),
+        _ => return Err(invalid()),
+    };
+    return duration.ok_or_else(|| anyhow!("ttl {:?} is too long", ttl));
+}
+
+static LABEL_VALUE: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"^([A-Za-z0-9]([-A-Za-z0-9_.]*[A-Za-z0-9])?)?$").unwrap());
+
+// label values are at most 63 characters and may be empty
+fn is_label_value(value: &str) -> bool {
+    return value.len() <= 63 && LABEL_VALUE.is_match(value);
+}
+
+// kinds which aren't namespaced, so aren't given ownership labels
+const CLUSTER_SCOPED_KINDS: [&str; 12] = [
+    "APIService",
+    "ClusterIssuer",
+    "ClusterRole",
+    "ClusterRoleBinding",
+    "CustomResourceDefinition",
+    "IngressClass",
+    "MutatingWebhookConfiguration",
+    "Namespace",
+    "PersistentVolume",
+    "PriorityClass",
+    "StorageClass",
+    "ValidatingWebhookConfiguration",
+];
+
+// the file with labels added to each of its namespaced manifests, None when the file isn't yaml
+// manifests (eg. helm chart sources) or nothing changed
+fn label_manifest_file(
+    path: &path::Path,
+    labels: &BTreeMap<String, String>,
+) -> Result<Option<String>> {
+    let contents = fs::read_to_string(path)?;
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(&contents) {
+        match serde_yaml::Value::deserialize(document) {
+            Ok(document) => documents.push(document),
+            Err(_) => return Ok(None),
+        }
+    }
+
+    let mut changed = false;
+    for document in documents.iter_mut() {
+        let is_manifest = document["apiVersion"].is_string() && document["metadata"].is_mapping();
+        let cluster_scoped = document["kind"]
+            .as_str()
+            .map(|kind| CLUSTER_SCOPED_KINDS.contains(&kind))
+            .unwrap_or(true);
+        if !is_manifest || cluster_scoped {
+            continue;
+        }
+        // unwrap since metadata was checked to be a mapping
+        let manifest_labels = document["metadata"]
+            .as_mapping_mut()
+            .unwrap()
+            .entry("labels".into())
+            .or_insert_with(|| serde_yaml::Mapping::new().into());
+        let manifest_labels = match manifest_labels.as_mapping_mut() {
+            Some(manifest_labels) => manifest_labels,
+            None => continue,
+        };
+        for (key, value) in labels.iter() {
+            if !manifest_labels.contains_key(key.as_str()) {
+                manifest_labels.insert(key.clone().into(), value.clone().into());
+                changed = true;
+            }
+        }
+    }
+    if !changed {
+        return Ok(None);
+    }
+
+    let mut labelled = String::new();
+    for (i, document) in documents.iter().enumerate() {
+        if i > 0 {
+            labelled.push_str("---\n");
+        }
+        labelled.push_str(&serde_yaml::to_string(document)?);
+    }
+    return Ok(Some(labelled));
+}
+
+// rfc 1123 subdomain, used for most resource names
+static DNS_SUBDOMAIN: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new(&format!(
+        "^{}(\\.{})*$",
+        DNS_LABEL_PATTERN, DNS_LABEL_PATTERN
+    ))
+    .unwrap()
+});
+
+fn is_dns_subdomain(name: &str) -> bool {
+    return name.len() <= 253 && DNS_SUBDOMAIN.is_match(name);
+}
+
+// annotations shared by every generated resource, the source commit and path are left out when
+// the input path isn't in a git repo
+fn provenance(
+    input_path: &path::Path,
+    options: &ProvenanceOptions,
+    clock: Clock,
+) -> Result<Provenance> {
+    let mut annotations = BTreeMap::from([(
+        "bargo.dev/generator".to_string(),
+        format!("bargo/{}", env!("CARGO_PKG_VERSION")),
+    )]);
+    let commit = git(input_path, &["rev-parse", "HEAD"]).ok();
+    match commit.as_ref() {
+        Some(commit) => {
+            annotations.insert("bargo.dev/source-commit".to_string(), commit.clone());
+        }
+        None => (),
+    }
+
+    let generated_at = if clock.is_frozen() {
+        Some(clock.now().timestamp().max(0) as u64)
+    } else if options.reproducible.unwrap_or(false) {
+        match std::env::var("SOURCE_DATE_EPOCH") {
+            Ok(epoch) => Some(
+                epoch
+                    .parse::<u64>()
+                    .map_err(|_| anyhow!("invalid SOURCE_DATE_EPOCH {:?}", epoch))?,
+            ),
+            Err(_) if commit.is_some() => {
+                let commit_time = git(input_path, &["log", "-1", "--format=%ct"])?;
+                Some(
+                    commit_time
+                        .parse::<u64>()
+                        .map_err(|_| anyhow!("invalid commit time {:?}", commit_time))?,
+                )
+            }
+            Err(_) => None,
+        }
+    } else {
+        Some(
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)?
+                .as_secs(),
+        )
+    };
+    match generated_at {
+        Some(generated_at) => {
+            annotations.insert(
+                "bargo.dev/generated-at".to_string(),
+                format_timestamp(generated_at)?,
+            );
+        }
+        None => (),
+    }
+
+    return Ok(Provenance {
+        annotations,
+        source_prefix: git(input_path, &["rev-parse", "--show-prefix"]).unwrap_or_default(),
+    });
+}
+
+// formats seconds since the unix epoch as an RFC 3339 UTC timestamp
+fn format_timestamp(secs: u64) -> Result<String> {
+    return i64::try_from(secs)
+        .ok()
+        .and_then(|secs| chrono::DateTime::from_timestamp(secs, 0))
+        .map(|time| time.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+        .ok_or_else(|| anyhow!("timestamp {} is out of range", secs));
+}
+
+// pipes contents through a post processor command, returning its stdout
+fn run_post_processor(
+    command: &str,
+    source_path: &path::Path,
+    to_path: &path::Path,
+    contents: String,
+) -> Result<String> {
+    debug!(source_path=?source_path, to_path=?to_path, command, "post processing file");
+    let mut child = std::process::Command::new("bash")
+        .arg("-c")
+        .arg(command)
+        .env("in", source_path)
+        .env("out", to_path)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| {
+            anyhow!(
+                "{:?}: failed to run post processor {:?}: {}",
+                to_path,
+                command,
+                e
+            )
+        })?;
+    // written from another thread so a post processor filling its stdout pipe before reading
+    // all of stdin can't deadlock
+    let mut stdin = child.stdin.take().unwrap(); // unwrap since stdin is piped
+    let writer = std::thread::spawn(move || stdin.write_all(contents.as_bytes()));
+    let output = child.wait_with_output()?;
+    // a post processor may exit without reading all of stdin, its exit status is what matters
+    let _ = writer.join();
+
+    if !output.status.success() {
+        return Err(anyhow!(
+            "{:?}: post processor {:?} failed: {}",
+            source_path,
+            command,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    return String::from_utf8(output.stdout).map_err(|_| {
+        anyhow!(
+            "{:?}: post processor {:?} output isn't valid utf-8",
+            source_path,
+            command
+        )
+    });
+}
+
+const GENERATED_HEADER_PREFIX: &str = "# Code generated by bargo from ";
+
+fn is_yaml_file(path: &path::Path) -> bool {
+    return matches!(
+        path.extension().and_then(|extension| extension.to_str()),
+        Some("yaml" | "yml")
+    );
+}
+
+// re-serializes every document in contents with serde_yaml, dropping empty documents and
+// comments
+fn canonical_yaml(contents: &str) -> Result<String> {
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(contents) {
+        let document = serde_yaml::Value::deserialize(document)?;
+        if document.is_null() {
+            continue;
+        }
+        documents.push(serde_yaml::to_string(&document)?);
+    }
+    return Ok(documents.join("---\n"));
+}
+
+// how argocd registers the cluster it runs in
+const IN_CLUSTER_NAME: &str = "in-cluster";
+const IN_CLUSTER_SERVER: &str = "https://kubernetes.default.svc";
+
+// an AppProject destination for the in-cluster cluster, identified by destination_by or by both
+// its name and server when unset
+fn project_destination(
+    namespace: String,
+    destination_by: Option<DestinationBy>,
+) -> AppProjectDestination {
+    return AppProjectDestination {
+        name: match destination_by {
+            Some(DestinationBy::Server) => String::new(),
+            _ => IN_CLUSTER_NAME.to_string(),
+        },
+        namespace,
+        server: match destination_by {
+            Some(DestinationBy::Name) => String::new(),
+            _ => IN_CLUSTER_SERVER.to_string(),
+        },
+    };
+}
+
+// re-encodes a generated application after passing each of its Application documents to edit
+fn edit_applications(
+    application: &str,
+    mut edit: impl FnMut(&mut serde_yaml::Mapping) -> Result<()>,
+) -> Result<String> {
+    let mut documents = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(application) {
+        let mut document = serde_yaml::Value::deserialize(document)
+            .map_err(|e| anyhow!("generated application isn't valid yaml: {}", e))?;
+        if document["kind"].as_str() == Some("Application") {
+            match document.as_mapping_mut() {
+                Some(mapping) => edit(mapping)?,
+                None => (),
+            }
+        }
+        documents.push(serde_yaml::to_string(&document)?);
+    }
+    return Ok(documents.join("---\n"));
+}
+
+// the metadata.namespace of every document in a generated application
+fn application_namespaces(application: &str) -> Result<Vec<String>> {
+    let mut namespaces = Vec::new();
+    for document in serde_yaml::Deserializer::from_str(application) {
+        let application = serde_yaml::Value::deserialize(document)
+            .map_err(|e| anyhow!("generated application isn't valid yaml: {}", e))?;
+        match application["metadata"]["namespace"].as_str() {
+            Some(namespace) => namespaces.push(namespace.to_string()),
+            None => (),
+        }
+    }
+    return Ok(namespaces);
+}
+
+// identifies an app within a target for reporting
+fn app_label(app_context: &TemplateContext) -> String {
+    return format!("{}/{}", app_context.project, app_context.app_name);
+}
+
+// names of the templates included, imported or extended by a template, None when any of them is an
+// expression rather than string literals
+fn template_dependencies(contents: &str) -> Option<Vec<String>> {
+    let mut dependencies = Vec::new();
+    for tag in TEMPLATE_REFERENCE_TAG.captures_iter(contents) {
+        let names = TEMPLATE_NAME_LITERAL
+            .captures_iter(&tag[1])
+            .filter_map(|name| name.iter().skip(1).flatten().next())
+            .map(|name| name.as_str().to_string())
+            .collect::<Vec<_>>();
+        if names.is_empty() {
+            return None;
+        }
+        dependencies.extend(names);
+    }
+    return Some(dependencies);
+}
+
+// the dynamic functions (see DYNAMIC_FUNCTION_CALL) called in a template
+fn dynamic_calls(contents: &str) -> BTreeSet<String> {
+    return DYNAMIC_FUNCTION_CALL
+        .captures_iter(contents)
+        .map(|call| call[1].to_string())
+        .collect();
+}
+
+// hashes the paths (relative to root) and contents of the given files
+fn hash_files(root: &path::Path, files: &[path::PathBuf]) -> Result<String> {
+    let mut hasher = Sha256::new();
+    for file in files {
+        hasher.update(file.strip_prefix(root)?.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        hasher
+            .update(fs::read(file).map_err(|e| anyhow!("failed to read file {:?}: {}", file, e))?);
+        hasher.update([0]);
+    }
+    return Ok(format!("{:x}", hasher.finalize()));
+}
+
+// writes the AppProject followed by all of its applications as a multi document yaml stream,
+// consuming the project's streamed applications file
+fn write_project(project: &ArgoCDProject, writer: &mut dyn Write) -> Result<()> {
+    write_app_project(project, writer)?;
+    return write_project_applications(project, writer);
+}
+
+fn write_app_project(project: &ArgoCDProject, writer: &mut dyn Write) -> Result<()> {
+    let mut app_project = project.project.clone();
+    app_project.remove_covered_destinations();
+    writer.write_all(serde_yaml::to_string(&app_project)?.as_bytes())?;
+    return Ok(());
+}
+
+fn write_project_applications(project: &ArgoCDProject, writer: &mut dyn Write) -> Result<()> {
+    let mut applications = fs::File::open(&project.applications_path)?;
+    io::copy(&mut applications, writer)?;
+    fs::remove_file(&project.applications_path)?;
+    return Ok(());
+}
+
+// walks from_dir creating the matching directories under to_dir and collecting every file along
+// with the directory it should be written to. Symlinks are followed but must resolve within root,
+// otherwise an app could copy anything on the machine (eg. ~/.ssh) into its output
+fn collect_files_to_copy(
+    root: &path::Path,
+    from_dir: &path::Path,
+    to_dir: &path::Path,
+    files: &mut Vec<(path::PathBuf, path::PathBuf)>,
+) -> Result<()> {
+    fs::create_dir_all(to_dir)?;
+    for f in fs::read_dir(from_dir)? {
+        let entry = f?;
+        let path = entry.path();
+        let file_type = entry.file_type()?;
+        let is_dir = match file_type.is_symlink() {
+            true => check_within_input(root, &path)?.is_dir(),
+            false => file_type.is_dir(),
+        };
+        if is_dir {
+            collect_files_to_copy(root, &path, &to_dir.join(entry.file_name()), files)?;
+            continue;
+        }
+        files.push((path, to_dir.to_path_buf()));
+    }
+    return Ok(());
+}
+
+// a rendered document containing this directive (eg. "# bargo:file: configmap.yaml") at the start
+// of a line is written to the named file in the same directory instead of the template's own
+// output file
+const FILE_DIRECTIVE: &str = "# bargo:file:";
+
+// a line whose value is a block scalar (eg. "data: |" or "- >-"), capturing its indentation
+static BLOCK_SCALAR_START: LazyLock<regex::Regex> = LazyLock::new(|| {
+    regex::Regex::new(r"^( *)(?:- +|[^#]*: +)?[|>][-+1-9]{0,2} *(?:#.*)?$").unwrap()
+});
+
+// splits rendered yaml on document separators and groups the documents by their file directive,
+// documents without one are kept in the template's output file (None). Directives inside block
+// scalars are kept as content. Returns None when there are no directives so the output is written
+// as is
+fn split_file_directives(
+    contents: &str,
+    own_file_name: &str,
+) -> Result<Option<BTreeMap<Option<String>, String>>> {
+    if !contents.contains(FILE_DIRECTIVE) {
+        return Ok(None);
+    }
+
+    let mut documents = vec![String::new()];
+    for line in contents.split_inclusive('\n') {
+        if line.trim_end() == "---" {
+            documents.push(String::new());
+            continue;
+        }
+        documents.last_mut().unwrap().push_str(line); // unwrap since documents is never empty
+    }
+
+    let mut files: BTreeMap<Option<String>, String> = BTreeMap::new();
+    for document in documents {
+        let mut file_name = None;
+        let mut contents = String::new();
+        // indentation of the line starting the block scalar the current line may be part of
+        let mut block_scalar_indent = None;
+        for line in document.split_inclusive('\n') {
+            let indent = line.len() - line.trim_start_matches(' ').len();
+            match block_scalar_indent {
+                Some(block_indent) if line.trim().is_empty() || indent > block_indent => {
+                    contents.push_str(line);
+                    continue;
+                }
+                _ => block_scalar_indent = None,
+            }
+            match BLOCK_SCALAR_START.captures(line.trim_end()) {
+                Some(captures) => block_scalar_indent = Some(captures[1].len()),
+                None => (),
+            }
+
+            match line.strip_prefix(FILE_DIRECTIVE) {
+                Some(name) => {
+                    let name = name.trim();
+                    if !is_plain_file_name(name) {
+                        return Err(anyhow!(
+                            "invalid file name {:?} in {}",
+                            name,
+                            FILE_DIRECTIVE
+                        ));
+                    }
+                    if name == own_file_name {
+                        return Err(anyhow!(
+                            "{} {} names the template's own output file, remove the directive to keep the document in it",
+                            FILE_DIRECTIVE,
+                            name
+                        ));
+                    }
+                    match file_name.as_ref() {
+                        Some(previous_name) => {
+                            return Err(anyhow!(
+                                "document has more than one {} directive ({} and {})",
+                                FILE_DIRECTIVE,
+                                previous_name,
+                                name
+                            ));
+                        }
+                        None => file_name = Some(name.to_string()),
+                    }
+                }
+                None => contents.push_str(line),
+            }
+        }
+        if contents.trim().is_empty() {
+            continue;
+        }
+        if !contents.ends_with('\n') {
+            contents.push('\n');
+        }
+
+        let file = files.entry(file_name).or_default();
+        if !file.is_empty() {
+            file.push_str("---\n");
+        }
+        file.push_str(&contents);
+    }
+    return Ok(Some(files));
+}
+
+// files without the .tera extension are still templated if their first line is this marker
+const TEMPLATE_MARKER: &str = "# bargo:template";
+
+fn has_template_marker(path: &path::Path) -> Result<bool> {
+    let mut buf = [0u8; TEMPLATE_MARKER.len() + 2];
+    let mut file = fs::File::open(path)?;
+    let mut read = 0;
+    while read < buf.len() {
+        let n = file.read(&mut buf[read..])?;
+        if n == 0 {
+            break;
+        }
+        read += n;
+    }
+
+    let first_line = buf[..read]
+        .split(|b| *b == b'\n')
+        .next()
+        .unwrap_or_default();
+    return Ok(first_line.trim_ascii_end() == TEMPLATE_MARKER.as_bytes());
+}
+
+// renders any tera expressions in a template's file name (eg. "{{ region }}-values.yaml.tera")
+// using the same context as the file contents
+fn render_file_name(file_name: &str, tera_context: &serde_json::Value) -> Result<String> {
+    if !file_name.contains("{{") {
+        return Ok(file_name.to_string());
+    }
+
+    let rendered = tera::Tera::one_off(
+        file_name,
+        &tera::Context::from_value(tera_context.clone())?,
+        false,
+    )
+    .map_err(|e| anyhow!("failed to render file name {:?}: {}", file_name, e))?;
+
+    if !is_plain_file_name(&rendered) {
+        return Err(anyhow!(
+            "file name {:?} rendered to invalid file name {:?}",
+            file_name,
+            rendered
+        ));
+    }
+    return Ok(rendered);
+}
+
+// links each allowed command into a new directory, failing if any of them aren't in PATH
+fn script_path(commands: &[String]) -> Result<ScriptPath> {
+    let dir = tempdir::TempDir::new("bargo-script-path")?;
+    for command in commands {
+        if !is_plain_file_name(command) {
+            return Err(anyhow!(
+                "allowed script command {:?} must be a command name, not a path",
+                command
+            ));
+        }
+        let resolved = find_in_path(command).ok_or(anyhow!(
+            "allowed script command {:?} isn't in PATH",
+            command
+        ))?;
+        std::os::unix::fs::symlink(&resolved, dir.path().join(command))?;
+    }
+    let bash = find_in_path("bash").ok_or(anyhow!("bash isn't in PATH"))?;
+    return Ok(ScriptPath { dir, bash });
+}
+
+fn find_in_path(command: &str) -> Option<path::PathBuf> {
+    let paths = std::env::var_os("PATH")?;
+    return std::env::split_paths(&paths)
+        .map(|dir| dir.join(command))
+        .find(|candidate| candidate.is_file());
+}
+
+// a single path component which can't point to another directory
+fn is_plain_file_name(name: &str) -> bool {
+    return !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']);
+}
+
+// resolves path, failing if it's outside the input path (eg. through .. or a symlink) so a config
+// or metadata file can't make bargo read from elsewhere on the machine
+fn check_within_input(input_path: &path::Path, path: &path::Path) -> Result<path::PathBuf> {
+    let resolved = path
+        .canonicalize()
+        .map_err(|e| anyhow!("failed to resolve {:?}: {}", path, e))?;
+    if !resolved.starts_with(input_path) {
+        return Err(anyhow!(
+            "{:?} resolves to {:?} which is outside the input path {:?}",
+            path,
+            resolved,
+            input_path
+        ));
+    }
+    return Ok(resolved);
+}
+
+// scripts can leave symlinks in an app's output, fail if any of them resolve outside root (or
+// don't resolve) since copying or uploading the output would follow them
+fn check_symlinks(dir: &path::Path, root: &path::Path) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let file_type = fs::symlink_metadata(&path)?.file_type();
+        if file_type.is_symlink() {
+            match path.canonicalize() {
+                Ok(resolved) if resolved.starts_with(root) => (),
+                Ok(resolved) => {
+                    return Err(anyhow!(
+                        "{:?} links to {:?} which is outside the output path",
+                        path,
+                        resolved
+                    ))
+                }
+                Err(e) => return Err(anyhow!("{:?} is a broken symlink: {}", path, e)),
+            }
+            continue;
+        }
+        if file_type.is_dir() {
+            check_symlinks(&path, root)?;
+        }
+    }
+    return Ok(());
+}
+
+static INVALID_DNS_SUBDOMAIN_CHARS: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"[^-a-z0-9.]").unwrap());
+
+static INVALID_DNS_LABEL_CHARS: LazyLock<regex::Regex> =
+    LazyLock::new(|| regex::Regex::new(r"[^-a-z0-9]").unwrap());
+
+// based on https://github.com/argoproj/applicationset/blob/de10506d8ff81970567381ef3f4dae4b76f50220/pkg/generators/cluster.go#L172
+// santize the name in accordance with the below rules
+// 1. contain no more than 253 characters (63 for namespaces) or the configured max length
+// 2. contain only lowercase alphanumeric characters, '-' or '.' (unless dots aren't allowed, they
+//    never are in namespaces)
+// 3. start and end with an alphanumeric character
+fn sanitize_name(name: &str, options: &SanitizeOptions, namespace: bool) -> String {
+    let allow_dots = options.allow_dots.unwrap_or(true) && !namespace;
+    let invalid_dns_name_chars = match allow_dots {
+        true => &*INVALID_DNS_SUBDOMAIN_CHARS,
+        false => &*INVALID_DNS_LABEL_CHARS,
+    };
+    let max_dns_name_length = match namespace {
+        true => options.max_length.unwrap_or(63).min(63),
+        false => options.max_length.unwrap_or(253).min(253),
+    };
+    let replacement = options.replacement.as_deref().unwrap_or("-");
+
+    let trim = |name: &str| {
+        name.trim_matches(|c: char| !c.is_ascii_alphanumeric())
+            .to_string()
+    };
+    let sanitized = name.to_lowercase();
+    let sanitized = invalid_dns_name_chars
+        .replace_all(&sanitized, replacement)
+        .to_string();
+    let sanitized = trim(&sanitized);
+    if sanitized.len() <= max_dns_name_length {
+        return sanitized;
+    }
+
+    // the hash of the full name keeps truncated names which share a prefix unique
+    if options.hash_suffix.unwrap_or(false) {
+        let hash = format!("{:x}", Sha256::digest(name.as_bytes()));
+        let hash = &hash[..8];
+        let prefix = trim(&sanitized[..max_dns_name_length.saturating_sub(hash.len() + 1)]);
+        if prefix.is_empty() {
+            return hash.to_string();
+        }
+        return format!("{}-{}", prefix, hash);
     }
+    return trim(&sanitized[..max_dns_name_length]);
+}
 
-    fn copy_and_template_folder(
-        &self,
-        tera_context: &serde_json::Value,
-        from_dir: &path::PathBuf,
-        to_dir: &path::PathBuf,
-    ) -> Result<()> {
-        // info!(from=?from_dir, to=?to_dir, "copying!");
-        for f in fs::read_dir(from_dir)? {
-            let entry = f?;
-            let path = entry.path();
-            if path.is_dir() {
-                self.copy_and_template_folder(
-                    &tera_context.clone(),
-                    &path,
-                    &to_dir.join(&entry.file_name()),
-                )?;
+// the replacement must keep names valid and max_length must leave room for the hash suffix (a -
+// followed by 8 characters) with at least one character of the name before it
+fn validate_sanitize_options(options: &SanitizeOptions) -> Result<()> {
+    match options.replacement.as_deref() {
+        Some(replacement)
+            if !replacement
+                .chars()
+                .all(|c| c == '-' || c.is_ascii_lowercase() || c.is_ascii_digit()) =>
+        {
+            return Err(anyhow!(
+                "sanitize.replacement {:?} may only contain lowercase alphanumeric characters and -",
+                replacement
+            ));
+        }
+        _ => (),
+    }
+    let min_length = match options.hash_suffix.unwrap_or(false) {
+        true => 10,
+        false => 1,
+    };
+    match options.max_length {
+        Some(max_length) if max_length < min_length => {
+            return Err(anyhow!(
+                "sanitize.max_length must be at least {}, got {}",
+                min_length,
+                max_length
+            ));
+        }
+        _ => (),
+    }
+    return Ok(());
+}
+
+// which directories discover_files descends into besides the ignore_dirs
+#[derive(Clone, Copy)]
+struct WalkOptions {
+    // hidden directories, eg. .git, are skipped unless set
+    include_hidden_dirs: bool,
+    // directories deeper than this below the root are skipped
+    max_depth: Option<usize>,
+}
+
+// recursively walks root returning all files accepted by the filter in sorted order. Directories
+// matching one of the ignore_dirs globs (relative to root) are not descended into at all
+fn find_files(
+    root: &path::Path,
+    ignore_dirs: &[glob::Pattern],
+    filter: &dyn Fn(&path::Path) -> bool,
+) -> Result<Vec<path::PathBuf>> {
+    let options = WalkOptions {
+        include_hidden_dirs: true,
+        max_depth: None,
+    };
+    return discover_files(root, ignore_dirs, options, filter);
+}
+
+// find_files for searching an input path for apps and templates, additionally skipping the
+// directories excluded by the walk options
+fn discover_files(
+    root: &path::Path,
+    ignore_dirs: &[glob::Pattern],
+    options: WalkOptions,
+    filter: &dyn Fn(&path::Path) -> bool,
+) -> Result<Vec<path::PathBuf>> {
+    let mut found = Vec::new();
+    find_files_in_dir(root, root, ignore_dirs, options, filter, &mut found)?;
+    return Ok(found);
+}
+
+fn find_files_in_dir(
+    root: &path::Path,
+    dir: &path::Path,
+    ignore_dirs: &[glob::Pattern],
+    options: WalkOptions,
+    filter: &dyn Fn(&path::Path) -> bool,
+    found: &mut Vec<path::PathBuf>,
+) -> Result<()> {
+    let mut entries = fs::read_dir(dir)
+        .map_err(|e| anyhow!("failed to read directory {:?}: {}", dir, e))?
+        .collect::<Result<Vec<_>, _>>()?;
+    entries.sort_by_key(|e| e.file_name());
+
+    for entry in entries {
+        let path = entry.path();
+        if path.is_dir() {
+            let relative_path = path.strip_prefix(root)?;
+            if ignore_dirs.iter().any(|p| p.matches_path(relative_path)) {
+                debug!(dir=?path, "skipping ignored directory");
                 continue;
             }
-            let mut to_path = to_dir.join(&entry.file_name());
-            fs::create_dir_all(to_path.parent().unwrap())?;
-
-            if path.extension().unwrap_or_default() == "tera" {
-                info!(vars=?tera_context, to_path=?to_path, "templating file");
-                let tera_template_name = path.strip_prefix(&self.input_path)?;
-                let contents = self.render_template(
-                    &tera_template_name.display().to_string(),
-                    tera_context.clone(),
-                )?;
-                to_path.set_extension("");
-                fs::write(to_path, contents)?;
+            if !options.include_hidden_dirs && entry.file_name().to_string_lossy().starts_with('.')
+            {
+                debug!(dir=?path, "skipping hidden directory");
                 continue;
             }
+            match options.max_depth {
+                Some(max_depth) if relative_path.components().count() > max_depth => {
+                    debug!(dir=?path, max_depth, "skipping directory beyond max depth");
+                    continue;
+                }
+                _ => (),
+            }
+            find_files_in_dir(root, &path, ignore_dirs, options, filter, found)?;
+            continue;
+        }
 
-            debug!(from_path=?path, to_path=?to_path, "copying file");
-            fs::copy(path, to_path)?;
+        if filter(&path) {
+            found.push(path);
         }
-        return Ok(());
     }
+    return Ok(());
+}
 
-    fn write_bargo_values(
-        &self,
-        tera_context: &serde_json::Value,
-        template_context: &TemplateContext,
-        to_dir: &path::PathBuf,
-    ) -> Result<()> {
-        if !to_dir.join("files/Chart.yaml").exists() {
-            info!(
-                ?to_dir,
-                "not writing bargo values file as didn't find Chart.yaml file in files subdir"
-            );
-            return Ok(());
+// reads the config with the profile applied, returning it along with the names of any targets the
+// profile removed
+fn read_config(input_path: &path::Path, profile: Option<&str>) -> Result<(Config, Vec<String>)> {
+    let config_file_path = input_path.join("bargo.toml");
+    let (mut config, unknown_keys): (Config, _) = read_toml_file(&config_file_path)?;
+    check_unknown_keys(
+        &config_file_path,
+        &unknown_keys,
+        config.deny_unknown_fields.unwrap_or(false),
+    )?;
+
+    let excluded_targets = match profile {
+        Some(profile) => apply_profile(&mut config, profile)
+            .map_err(|e| anyhow!("{:?}: {}", config_file_path, e))?,
+        None => Vec::new(),
+    };
+
+    // target names are used as output directory names
+    for target in config.targets.iter() {
+        if !is_plain_file_name(&target.name) {
+            return Err(anyhow!(
+                "{:?}: target name {:?} isn't a valid directory name",
+                config_file_path,
+                target.name
+            ));
         }
+    }
 
-        let mut bargo_vars = serde_json::Map::new();
-        bargo_vars.insert("vars".to_string(), serde_json::to_value(tera_context)?);
-        bargo_vars.insert(
-            "metadata".to_string(),
-            serde_json::to_value(template_context)?,
-        );
+    info!(config_file_path=?config_file_path, config=?config, "loaded config");
+    return Ok((config, excluded_targets));
+}
 
-        let mut bargo_values_map = serde_json::Map::new();
-        bargo_values_map.insert("bargo".to_string(), serde_json::Value::Object(bargo_vars));
-        // put values under global namespace so that all subcharts have access to them
-        let mut values_map = serde_json::Map::new();
-        values_map.insert(
-            "global".to_string(),
-            serde_json::Value::Object(bargo_values_map),
-        );
-        let values = serde_json::Value::Object(values_map);
+// reads a vars file, fetching urls with curl. The format is taken from the extension, defaulting
+// to yaml
+fn read_vars_file(input_path: &path::Path, vars_file: &VarsFile) -> Result<serde_json::Value> {
+    let (name, contents) = match vars_file {
+        VarsFile::Path(p) if p.starts_with("https://") => (p.as_str(), fetch_vars_file(p, None)?),
+        VarsFile::Path(p) => {
+            let path = check_within_input(input_path, &input_path.join(p))?;
+            let contents = fs::read_to_string(&path)
+                .map_err(|e| anyhow!("failed to read vars file {:?}: {}", path, e))?;
+            (p.as_str(), contents)
+        }
+        VarsFile::Url { url, token_env } => {
+            let token = match token_env {
+                Some(token_env) => Some(std::env::var(token_env).map_err(|_| {
+                    anyhow!("env var {} for vars file {} isn't set", token_env, url)
+                })?),
+                None => None,
+            };
+            (url.as_str(), fetch_vars_file(url, token)?)
+        }
+    };
 
-        let s_values = yaml_encode(&values)?;
-        let mut file = fs::File::create(to_dir.join("files/bargo_values.yaml"))?;
-        file.write_all(s_values.as_bytes())?;
-        file.flush()?;
+    let extension = path::Path::new(name.split(['?', '#']).next().unwrap_or_default())
+        .extension()
+        .unwrap_or_default();
+    let vars: serde_json::Value = match extension.to_str() {
+        Some("json") => serde_json::from_str(&contents).map_err(|e| anyhow!("{}", e)),
+        Some("toml") => toml::from_str(&contents).map_err(|e| anyhow!("{}", e)),
+        _ => serde_yaml::from_str(&contents).map_err(|e| anyhow!("{}", e)),
+    }
+    .map_err(|e| anyhow!("failed to parse vars file {}: {}", name, e))?;
+    if !vars.is_object() {
+        return Err(anyhow!("vars file {} doesn't hold a map of vars", name));
+    }
+    info!(vars_file = name, "loaded vars file");
+    return Ok(vars);
+}
 
-        Ok(())
+// fetches a url with curl, only following https redirects. The token is passed to curl on stdin
+// so it doesn't show up in the process list
+fn fetch_vars_file(url: &str, token: Option<String>) -> Result<String> {
+    if !url.starts_with("https://") {
+        return Err(anyhow!("vars file url {} must use https", url));
+    }
+    let mut command = std::process::Command::new("curl");
+    command.args([
+        "--fail",
+        "--silent",
+        "--show-error",
+        "--location",
+        "--proto",
+        "=https",
+        "--max-time",
+        "30",
+    ]);
+    if token.is_some() {
+        command.args(["--header", "@-"]);
+    }
+    let mut child = command
+        .arg(url)
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to run curl: {}", e))?;
+    // unwrap since stdin is piped, dropping it closes curl's stdin
+    let mut stdin = child.stdin.take().unwrap();
+    match token {
+        Some(token) => stdin.write_all(format!("Authorization: Bearer {}\n", token).as_bytes())?,
+        None => (),
     }
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "failed to fetch vars file {}: {}",
+            url,
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    return String::from_utf8(output.stdout)
+        .map_err(|_| anyhow!("vars file {} isn't valid utf-8", url));
 }
 
-// based on https://github.com/argoproj/applicationset/blob/de10506d8ff81970567381ef3f4dae4b76f50220/pkg/generators/cluster.go#L172
-// santize the name in accordance with the below rules
-// 1. contain no more than 253 characters
-// 2. contain only lowercase alphanumeric characters, '-' or '.'
-// 3. start and end with an alphanumeric character
-fn sanitize_name(name: &str) -> String {
-    let invalid_dns_name_chars = regex::Regex::new(r"[^-a-z0-9.]").unwrap();
-    let max_dns_name_length = 253;
-
-    let name = name.to_lowercase();
-    let name = invalid_dns_name_chars.replace_all(&name, "-").to_string();
-    let name = if name.len() > max_dns_name_length {
-        name[..max_dns_name_length].to_string()
-    } else {
-        name
+// applies the overrides of the named profile to the config, returning the names of the targets it
+// removed
+fn apply_profile(config: &mut Config, name: &str) -> Result<Vec<String>> {
+    let profile = match config
+        .profiles
+        .as_mut()
+        .and_then(|profiles| profiles.remove(name))
+    {
+        Some(profile) => profile,
+        None => {
+            return Err(anyhow!(
+                "unknown profile {:?}, the config has profiles: {}",
+                name,
+                config
+                    .profiles
+                    .iter()
+                    .flat_map(|profiles| profiles.keys())
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        }
     };
 
-    return name.trim_matches('-').to_string();
+    match profile.application_template {
+        Some(application_template) => config.application_template = application_template,
+        None => (),
+    }
+    let mut excluded_targets = Vec::new();
+    match profile.targets {
+        Some(targets) => {
+            excluded_targets = config
+                .targets
+                .iter()
+                .filter(|t| !targets.iter().any(|target| target.name == t.name))
+                .map(|t| t.name.clone())
+                .collect();
+            config.targets = targets;
+        }
+        None => (),
+    }
+    match profile.vars {
+        Some(vars) => merge(config.vars.get_or_insert_with(default_serde_object), vars),
+        None => (),
+    }
+    match profile.default_application_options {
+        Some(options) => merge(
+            config
+                .default_application_options
+                .get_or_insert_with(default_serde_object),
+            options,
+        ),
+        None => (),
+    }
+    info!(profile = name, "applied config profile");
+    return Ok(excluded_targets);
 }
 
-fn read_config(input_path: &path::Path) -> Result<Config> {
+// reads the bargo.toml of an input after the first if it has one
+fn read_input_config(input_path: &path::Path, config: &Config) -> Result<Option<InputConfig>> {
     let config_file_path = input_path.join("bargo.toml");
-    let config = fs::read(&config_file_path)
-        .map_err(|e| anyhow!("failed to parse config file {:?}: {}", config_file_path, e))?;
-    let config = toml::from_slice(&config)
-        .map_err(|e| anyhow!("failed to parse config file {:?}: {}", config_file_path, e))?;
+    if !config_file_path.exists() {
+        return Ok(None);
+    }
+    let (input_config, _): (InputConfig, _) = read_toml_file(&config_file_path)?;
+    for target in input_config.targets.iter() {
+        if !config.targets.iter().any(|t| t.name == target.name) {
+            warn!(config_file_path=?config_file_path, target=target.name, "input config sets vars for a target which isn't in the config, ignoring them");
+        }
+    }
+    info!(config_file_path=?config_file_path, config=?input_config, "loaded input config");
+    return Ok(Some(input_config));
+}
 
-    info!(config_file_path=?config_file_path, config=?config, "loaded config");
-    return Ok(config);
+// the ignore_dirs globs of an input along with any of the given directories within it
+fn input_ignore_dirs<'a>(
+    input_path: &path::Path,
+    globs: &Option<Vec<String>>,
+    dirs: impl Iterator<Item = &'a path::PathBuf>,
+) -> Result<Vec<glob::Pattern>> {
+    let mut ignore_dirs = globs
+        .iter()
+        .flatten()
+        .map(|d| glob::Pattern::new(d.trim_end_matches("/**").trim_end_matches('/')))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow!("invalid ignore_dirs glob: {}", e))?;
+    // generated output contains copies of metadata files, so output directories within the
+    // input must never be discovered as apps
+    for dir in dirs {
+        let dir = match dir.canonicalize() {
+            Ok(dir) => dir,
+            Err(_) => continue,
+        };
+        match dir.strip_prefix(input_path) {
+            Ok(relative_dir) if !relative_dir.as_os_str().is_empty() => ignore_dirs.push(
+                glob::Pattern::new(&glob::Pattern::escape(&relative_dir.to_string_lossy()))?,
+            ),
+            _ => (),
+        }
+    }
+    return Ok(ignore_dirs);
+}
+
+// parses a toml file returning the keys which didn't match any field
+fn read_toml_file<T: serde::de::DeserializeOwned>(file: &path::Path) -> Result<(T, Vec<String>)> {
+    let contents = fs::read_to_string(file)
+        .map_err(|e| anyhow!("failed to read config file {:?}: {}", file, e))?;
+    return parse_toml(file, &contents);
+}
+
+fn parse_toml<T: serde::de::DeserializeOwned>(
+    file: &path::Path,
+    contents: &str,
+) -> Result<(T, Vec<String>)> {
+    let mut unknown_keys = Vec::new();
+    let mut deserializer = toml::de::Deserializer::new(contents);
+    let value = serde_ignored::deserialize(&mut deserializer, |path| {
+        unknown_keys.push(path.to_string())
+    })
+    .map_err(|e| diagnostics::toml_error(file, contents, &e))?;
+    return Ok((value, unknown_keys));
+}
+
+// unknown keys are usually misspelled options so they are always warned about, and are an error
+// when deny_unknown_fields is set in bargo.toml
+fn check_unknown_keys(
+    file: &path::Path,
+    unknown_keys: &[String],
+    deny_unknown_fields: bool,
+) -> Result<()> {
+    if unknown_keys.is_empty() {
+        return Ok(());
+    }
+
+    if deny_unknown_fields {
+        return Err(anyhow!(
+            "failed to parse {:?}: unknown keys {}",
+            file,
+            unknown_keys.join(", ")
+        ));
+    }
+    for key in unknown_keys {
+        warn!(?file, key, "ignoring unknown key");
+    }
+    return Ok(());
+}
+
+// the global vars merged with the target's
+fn global_and_target_vars(config: &Config, target: &ConfigTarget) -> serde_json::Value {
+    // merged into an empty object so merge suffixes in the global vars are removed too
+    let mut vars = default_serde_object();
+    merge(
+        &mut vars,
+        config.vars.clone().unwrap_or_else(default_serde_object),
+    );
+    merge(
+        &mut vars,
+        target.vars.clone().unwrap_or_else(default_serde_object),
+    );
+    return vars;
+}
+
+// merges the layers in order, returning each leaf of the result keyed by its dotted path with its
+// value and the name of the last layer which set it
+fn explain_layers(layers: &[(&str, Option<&serde_json::Value>)]) -> serde_json::Value {
+    let mut merged = default_serde_object();
+    let mut sources = BTreeMap::new();
+    for (name, layer) in layers {
+        let layer = match layer {
+            Some(layer) => layer,
+            None => continue,
+        };
+        merge(&mut merged, (*layer).clone());
+        for (path, _) in leaf_values("", layer) {
+            sources.insert(path, *name);
+        }
+    }
+
+    let mut explained = serde_json::Map::new();
+    for (path, value) in leaf_values("", &merged) {
+        let source = sources.get(&path).copied().unwrap_or_default();
+        explained.insert(
+            path,
+            serde_json::json!({ "value": value, "source": source }),
+        );
+    }
+    return serde_json::Value::Object(explained);
+}
+
+// every non object value (and nested empty object) within value keyed by its dotted path, without
+// any merge strategy suffix
+fn leaf_values(prefix: &str, value: &serde_json::Value) -> Vec<(String, serde_json::Value)> {
+    match value {
+        serde_json::Value::Object(object) if !object.is_empty() => {
+            let mut leaves = Vec::new();
+            for (key, value) in object {
+                let (key, _) = merge_key(key);
+                let path = match prefix.is_empty() {
+                    true => key.to_string(),
+                    false => format!("{}.{}", prefix, key),
+                };
+                leaves.extend(leaf_values(&path, value));
+            }
+            return leaves;
+        }
+        serde_json::Value::Object(_) if prefix.is_empty() => return Vec::new(),
+        _ => return vec![(prefix.to_string(), value.clone())],
+    }
 }
 
 fn default_serde_object() -> serde_json::Value {
     serde_json::Value::Object(serde_json::Map::new())
 }
 
-fn read_metadata(metadata_file: &path::Path) -> Result<crate::Metadata> {
-    let config = fs::read(&metadata_file)
-        .map_err(|e| anyhow!("failed to parse config file {:?}: {}", metadata_file, e))?;
-    let config = toml::from_slice(&config)
-        .map_err(|e| anyhow!("failed to parse config file {:?}: {}", metadata_file, e))?;
+fn read_metadata(
+    metadata_file: &path::Path,
+    deny_unknown_fields: bool,
+    template_context: Option<&serde_json::Value>,
+) -> Result<Metadata> {
+    let mut contents = fs::read_to_string(metadata_file)
+        .map_err(|e| anyhow!("failed to read config file {:?}: {}", metadata_file, e))?;
+    match template_context {
+        Some(template_context) => {
+            contents = tera::Tera::one_off(
+                &contents,
+                &tera::Context::from_value(template_context.clone())?,
+                false,
+            )
+            .map_err(|e| {
+                anyhow!(
+                    "failed to render metadata file {:?}: {:#}",
+                    metadata_file,
+                    anyhow::Error::from(e)
+                )
+            })?;
+        }
+        None => (),
+    }
+
+    let (config, unknown_keys) = parse_toml(metadata_file, &contents)?;
+    check_unknown_keys(metadata_file, &unknown_keys, deny_unknown_fields)?;
 
     info!(file=?metadata_file, config=?config, "loaded metadata file");
     return Ok(config);
 }
 
+// merges the --set-json objects followed by the --var key=value overrides into an object, dotted
+// keys setting nested values
+fn parse_var_overrides(set_json: &[String], vars: &[String]) -> Result<serde_json::Value> {
+    let mut overrides = parse_json_overrides("--set-json", set_json)?;
+    for var in vars {
+        let (key, value) = var
+            .split_once('=')
+            .ok_or(anyhow!("invalid --var {:?}, expected KEY=VALUE", var))?;
+        if key.split('.').any(|k| k.is_empty()) {
+            return Err(anyhow!(
+                "invalid --var {:?}, the key has an empty part",
+                var
+            ));
+        }
+        let mut value = serde_json::from_str(value)
+            .unwrap_or_else(|_| serde_json::Value::String(value.to_string()));
+        for k in key.rsplit('.') {
+            value = serde_json::json!({ k: value });
+        }
+        merge(&mut overrides, value);
+    }
+    return Ok(overrides);
+}
+
+// merges json objects given on the command line with flag in order
+fn parse_json_overrides(flag: &str, values: &[String]) -> Result<serde_json::Value> {
+    let mut overrides = default_serde_object();
+    for value in values {
+        let object: serde_json::Value = serde_json::from_str(value)
+            .map_err(|e| anyhow!("invalid {} {:?}: {}", flag, value, e))?;
+        if !object.is_object() {
+            return Err(anyhow!("invalid {} {:?}, expected an object", flag, value));
+        }
+        merge(&mut overrides, object);
+    }
+    return Ok(overrides);
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum MergeStrategy {
+    Replace,
+    Append,
+    UniqueAppend,
+}
+
+// the key and merge strategy of an object key, arrays replace the existing value unless their key
+// ends with + to append to it or +unique to append the items which aren't already present, eg.
+//
+//   vars = { "tolerations+" = [{ key = "dedicated", operator = "Exists" }] }
+//
+// A key meant to end with a literal + or +unique escapes it with a backslash, eg. 'c\+' sets c+
+fn merge_key(key: &str) -> (Cow<'_, str>, MergeStrategy) {
+    match key
+        .strip_suffix("\\+unique")
+        .or_else(|| key.strip_suffix("\\+"))
+    {
+        Some(escaped) => {
+            return (
+                Cow::Owned(format!("{}{}", escaped, &key[escaped.len() + 1..])),
+                MergeStrategy::Replace,
+            )
+        }
+        None => (),
+    }
+    match key.strip_suffix("+unique") {
+        Some(key) => return (Cow::Borrowed(key), MergeStrategy::UniqueAppend),
+        None => (),
+    }
+    match key.strip_suffix('+') {
+        Some(key) => return (Cow::Borrowed(key), MergeStrategy::Append),
+        None => return (Cow::Borrowed(key), MergeStrategy::Replace),
+    }
+}
+
+// removes the merge suffixes from the keys of an object which replaces a value rather than being
+// merged into one, its nulls are kept since there's nothing for them to remove
+fn strip_merge_suffixes(value: serde_json::Value) -> serde_json::Value {
+    match value {
+        serde_json::Value::Object(object) => {
+            return serde_json::Value::Object(
+                object
+                    .into_iter()
+                    .map(|(k, v)| (merge_key(&k).0.into_owned(), strip_merge_suffixes(v)))
+                    .collect(),
+            )
+        }
+        value => return value,
+    }
+}
+
 // from: https://stackoverflow.com/questions/47070876/how-can-i-merge-two-json-objects-with-rust
 fn merge(a: &mut serde_json::Value, b: serde_json::Value) {
     if let serde_json::Value::Object(a) = a {
         if let serde_json::Value::Object(b) = b {
             for (k, v) in b {
+                let (key, strategy) = merge_key(&k);
                 if v.is_null() {
-                    a.remove(&k);
-                } else {
-                    merge(a.entry(k).or_insert(serde_json::Value::Null), v);
+                    a.remove(key.as_ref());
+                    continue;
+                }
+                let existing = a.entry(key).or_insert(serde_json::Value::Null);
+                match (strategy, existing, v) {
+                    (
+                        MergeStrategy::Append | MergeStrategy::UniqueAppend,
+                        serde_json::Value::Array(existing),
+                        serde_json::Value::Array(items),
+                    ) => {
+                        for item in items {
+                            if strategy == MergeStrategy::UniqueAppend && existing.contains(&item) {
+                                continue;
+                            }
+                            existing.push(item);
+                        }
+                    }
+                    (_, existing, v @ serde_json::Value::Object(_)) if !existing.is_object() => {
+                        *existing = strip_merge_suffixes(v);
+                    }
+                    // nothing to append to, or not arrays, so the value is set as is
+                    (_, existing, v) => merge(existing, v),
                 }
             }
 
@@ -537,3 +4859,264 @@ fn nindent_filter(
     let s = s.replace("\n", &indent);
     return Ok(serde_json::Value::String(s));
 }
+
+// Merges the `with` object into an object using the same rules as vars, eg.
+// {{ defaults | merge(with=overrides) | yaml_encode }}
+fn merge_filter(
+    value: &serde_json::Value,
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    if !value.is_object() {
+        return Err(tera::Error::msg("Filter `merge` expected an object"));
+    }
+    let with = match args.get("with") {
+        Some(with) if with.is_object() => with,
+        Some(_) => {
+            return Err(tera::Error::msg(
+                "Filter `merge` expected the arg `with` to be an object",
+            ))
+        }
+        None => {
+            return Err(tera::Error::msg(
+                "Filter `merge` expected an arg called `with`",
+            ))
+        }
+    };
+
+    let mut merged = value.clone();
+    merge(&mut merged, with.clone());
+    return Ok(merged);
+}
+
+// Parses a yaml string into a value
+fn from_yaml_filter(
+    value: &serde_json::Value,
+    _args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let s = tera::try_get_value!("from_yaml", "value", String, value);
+    return serde_yaml::from_str(&s)
+        .map_err(|e| tera::Error::msg(format!("Filter `from_yaml` failed to parse yaml: {e}")));
+}
+
+// Parses a json string into a value
+fn from_json_filter(
+    value: &serde_json::Value,
+    _args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let s = tera::try_get_value!("from_json", "value", String, value);
+    return serde_json::from_str(&s)
+        .map_err(|e| tera::Error::msg(format!("Filter `from_json` failed to parse json: {e}")));
+}
+
+// Compares a semver version with the `with` version, returning -1, 0 or 1. A leading v is ignored
+fn semver_compare_filter(
+    value: &serde_json::Value,
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let version = tera::try_get_value!("semver_compare", "value", String, value);
+    let with = match args.get("with") {
+        Some(with) => tera::try_get_value!("semver_compare", "with", String, with),
+        None => {
+            return Err(tera::Error::msg(
+                "Filter `semver_compare` expected an arg called `with`",
+            ))
+        }
+    };
+    let ordering =
+        parse_semver("semver_compare", &version)?.cmp(&parse_semver("semver_compare", &with)?);
+    return Ok(serde_json::Value::from(ordering as i8));
+}
+
+// Whether a semver version matches the `req` requirement, eg.
+// {% if chart_version | semver_matches(req=">=2.0.0") %}
+fn semver_matches_filter(
+    value: &serde_json::Value,
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let version = tera::try_get_value!("semver_matches", "value", String, value);
+    let req = match args.get("req") {
+        Some(req) => tera::try_get_value!("semver_matches", "req", String, req),
+        None => {
+            return Err(tera::Error::msg(
+                "Filter `semver_matches` expected an arg called `req`",
+            ))
+        }
+    };
+    let req = semver::VersionReq::parse(&req).map_err(|e| {
+        tera::Error::msg(format!(
+            "Filter `semver_matches` got an invalid requirement {:?}: {}",
+            req, e
+        ))
+    })?;
+    return Ok(serde_json::Value::Bool(
+        req.matches(&parse_semver("semver_matches", &version)?),
+    ));
+}
+
+fn parse_semver(filter: &str, version: &str) -> tera::Result<semver::Version> {
+    return semver::Version::parse(version.strip_prefix('v').unwrap_or(version)).map_err(|e| {
+        tera::Error::msg(format!(
+            "Filter `{}` got an invalid version {:?}: {}",
+            filter, version, e
+        ))
+    });
+}
+
+// Replaces every match of the `pattern` regex with `replacement`, which can reference capture
+// groups as $1 or ${name}, eg. {{ name | regex_replace(pattern="[^a-z0-9-]+", replacement="-") }}
+fn regex_replace_filter(
+    value: &serde_json::Value,
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let s = tera::try_get_value!("regex_replace", "value", String, value);
+    let pattern = match args.get("pattern") {
+        Some(pattern) => tera::try_get_value!("regex_replace", "pattern", String, pattern),
+        None => {
+            return Err(tera::Error::msg(
+                "Filter `regex_replace` expected an arg called `pattern`",
+            ))
+        }
+    };
+    let replacement = match args.get("replacement") {
+        Some(replacement) => {
+            tera::try_get_value!("regex_replace", "replacement", String, replacement)
+        }
+        None => {
+            return Err(tera::Error::msg(
+                "Filter `regex_replace` expected an arg called `replacement`",
+            ))
+        }
+    };
+
+    let re = cached_regex(&pattern).map_err(|e| {
+        tera::Error::msg(format!(
+            "Filter `regex_replace` got an invalid pattern {:?}: {}",
+            pattern, e
+        ))
+    })?;
+    return Ok(serde_json::Value::String(
+        re.replace_all(&s, replacement.as_str()).into_owned(),
+    ));
+}
+
+// regexes built from patterns only known at render time, compiled once per run since the same
+// pattern is usually used for every app
+static REGEX_CACHE: LazyLock<Mutex<HashMap<String, regex::Regex>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+
+fn cached_regex(pattern: &str) -> Result<regex::Regex, regex::Error> {
+    // unwrap since the lock is never held across a panic
+    let mut cache = REGEX_CACHE.lock().unwrap();
+    match cache.get(pattern) {
+        Some(regex) => return Ok(regex.clone()),
+        None => {
+            let regex = regex::Regex::new(pattern)?;
+            cache.insert(pattern.to_string(), regex.clone());
+            return Ok(regex);
+        }
+    }
+}
+
+// Encodes an object into toml
+fn to_toml_filter(
+    value: &serde_json::Value,
+    _args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    if !value.is_object() {
+        return Err(tera::Error::msg("Filter `to_toml` expected an object"));
+    }
+    // converted to a toml value first since it orders plain values before tables as toml requires
+    return toml::Value::try_from(value)
+        .and_then(|value| toml::to_string(&value))
+        .map(|s| serde_json::Value::String(s.trim().to_string()))
+        .map_err(|e| tera::Error::msg(format!("Filter `to_toml` failed to encode toml: {e}")));
+}
+
+// Sanitizes a string into a kubernetes name the same way project and app names are, following the
+// sanitize options. namespace=true sanitizes it as a namespace name
+fn k8s_name_filter(
+    options: SanitizeOptions,
+) -> impl Fn(&serde_json::Value, &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value>
+{
+    return move |value, args| {
+        let name = tera::try_get_value!("k8s_name", "value", String, value);
+        let namespace = match args.get("namespace") {
+            Some(namespace) => tera::try_get_value!("k8s_name", "namespace", bool, namespace),
+            None => false,
+        };
+        return Ok(serde_json::Value::String(sanitize_name(
+            &name, &options, namespace,
+        )));
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn merge_key_strategies() {
+        let cases = [
+            ("a", "a", MergeStrategy::Replace),
+            ("a+", "a", MergeStrategy::Append),
+            ("a+unique", "a", MergeStrategy::UniqueAppend),
+            ("a\\+", "a+", MergeStrategy::Replace),
+            ("a\\+unique", "a+unique", MergeStrategy::Replace),
+        ];
+        for (key, expected_key, expected_strategy) in cases {
+            let (merged_key, strategy) = merge_key(key);
+            assert_eq!(merged_key, expected_key, "{}", key);
+            assert!(strategy == expected_strategy, "{}", key);
+        }
+    }
+
+    #[test]
+    fn merge_appends_arrays() {
+        let mut a = json!({"a": [1, 2], "b": [1, 2], "c": [1, 2]});
+        merge(
+            &mut a,
+            json!({"a+": [2, 3], "b+unique": [2, 3], "c": [3], "d+": [1]}),
+        );
+        assert_eq!(
+            a,
+            json!({"a": [1, 2, 2, 3], "b": [1, 2, 3], "c": [3], "d": [1]})
+        );
+    }
+
+    #[test]
+    fn merge_strips_nested_suffixes() {
+        let mut a = json!({"existing": {"items": [1]}});
+        merge(
+            &mut a,
+            json!({
+                "existing": {"items+": [2]},
+                "new": {"items+": [1], "nested": {"more+unique": [1]}},
+            }),
+        );
+        assert_eq!(
+            a,
+            json!({
+                "existing": {"items": [1, 2]},
+                "new": {"items": [1], "nested": {"more": [1]}},
+            })
+        );
+    }
+
+    #[test]
+    fn merge_nulls() {
+        let mut a = json!({"remove": 1, "sync_policy": {"prune": true}});
+        merge(
+            &mut a,
+            json!({
+                "remove": null,
+                "sync_policy": {"prune": null},
+                "new": {"automated": null, "list+": [1]},
+            }),
+        );
+        assert_eq!(
+            a,
+            json!({"sync_policy": {}, "new": {"automated": null, "list": [1]}})
+        );
+    }
+}
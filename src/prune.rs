@@ -0,0 +1,123 @@
+use anyhow::{anyhow, Result};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path,
+};
+
+use crate::{
+    drift::argocd_resources,
+    lockfile::LOCKFILE_NAME,
+    snapshot::{render_to_temp_dir, target_dirs},
+    Args,
+};
+
+// renders the input and lists the Applications and AppProjects in the previous output, or the
+// applications in the committed lockfile without one, which would no longer be generated and so
+// would be pruned by argocd. Fails when anything would be pruned unless confirm_prune is set
+pub fn prune_check(
+    args: Args,
+    previous_output_path: Option<&path::Path>,
+    confirm_prune: bool,
+) -> Result<()> {
+    let pruned = match previous_output_path {
+        Some(previous_output_path) => pruned_from_output(args, previous_output_path)?,
+        None => pruned_from_lockfile(args)?,
+    };
+    for (target_name, resource) in pruned.iter() {
+        println!("would prune: {} (target {})", resource, target_name);
+    }
+
+    if pruned.is_empty() {
+        println!("no applications or projects would be pruned");
+        return Ok(());
+    }
+    if !confirm_prune {
+        return Err(anyhow!(
+            "{} application(s) or project(s) would be pruned, rerun with --confirm-prune if this is intended",
+            pruned.len()
+        ));
+    }
+    println!(
+        "{} application(s) or project(s) will be pruned",
+        pruned.len()
+    );
+    return Ok(());
+}
+
+// (target, kind/name) of the resources in the previous output which are no longer generated
+fn pruned_from_output(
+    args: Args,
+    previous_output_path: &path::Path,
+) -> Result<Vec<(String, String)>> {
+    let (project_processor, _output_dir) = render_to_temp_dir(args, &[previous_output_path])?;
+
+    let mut previous = BTreeMap::new();
+    for target_name in target_dirs(previous_output_path)? {
+        let resources = argocd_resources(
+            &previous_output_path
+                .join(&target_name)
+                .join("argocd-config"),
+        )?;
+        previous.insert(target_name, resources);
+    }
+    let mut generated = BTreeMap::new();
+    for target_name in project_processor.processed_targets() {
+        let resources = argocd_resources(
+            &project_processor
+                .output_path()
+                .join(&target_name)
+                .join("argocd-config"),
+        )?;
+        generated.insert(target_name, resources);
+    }
+
+    let pruned = removed(
+        &previous,
+        &generated,
+        &project_processor.configured_targets(),
+    );
+    return Ok(pruned
+        .into_iter()
+        .map(|(target_name, (kind, name))| (target_name, format!("{}/{}", kind, name)))
+        .collect());
+}
+
+// (target, Application/project/name) of the applications in the lockfile which are no longer
+// generated, tombstoned ones included since they'd be pruned too
+fn pruned_from_lockfile(args: Args) -> Result<Vec<(String, String)>> {
+    let (project_processor, _output_dir) = render_to_temp_dir(args, &[])?;
+    let lockfile_path = project_processor.input_path().join(LOCKFILE_NAME);
+    if !lockfile_path.exists() {
+        return Err(anyhow!(
+            "{:?} doesn't exist, pass the previous output path to compare against instead",
+            lockfile_path
+        ));
+    }
+    let (_, pruned) = project_processor.updated_lockfile()?;
+    return Ok(pruned
+        .into_iter()
+        .map(|(target_name, entry)| (target_name, format!("Application/{}", entry.application)))
+        .collect());
+}
+
+// (target, item) of every previous item which is no longer generated. Configured targets the run
+// didn't render (eg. left out by --target) are skipped, while every item of a target which is no
+// longer configured is removed
+pub fn removed<T: Ord + Clone>(
+    previous: &BTreeMap<String, BTreeSet<T>>,
+    generated: &BTreeMap<String, BTreeSet<T>>,
+    configured_targets: &[String],
+) -> Vec<(String, T)> {
+    let mut removed = Vec::new();
+    for (target_name, previous_items) in previous {
+        let generated_items = match generated.get(target_name) {
+            Some(generated_items) => generated_items,
+            None if configured_targets.contains(target_name) => continue,
+            None => &BTreeSet::new(),
+        };
+        for item in previous_items.difference(generated_items) {
+            removed.push((target_name.clone(), item.clone()));
+        }
+    }
+    return removed;
+}
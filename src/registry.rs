@@ -0,0 +1,84 @@
+use std::{collections::HashMap, sync::Mutex};
+use tracing::info;
+
+// tera function returning the newest tag of a container image repository matching a glob, eg.
+//
+//   image: ghcr.io/example/app:{{ resolve_tag(repo="ghcr.io/example/app", pattern="v1.2.*") }}
+//
+// Tags are listed with the oras cli, so its registry logins apply, and each repository is only
+// listed once per run. Tags are compared as semver (ignoring a leading v), tags which aren't
+// semver sort before those which are and by name among themselves. Apps calling it are always
+// re-rendered with --cache since a newer tag can be pushed without any of their inputs changing
+#[derive(Default)]
+pub struct ResolveTag {
+    tags: Mutex<HashMap<String, Vec<String>>>,
+}
+
+impl tera::Function for ResolveTag {
+    fn call(&self, args: &HashMap<String, serde_json::Value>) -> tera::Result<serde_json::Value> {
+        let repo = string_arg(args, "repo")?;
+        let pattern = string_arg(args, "pattern")?;
+        let pattern = glob::Pattern::new(pattern).map_err(|e| {
+            tera::Error::msg(format!("resolve_tag: invalid pattern {:?}: {}", pattern, e))
+        })?;
+
+        let tags = self.list_tags(repo)?;
+        return tags
+            .iter()
+            .filter(|tag| pattern.matches(tag))
+            .max_by(|a, b| tag_version(a).cmp(&tag_version(b)).then(a.cmp(b)))
+            .map(|tag| serde_json::Value::String(tag.clone()))
+            .ok_or_else(|| {
+                tera::Error::msg(format!(
+                    "resolve_tag: no tag of {} matches {:?}",
+                    repo,
+                    pattern.as_str()
+                ))
+            });
+    }
+}
+
+impl ResolveTag {
+    fn list_tags(&self, repo: &str) -> tera::Result<Vec<String>> {
+        // held while listing so concurrently rendered apps don't list the same repo twice
+        let mut cache = self.tags.lock().unwrap();
+        match cache.get(repo) {
+            Some(tags) => return Ok(tags.clone()),
+            None => (),
+        }
+
+        info!(repo, "listing image tags");
+        let output = std::process::Command::new("oras")
+            .args(["repo", "tags", repo])
+            .output()
+            .map_err(|e| tera::Error::msg(format!("resolve_tag: failed to run oras: {}", e)))?;
+        if !output.status.success() {
+            return Err(tera::Error::msg(format!(
+                "resolve_tag: failed to list the tags of {}: {}",
+                repo,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        let tags = String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .map(|tag| tag.trim().to_string())
+            .filter(|tag| !tag.is_empty())
+            .collect::<Vec<_>>();
+        cache.insert(repo.to_string(), tags.clone());
+        return Ok(tags);
+    }
+}
+
+fn string_arg<'a>(
+    args: &'a HashMap<String, serde_json::Value>,
+    name: &str,
+) -> tera::Result<&'a str> {
+    return args
+        .get(name)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| tera::Error::msg(format!("resolve_tag: missing string argument {}", name)));
+}
+
+fn tag_version(tag: &str) -> Option<semver::Version> {
+    return semver::Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok();
+}
@@ -0,0 +1,84 @@
+use anyhow::{anyhow, Result};
+use std::{collections::HashMap, path, sync::Arc};
+use tracing::{info, warn};
+
+// limits keeping a runaway filter from hanging or exhausting the render, generous enough for any
+// filter doing string or lookup work
+const MAX_OPERATIONS: u64 = 1_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_STRING_SIZE: usize = 1024 * 1024;
+const MAX_COLLECTION_SIZE: usize = 10_000;
+
+// registers every function defined in the rhai script as a tera filter of the same name. The
+// filtered value is passed as the first parameter and, for functions taking a second parameter,
+// the filter's arguments as an object map, eg.
+//
+//   fn cost_center(team, args) { if team == "web" { "cc-100" } else { args.fallback } }
+//
+// is used as {{ team | cost_center(fallback="cc-000") }}. Functions declared private aren't
+// registered
+pub fn register_filters(tera: &mut tera::Tera, script_path: &path::Path) -> Result<()> {
+    let mut engine = rhai::Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_string_size(MAX_STRING_SIZE)
+        .set_max_array_size(MAX_COLLECTION_SIZE)
+        .set_max_map_size(MAX_COLLECTION_SIZE);
+    let ast = engine
+        .compile_file(script_path.to_path_buf())
+        .map_err(|e| anyhow!("failed to compile filters script {:?}: {}", script_path, e))?;
+    let script = Arc::new((engine, ast));
+
+    for function in script.1.iter_functions() {
+        // private functions are helpers for the filters
+        if function.access == rhai::FnAccess::Private {
+            continue;
+        }
+        let name = function.name.to_string();
+        let param_count = function.params.len();
+        if !(1..=2).contains(&param_count) {
+            warn!(
+                function = name,
+                script=?script_path,
+                "skipping script function, filters take the value and optionally the filter arguments"
+            );
+            continue;
+        }
+
+        info!(filter = name, script=?script_path, "registering script filter");
+        let script = script.clone();
+        let filter_name = name.clone();
+        tera.register_filter(
+            &name,
+            move |value: &serde_json::Value, args: &HashMap<String, serde_json::Value>| {
+                let (engine, ast) = script.as_ref();
+                let mut call_args = vec![to_dynamic(&filter_name, value)?];
+                if param_count == 2 {
+                    call_args.push(to_dynamic(&filter_name, args)?);
+                }
+                let result = engine
+                    .call_fn::<rhai::Dynamic>(&mut rhai::Scope::new(), ast, &filter_name, call_args)
+                    .map_err(|e| {
+                        tera::Error::msg(format!("filter {} failed: {}", filter_name, e))
+                    })?;
+                return rhai::serde::from_dynamic::<serde_json::Value>(&result).map_err(|e| {
+                    tera::Error::msg(format!(
+                        "filter {} returned an unsupported value: {}",
+                        filter_name, e
+                    ))
+                });
+            },
+        );
+    }
+    return Ok(());
+}
+
+fn to_dynamic<T: serde::Serialize>(filter_name: &str, value: &T) -> tera::Result<rhai::Dynamic> {
+    return rhai::serde::to_dynamic(value).map_err(|e| {
+        tera::Error::msg(format!(
+            "failed to pass value to filter {}: {}",
+            filter_name, e
+        ))
+    });
+}
@@ -0,0 +1,461 @@
+use anyhow::{anyhow, Result};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use std::{
+    collections::HashMap,
+    fs,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    path,
+    sync::mpsc,
+    thread,
+    time::Duration,
+};
+use tracing::{error, info, info_span, warn};
+
+use crate::{diagnostics, processor::ProjectProcessor, Args};
+
+#[derive(serde::Deserialize, Debug)]
+struct ServerConfig {
+    // where the source repos are cloned, each repo gets a directory named after it
+    work_dir: String,
+    repos: Vec<RepoConfig>,
+}
+
+#[derive(serde::Deserialize, Debug, Clone)]
+pub struct RepoConfig {
+    // webhooks for the repo are sent to /hooks/<name>
+    pub name: String,
+    pub url: String,
+    // only pushes to this branch trigger a run, defaults to main
+    pub branch: Option<String>,
+    pub output_path: String,
+    // name of the env var holding the webhook secret, used to check github signatures and gitlab
+    // tokens. Required unless insecure is set
+    pub secret_env: Option<String>,
+    // accept unauthenticated webhooks for repos without a secret_env, eg. on a private network
+    pub insecure: Option<bool>,
+    // run with bash after the output is generated, eg. to commit and push or apply it. The source
+    // and output paths are passed in the $in and $out env vars like app scripts
+    pub after_script: Option<String>,
+}
+
+struct Request {
+    method: String,
+    path: String,
+    headers: HashMap<String, String>,
+    body: Vec<u8>,
+}
+
+// status and message sent back to the client
+type Response = (u16, String);
+
+// limits on what a client can send, requests are read before they can be fully authenticated
+const MAX_HEADER_SIZE: u64 = 64 * 1024;
+const MAX_BODY_SIZE: usize = 1024 * 1024;
+// requests are handled one at a time, so a slow client mustn't hold up every other webhook
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+
+// listens for github and gitlab push webhooks, rendering the pushed repo into its output path each
+// time its branch is pushed to. Webhooks are answered once the run is queued since github gives up
+// on deliveries after 10s, each repo is rendered on its own thread so its runs never overlap
+pub fn serve(args: Args, listen: &str, config_path: &path::Path) -> Result<()> {
+    let contents = fs::read_to_string(config_path)
+        .map_err(|e| anyhow!("failed to read server config {:?}: {}", config_path, e))?;
+    let config: ServerConfig = toml::from_str(&contents)
+        .map_err(|e| diagnostics::toml_error(config_path, &contents, &e))?;
+    for repo in config.repos.iter() {
+        if repo.secret_env.is_none() && !repo.insecure.unwrap_or(false) {
+            return Err(anyhow!(
+                "repo {} has no secret_env, set insecure = true to accept unauthenticated webhooks",
+                repo.name
+            ));
+        }
+    }
+    fs::create_dir_all(&config.work_dir)?;
+    let renderers = config
+        .repos
+        .iter()
+        .map(|repo| {
+            let renderer = spawn_renderer(&args, path::Path::new(&config.work_dir), repo);
+            (repo.name.clone(), renderer)
+        })
+        .collect::<HashMap<_, _>>();
+
+    let listener =
+        TcpListener::bind(listen).map_err(|e| anyhow!("failed to listen on {}: {}", listen, e))?;
+    info!(listen, repos = config.repos.len(), "listening for webhooks");
+
+    for stream in listener.incoming() {
+        let mut stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                warn!(error=?e, "failed to accept connection");
+                continue;
+            }
+        };
+        let timeouts = stream
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .and_then(|()| stream.set_write_timeout(Some(REQUEST_TIMEOUT)));
+        if let Err(e) = timeouts {
+            warn!(error=?e, "failed to set connection timeouts");
+            continue;
+        }
+        let (status, message) =
+            handle_request(&config, &renderers, &mut BufReader::new(&mut stream));
+        if let Err(e) = write_response(&mut stream, status, &message) {
+            warn!(error=?e, "failed to write response");
+        }
+    }
+    return Ok(());
+}
+
+// renders the repo each time a run is queued on the returned sender. Runs queued while one is in
+// progress are coalesced into a single run since it checks out the branch's latest commit anyway
+fn spawn_renderer(args: &Args, work_dir: &path::Path, repo: &RepoConfig) -> mpsc::Sender<()> {
+    let (sender, receiver) = mpsc::channel::<()>();
+    let args = args.clone();
+    let work_dir = work_dir.to_path_buf();
+    let repo = repo.clone();
+    thread::spawn(move || {
+        while receiver.recv().is_ok() {
+            while receiver.try_recv().is_ok() {}
+            let _repo_span = info_span!("render", repo = repo.name).entered();
+            match render_repo(&args, &work_dir, &repo) {
+                Ok(()) => info!("rendered"),
+                Err(e) => error!("{:#}", e),
+            }
+        }
+    });
+    return sender;
+}
+
+fn handle_request(
+    config: &ServerConfig,
+    renderers: &HashMap<String, mpsc::Sender<()>>,
+    reader: &mut impl BufRead,
+) -> Response {
+    let mut request = match read_head(reader) {
+        Ok(request) => request,
+        Err(response) => return response,
+    };
+    if request.method != "POST" {
+        return (405, "only POST is supported".to_string());
+    }
+    let repo = match request
+        .path
+        .strip_prefix("/hooks/")
+        .and_then(|name| config.repos.iter().find(|repo| repo.name == name))
+    {
+        Some(repo) => repo,
+        None => return (404, format!("no repo configured for {}", request.path)),
+    };
+    let _repo_span = info_span!("webhook", repo = repo.name).entered();
+
+    // the body is only read once the headers carry valid credentials, github signatures cover the
+    // body so they can only be checked after
+    match authenticate_headers(repo, &request) {
+        Ok(()) => (),
+        Err(e) => {
+            warn!(error=%e, "rejected webhook");
+            return (401, format!("{:#}", e));
+        }
+    }
+    match read_body(reader, &mut request) {
+        Ok(()) => (),
+        Err(response) => return response,
+    }
+    match authenticate_body(repo, &request) {
+        Ok(()) => (),
+        Err(e) => {
+            warn!(error=%e, "rejected webhook");
+            return (401, format!("{:#}", e));
+        }
+    }
+
+    let branch = repo.branch.as_deref().unwrap_or("main");
+    let pushed_ref = serde_json::from_slice::<serde_json::Value>(&request.body)
+        .ok()
+        .and_then(|payload| payload["ref"].as_str().map(str::to_string));
+    if pushed_ref.as_deref() != Some(format!("refs/heads/{}", branch).as_str()) {
+        info!(pushed_ref, branch, "ignoring push to another branch");
+        return (
+            202,
+            "ignored, not a push to the configured branch".to_string(),
+        );
+    }
+
+    // sending only fails when the renderer thread has panicked
+    return match renderers[&repo.name].send(()) {
+        Ok(()) => (202, "render queued".to_string()),
+        Err(_) => (500, "the repo's renderer has stopped".to_string()),
+    };
+}
+
+// the repo's webhook secret, None for insecure repos
+fn webhook_secret(repo: &RepoConfig) -> Result<Option<String>> {
+    return match repo.secret_env.as_ref() {
+        Some(secret_env) => std::env::var(secret_env)
+            .map(Some)
+            .map_err(|_| anyhow!("webhook secret env var {} isn't set", secret_env)),
+        None if repo.insecure.unwrap_or(false) => Ok(None),
+        None => Err(anyhow!("repo {} has no secret_env", repo.name)),
+    };
+}
+
+// gitlab sends the secret itself (X-Gitlab-Token) so it's checked before the body is read. github
+// signs the body with the secret (X-Hub-Signature-256), only the signature's presence is checked
+// until the body has been read
+fn authenticate_headers(repo: &RepoConfig, request: &Request) -> Result<()> {
+    let secret = match webhook_secret(repo)? {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    if request.headers.contains_key("x-hub-signature-256") {
+        return Ok(());
+    }
+    if let Some(token) = request.headers.get("x-gitlab-token") {
+        if constant_time_eq(token.as_bytes(), secret.as_bytes()) {
+            return Ok(());
+        }
+        return Err(anyhow!("invalid webhook token"));
+    }
+    return Err(anyhow!("webhook has no signature or token"));
+}
+
+fn authenticate_body(repo: &RepoConfig, request: &Request) -> Result<()> {
+    let secret = match webhook_secret(repo)? {
+        Some(secret) => secret,
+        None => return Ok(()),
+    };
+    if let Some(signature) = request.headers.get("x-hub-signature-256") {
+        let expected = github_signature(secret.as_bytes(), &request.body);
+        if constant_time_eq(signature.as_bytes(), expected.as_bytes()) {
+            return Ok(());
+        }
+        return Err(anyhow!("invalid webhook signature"));
+    }
+    // gitlab tokens were checked along with the headers
+    return Ok(());
+}
+
+// clones or updates the repo's checkout under work_dir, renders it and runs its after script
+pub fn render_repo(args: &Args, work_dir: &path::Path, repo: &RepoConfig) -> Result<()> {
+    let branch = repo.branch.as_deref().unwrap_or("main");
+    let checkout = work_dir.join(&repo.name);
+    if checkout.join(".git").exists() {
+        git(&checkout, &["fetch", "--depth", "1", "origin", branch])?;
+        git(&checkout, &["checkout", "--force", "FETCH_HEAD"])?;
+    } else {
+        git(
+            work_dir,
+            &[
+                "clone", "--depth", "1", "--branch", branch, &repo.url, &repo.name,
+            ],
+        )?;
+    }
+
+    let mut args = args.clone();
+    args.command = None;
+    args.input_path = vec![checkout.display().to_string()];
+    args.output_path = Some(repo.output_path.clone());
+    let mut project_processor = ProjectProcessor::new(args)?;
+    project_processor.process()?;
+    project_processor.finish()?;
+
+    match repo.after_script.as_ref() {
+        Some(script) => {
+            let output = std::process::Command::new("bash")
+                .arg("-c")
+                .arg(script)
+                .env("in", &checkout)
+                .env("out", &repo.output_path)
+                .output()?;
+            info!(output=?output, "after script output");
+            if !output.status.success() {
+                return Err(anyhow!(
+                    "after script exited with a non zero status: {}",
+                    String::from_utf8_lossy(&output.stderr)
+                ));
+            }
+        }
+        None => (),
+    }
+    return Ok(());
+}
+
+// runs git in dir returning its trimmed stdout
+pub fn git(dir: &path::Path, git_args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new("git")
+        .args(git_args)
+        .current_dir(dir)
+        .output()
+        .map_err(|e| anyhow!("failed to run git: {}", e))?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "git {} failed: {}",
+            git_args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    return Ok(String::from_utf8_lossy(&output.stdout).trim().to_string());
+}
+
+// reads the request line and headers, leaving the body to read_body
+fn read_head(reader: &mut impl BufRead) -> Result<Request, Response> {
+    let mut limited = reader.take(MAX_HEADER_SIZE);
+    let mut read_line = || {
+        let mut line = String::new();
+        limited
+            .read_line(&mut line)
+            .map_err(|e| (400, format!("failed to read request: {}", e)))?;
+        if !line.ends_with('\n') {
+            return Err((431, "request headers are too large".to_string()));
+        }
+        return Ok(line);
+    };
+
+    let request_line = read_line()?;
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default().to_string();
+    let path = parts.next().unwrap_or_default().to_string();
+
+    let mut headers = HashMap::new();
+    loop {
+        let line = read_line()?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        match line.split_once(':') {
+            Some((name, value)) => {
+                headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+            }
+            None => return Err((400, format!("invalid header {:?}", line))),
+        }
+    }
+
+    return Ok(Request {
+        method,
+        path,
+        headers,
+        body: Vec::new(),
+    });
+}
+
+fn read_body(reader: &mut impl BufRead, request: &mut Request) -> Result<(), Response> {
+    let content_length = match request.headers.get("content-length") {
+        Some(length) => length
+            .parse::<usize>()
+            .map_err(|_| (400, format!("invalid content length {:?}", length)))?,
+        None => 0,
+    };
+    if content_length > MAX_BODY_SIZE {
+        return Err((
+            413,
+            format!("request body is larger than {} bytes", MAX_BODY_SIZE),
+        ));
+    }
+    request.body = vec![0; content_length];
+    reader
+        .read_exact(&mut request.body)
+        .map_err(|e| (400, format!("failed to read request body: {}", e)))?;
+    return Ok(());
+}
+
+fn write_response(stream: &mut TcpStream, status: u16, message: &str) -> Result<()> {
+    let reason = match status {
+        200 => "OK",
+        202 => "Accepted",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Payload Too Large",
+        431 => "Request Header Fields Too Large",
+        _ => "Internal Server Error",
+    };
+    write!(
+        stream,
+        "HTTP/1.1 {} {}\r\ncontent-type: text/plain\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}\n",
+        status,
+        reason,
+        message.len() + 1,
+        message
+    )?;
+    return Ok(());
+}
+
+// the X-Hub-Signature-256 github sends for a body signed with the webhook secret
+fn github_signature(secret: &[u8], body: &[u8]) -> String {
+    // unwrap since hmac accepts keys of any length
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).unwrap();
+    mac.update(body);
+    return format!(
+        "sha256={}",
+        mac.finalize()
+            .into_bytes()
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>()
+    );
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    return a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // https://docs.github.com/en/webhooks/using-webhooks/validating-webhook-deliveries#testing-the-webhook-payload-validation
+    #[test]
+    fn github_signature_matches_documented_example() {
+        assert_eq!(
+            github_signature(b"It's a Secret to Everybody", b"Hello, World!"),
+            "sha256=757107ea0eb2509fc211221cce984b8a37570b6d7586c22c46f4379c8b043e17"
+        );
+    }
+
+    #[test]
+    fn github_signature_hashes_long_secrets() {
+        let secret = [b'k'; 100];
+        assert_ne!(
+            github_signature(&secret, b"body"),
+            github_signature(&secret[..64], b"body")
+        );
+    }
+
+    #[test]
+    fn reads_request() {
+        let mut reader = &b"POST /hooks/repo HTTP/1.1\r\nContent-Length: 4\r\nX-Gitlab-Token:  secret \r\n\r\nbodyextra"[..];
+        let mut request = read_head(&mut reader).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/hooks/repo");
+        assert_eq!(request.headers["content-length"], "4");
+        assert_eq!(request.headers["x-gitlab-token"], "secret");
+        read_body(&mut reader, &mut request).unwrap();
+        assert_eq!(request.body, b"body");
+    }
+
+    #[test]
+    fn rejects_invalid_requests() {
+        let mut reader = &b"POST / HTTP/1.1\r\nno colon\r\n\r\n"[..];
+        assert_eq!(read_head(&mut reader).err().unwrap().0, 400);
+
+        let long_header = format!("POST / HTTP/1.1\r\nx: {}\r\n\r\n", "a".repeat(70 * 1024));
+        assert_eq!(read_head(&mut long_header.as_bytes()).err().unwrap().0, 431);
+
+        let mut request =
+            read_head(&mut &b"POST / HTTP/1.1\r\nContent-Length: 2000000\r\n\r\n"[..]).unwrap();
+        assert_eq!(read_body(&mut &b""[..], &mut request).err().unwrap().0, 413);
+
+        let mut request =
+            read_head(&mut &b"POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\n"[..]).unwrap();
+        assert_eq!(
+            read_body(&mut &b"short"[..], &mut request).err().unwrap().0,
+            400
+        );
+    }
+}
@@ -0,0 +1,219 @@
+use anyhow::{anyhow, Result};
+use std::{fs, path};
+use tracing::info;
+
+use crate::{processor::ProjectProcessor, Args};
+
+// differences between two generated trees, paths are relative to the compared directories
+#[derive(Default, Debug)]
+pub struct TreeDiff {
+    pub missing: Vec<path::PathBuf>,
+    pub unexpected: Vec<path::PathBuf>,
+    pub changed: Vec<path::PathBuf>,
+}
+
+impl TreeDiff {
+    pub fn is_empty(&self) -> bool {
+        return self.missing.is_empty() && self.unexpected.is_empty() && self.changed.is_empty();
+    }
+}
+
+// renders the input into a temporary directory returning the processor so the generated output
+// can be inspected before the directory is removed
+pub fn render_to_temp_dir(
+    mut args: Args,
+    ignore_dirs: &[&path::Path],
+) -> Result<(ProjectProcessor, tempdir::TempDir)> {
+    let output_dir = tempdir::TempDir::new("argocd-preprocessor")?;
+    args.output_path = Some(output_dir.path().display().to_string());
+    args.extra_ignore_dirs
+        .extend(ignore_dirs.iter().map(|d| d.to_path_buf()));
+
+    let mut project_processor = ProjectProcessor::new(args)?;
+    project_processor.process()?;
+    return Ok((project_processor, output_dir));
+}
+
+// renders the input and compares each generated target with the matching directory under
+// expected_path. When updating snapshots mismatched targets are overwritten with the generated
+// output instead of failing
+pub fn test(args: Args, expected_path: &path::Path, update_snapshots: bool) -> Result<()> {
+    let (project_processor, _output_dir) = render_to_temp_dir(args, &[expected_path])?;
+
+    let mut failed_targets = Vec::new();
+    for target_name in project_processor.processed_targets() {
+        let expected_dir = expected_path.join(&target_name);
+        let actual_dir = project_processor.output_path().join(&target_name);
+        let diff = compare_dirs(&expected_dir, &actual_dir)?;
+        if diff.is_empty() {
+            info!(target = target_name, "output matches expected");
+            continue;
+        }
+
+        print_diff(&expected_dir, &actual_dir, &diff)?;
+        if update_snapshots {
+            replace_dir(&actual_dir, &expected_dir)?;
+            println!("updated {}", expected_dir.display());
+            continue;
+        }
+        failed_targets.push(target_name);
+    }
+
+    if !failed_targets.is_empty() {
+        return Err(anyhow!(
+            "output for targets {:?} doesn't match {:?}",
+            failed_targets,
+            expected_path
+        ));
+    }
+    println!("all targets match {}", expected_path.display());
+    return Ok(());
+}
+
+// renders the input and fails if any generated target differs from the already generated output
+pub fn verify(args: Args, output_path: &path::Path) -> Result<()> {
+    let (project_processor, _output_dir) = render_to_temp_dir(args, &[output_path])?;
+
+    let mut stale_targets = Vec::new();
+    for target_name in project_processor.processed_targets() {
+        let committed_dir = output_path.join(&target_name);
+        let diff = compare_dirs(
+            &committed_dir,
+            &project_processor.output_path().join(&target_name),
+        )?;
+        if !diff.is_empty() {
+            print_diff_paths(
+                &committed_dir,
+                &project_processor.output_path().join(&target_name),
+                &diff,
+            );
+            stale_targets.push(target_name);
+        }
+    }
+
+    // dirs of targets which are no longer in the config would be left behind by a regeneration
+    let configured_targets = project_processor.configured_targets();
+    for dir in target_dirs(output_path)? {
+        if !configured_targets.contains(&dir) {
+            println!("removed: {}", output_path.join(&dir).display());
+            stale_targets.push(dir);
+        }
+    }
+
+    if !stale_targets.is_empty() {
+        return Err(anyhow!(
+            "output for targets {:?} in {:?} is out of date, regenerate it",
+            stale_targets,
+            output_path
+        ));
+    }
+    println!("output in {} is up to date", output_path.display());
+    return Ok(());
+}
+
+pub fn compare_dirs(expected_dir: &path::Path, actual_dir: &path::Path) -> Result<TreeDiff> {
+    let expected_files = list_files(expected_dir)?;
+    let actual_files = list_files(actual_dir)?;
+
+    let mut diff = TreeDiff::default();
+    for file in expected_files.iter() {
+        if !actual_files.contains(file) {
+            diff.missing.push(file.clone());
+        } else if fs::read(expected_dir.join(file))? != fs::read(actual_dir.join(file))? {
+            diff.changed.push(file.clone());
+        }
+    }
+    for file in actual_files {
+        if !expected_files.contains(&file) {
+            diff.unexpected.push(file);
+        }
+    }
+    return Ok(diff);
+}
+
+// prints the paths that differ along with a unified diff of every changed file
+fn print_diff(expected_dir: &path::Path, actual_dir: &path::Path, diff: &TreeDiff) -> Result<()> {
+    print_diff_paths(expected_dir, actual_dir, diff);
+    for file in diff.changed.iter() {
+        let expected = String::from_utf8_lossy(&fs::read(expected_dir.join(file))?).to_string();
+        let actual = String::from_utf8_lossy(&fs::read(actual_dir.join(file))?).to_string();
+        let expected_name = expected_dir.join(file).display().to_string();
+        print!(
+            "{}",
+            similar::TextDiff::from_lines(&expected, &actual)
+                .unified_diff()
+                .header(&expected_name, &format!("{} (generated)", expected_name))
+        );
+    }
+    return Ok(());
+}
+
+// labelled from the committed (expected) tree's point of view: removed files are only in the
+// committed tree, added files only in the generated one
+fn print_diff_paths(expected_dir: &path::Path, actual_dir: &path::Path, diff: &TreeDiff) {
+    for file in diff.missing.iter() {
+        println!("removed: {}", expected_dir.join(file).display());
+    }
+    for file in diff.unexpected.iter() {
+        println!("added: {}", actual_dir.join(file).display());
+    }
+    for file in diff.changed.iter() {
+        println!("changed: {}", expected_dir.join(file).display());
+    }
+}
+
+// names of the non hidden directories in dir
+pub fn target_dirs(dir: &path::Path) -> Result<Vec<String>> {
+    let mut dirs = Vec::new();
+    if !dir.exists() {
+        return Ok(dirs);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        if entry.file_type()?.is_dir() && !name.starts_with('.') {
+            dirs.push(name);
+        }
+    }
+    dirs.sort();
+    return Ok(dirs);
+}
+
+// replaces to_dir with a copy of from_dir
+fn replace_dir(from_dir: &path::Path, to_dir: &path::Path) -> Result<()> {
+    if to_dir.exists() {
+        fs::remove_dir_all(to_dir)?;
+    }
+    for file in list_files(from_dir)? {
+        let to_path = to_dir.join(&file);
+        fs::create_dir_all(to_path.parent().unwrap())?;
+        fs::copy(from_dir.join(&file), to_path)?;
+    }
+    return Ok(());
+}
+
+// all files under dir relative to it in sorted order, a missing directory has no files
+pub fn list_files(dir: &path::Path) -> Result<Vec<path::PathBuf>> {
+    let mut files = Vec::new();
+    if dir.exists() {
+        list_files_in_dir(dir, dir, &mut files)?;
+    }
+    files.sort();
+    return Ok(files);
+}
+
+fn list_files_in_dir(
+    root: &path::Path,
+    dir: &path::Path,
+    files: &mut Vec<path::PathBuf>,
+) -> Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            list_files_in_dir(root, &path, files)?;
+            continue;
+        }
+        files.push(path.strip_prefix(root)?.to_path_buf());
+    }
+    return Ok(());
+}
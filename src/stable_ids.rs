@@ -0,0 +1,94 @@
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// tera functions generating identifiers from their arguments alone, so they stay the same across
+// runs and hosts unlike random values
+
+// a name based (v5) uuid of name within namespace, which is either a uuid or one of the standard
+// dns, url, oid or x500 namespaces, eg. {{ uuid_v5(namespace="dns", name=host) }}
+pub fn uuid_v5_function(
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let namespace = string_arg("uuid_v5", args, "namespace")?;
+    let name = string_arg("uuid_v5", args, "name")?;
+    let namespace = match namespace {
+        "dns" => uuid::Uuid::NAMESPACE_DNS,
+        "url" => uuid::Uuid::NAMESPACE_URL,
+        "oid" => uuid::Uuid::NAMESPACE_OID,
+        "x500" => uuid::Uuid::NAMESPACE_X500,
+        namespace => uuid::Uuid::parse_str(namespace).map_err(|e| {
+            tera::Error::msg(format!(
+                "uuid_v5: namespace {:?} isn't a uuid or one of dns, url, oid or x500: {}",
+                namespace, e
+            ))
+        })?,
+    };
+    return Ok(serde_json::Value::String(
+        uuid::Uuid::new_v5(&namespace, name.as_bytes()).to_string(),
+    ));
+}
+
+// an integer between min and max (inclusive) picked by seed, eg. a node port which only changes
+// with the seed {{ seeded_int(seed=host, min=30000, max=32767) }}
+pub fn seeded_int_function(
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let seed = string_arg("seeded_int", args, "seed")?;
+    let min = int_arg("seeded_int", args, "min")?;
+    let max = int_arg("seeded_int", args, "max")?;
+    if min > max {
+        return Err(tera::Error::msg(format!(
+            "seeded_int: min {} is greater than max {}",
+            min, max
+        )));
+    }
+    let range = (max as i128 - min as i128 + 1) as u128;
+    return Ok(serde_json::Value::from(
+        (min as i128 + (seeded(seed) % range) as i128) as i64,
+    ));
+}
+
+// an item of from picked by seed, eg. {{ seeded_choice(seed=host, from=["a", "b", "c"]) }}
+pub fn seeded_choice_function(
+    args: &HashMap<String, serde_json::Value>,
+) -> tera::Result<serde_json::Value> {
+    let seed = string_arg("seeded_choice", args, "seed")?;
+    let items = args
+        .get("from")
+        .and_then(|value| value.as_array())
+        .filter(|items| !items.is_empty())
+        .ok_or_else(|| tera::Error::msg("seeded_choice: missing non empty array argument from"))?;
+    return Ok(items[(seeded(seed) % items.len() as u128) as usize].clone());
+}
+
+// the first 16 bytes of the seed's sha256
+fn seeded(seed: &str) -> u128 {
+    let hash = Sha256::digest(seed.as_bytes());
+    return u128::from_be_bytes(hash[..16].try_into().unwrap());
+}
+
+fn string_arg<'a>(
+    function: &str,
+    args: &'a HashMap<String, serde_json::Value>,
+    name: &str,
+) -> tera::Result<&'a str> {
+    return args
+        .get(name)
+        .and_then(|value| value.as_str())
+        .ok_or_else(|| {
+            tera::Error::msg(format!("{}: missing string argument {}", function, name))
+        });
+}
+
+fn int_arg(
+    function: &str,
+    args: &HashMap<String, serde_json::Value>,
+    name: &str,
+) -> tera::Result<i64> {
+    return args
+        .get(name)
+        .and_then(|value| value.as_i64())
+        .ok_or_else(|| {
+            tera::Error::msg(format!("{}: missing integer argument {}", function, name))
+        });
+}
@@ -0,0 +1,39 @@
+use anyhow::Result;
+use opentelemetry::trace::TracerProvider as _;
+use opentelemetry_otlp::WithExportConfig;
+use opentelemetry_sdk::{trace::SdkTracerProvider, Resource};
+use tracing::Subscriber;
+use tracing_subscriber::registry::LookupSpan;
+
+// builds an OTLP/HTTP span exporter when an endpoint is given on the command line or through the
+// standard OTEL_EXPORTER_OTLP_* environment variables. The returned provider must be shut down
+// before exiting to flush any buffered spans
+pub fn init<S>(
+    endpoint: Option<String>,
+) -> Result<
+    Option<(
+        tracing_opentelemetry::OpenTelemetryLayer<S, opentelemetry_sdk::trace::Tracer>,
+        SdkTracerProvider,
+    )>,
+>
+where
+    S: Subscriber + for<'span> LookupSpan<'span>,
+{
+    let env_configured = std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_some()
+        || std::env::var_os("OTEL_EXPORTER_OTLP_TRACES_ENDPOINT").is_some();
+    if endpoint.is_none() && !env_configured {
+        return Ok(None);
+    }
+
+    let mut exporter = opentelemetry_otlp::SpanExporter::builder().with_http();
+    if let Some(endpoint) = endpoint {
+        exporter = exporter.with_endpoint(endpoint);
+    }
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter.build()?)
+        .with_resource(Resource::builder().with_service_name("bargo").build())
+        .build();
+    let layer = tracing_opentelemetry::layer().with_tracer(provider.tracer("bargo"));
+    return Ok(Some((layer, provider)));
+}
@@ -0,0 +1,132 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Phase {
+    Discovery,
+    Templating,
+    Copying,
+    Scripts,
+    Writing,
+}
+
+struct Entry {
+    phase: Phase,
+    target: Option<String>,
+    app: Option<String>,
+    duration: Duration,
+}
+
+// records how long each phase of a run took, broken down by target and app. Phases which run
+// across multiple threads (copying and templating files) are summed so they can add up to more
+// than the wall clock time
+pub struct Timings {
+    enabled: bool,
+    entries: Mutex<Vec<Entry>>,
+}
+
+impl Timings {
+    pub fn new(enabled: bool) -> Timings {
+        return Timings {
+            enabled,
+            entries: Mutex::new(Vec::new()),
+        };
+    }
+
+    pub fn time<T>(
+        &self,
+        phase: Phase,
+        target: Option<&str>,
+        app: Option<&str>,
+        f: impl FnOnce() -> T,
+    ) -> T {
+        if !self.enabled {
+            return f();
+        }
+
+        let start = Instant::now();
+        let result = f();
+        self.record(phase, target, app, start.elapsed());
+        return result;
+    }
+
+    pub fn record(
+        &self,
+        phase: Phase,
+        target: Option<&str>,
+        app: Option<&str>,
+        duration: Duration,
+    ) {
+        if !self.enabled {
+            return;
+        }
+
+        self.entries.lock().unwrap().push(Entry {
+            phase,
+            target: target.map(str::to_string),
+            app: app.map(str::to_string),
+            duration,
+        });
+    }
+
+    // formats the totals per phase and per target followed by the slowest apps
+    pub fn report(&self, slowest: usize) -> String {
+        let entries = self.entries.lock().unwrap();
+
+        let mut by_phase: BTreeMap<Phase, Duration> = BTreeMap::new();
+        let mut by_target: BTreeMap<&str, Duration> = BTreeMap::new();
+        let mut by_app: BTreeMap<(&str, &str), BTreeMap<Phase, Duration>> = BTreeMap::new();
+        for entry in entries.iter() {
+            *by_phase.entry(entry.phase).or_default() += entry.duration;
+            if let Some(target) = entry.target.as_deref() {
+                *by_target.entry(target).or_default() += entry.duration;
+                if let Some(app) = entry.app.as_deref() {
+                    *by_app
+                        .entry((target, app))
+                        .or_default()
+                        .entry(entry.phase)
+                        .or_default() += entry.duration;
+                }
+            }
+        }
+
+        let mut report = String::new();
+        writeln!(report, "time by phase:").unwrap();
+        for (phase, duration) in by_phase.iter() {
+            writeln!(
+                report,
+                "  {:<12} {:>10.3?}",
+                format!("{:?}", phase),
+                duration
+            )
+            .unwrap();
+        }
+
+        writeln!(report, "time by target:").unwrap();
+        for (target, duration) in by_target.iter() {
+            writeln!(report, "  {:<12} {:>10.3?}", target, duration).unwrap();
+        }
+
+        let mut apps = by_app
+            .iter()
+            .map(|(key, phases)| (key, phases, phases.values().sum::<Duration>()))
+            .collect::<Vec<_>>();
+        apps.sort_by_key(|app| std::cmp::Reverse(app.2));
+
+        writeln!(report, "slowest {} apps:", slowest.min(apps.len())).unwrap();
+        for ((target, app), phases, total) in apps.into_iter().take(slowest) {
+            let phases = phases
+                .iter()
+                .map(|(phase, duration)| format!("{:?} {:.3?}", phase, duration))
+                .collect::<Vec<_>>()
+                .join(", ");
+            writeln!(report, "  {:>10.3?} {}/{} ({})", total, target, app, phases).unwrap();
+        }
+
+        return report;
+    }
+}
@@ -0,0 +1,80 @@
+use anyhow::{anyhow, Result};
+use std::path;
+use tracing::info;
+
+// object storage the generated output is uploaded to, each target is synced to its own prefix
+// under the url by shelling out to the provider's cli (aws or gcloud) so their usual credential
+// handling applies
+pub struct Upload {
+    url: String,
+    server_side_encryption: Option<String>,
+}
+
+impl Upload {
+    pub fn new(url: String, server_side_encryption: Option<String>) -> Result<Upload> {
+        if !url.starts_with("s3://") && !url.starts_with("gs://") {
+            return Err(anyhow!(
+                "unsupported upload url {:?}, expected an s3:// or gs:// url",
+                url
+            ));
+        }
+        if server_side_encryption.is_some() && !url.starts_with("s3://") {
+            return Err(anyhow!(
+                "server side encryption is only supported for s3 uploads"
+            ));
+        }
+
+        return Ok(Upload {
+            url: url.trim_end_matches('/').to_string(),
+            server_side_encryption,
+        });
+    }
+
+    pub fn url(&self) -> &str {
+        return &self.url;
+    }
+
+    // mirrors target_dir to <url>/<target_name>, removing objects which are no longer generated
+    pub fn upload_target(&self, target_dir: &path::Path, target_name: &str) -> Result<()> {
+        let destination = format!("{}/{}", self.url, target_name);
+        info!(from=?target_dir, to=destination, "uploading target");
+
+        let mut command = match self.url.starts_with("s3://") {
+            true => {
+                let mut command = std::process::Command::new("aws");
+                command.args(["s3", "sync", "--delete", "--no-progress"]);
+                match self.server_side_encryption.as_ref() {
+                    Some(sse) => {
+                        command.args(["--sse", sse]);
+                    }
+                    None => (),
+                }
+                command
+            }
+            false => {
+                let mut command = std::process::Command::new("gcloud");
+                command.args([
+                    "storage",
+                    "rsync",
+                    "--recursive",
+                    "--delete-unmatched-destination-objects",
+                ]);
+                command
+            }
+        };
+        let output = command
+            .arg(target_dir)
+            .arg(&destination)
+            .output()
+            .map_err(|e| anyhow!("failed to run upload command {:?}: {}", command, e))?;
+        if !output.status.success() {
+            return Err(anyhow!(
+                "failed to upload {:?} to {}: {}",
+                target_dir,
+                destination,
+                String::from_utf8_lossy(&output.stderr)
+            ));
+        }
+        return Ok(());
+    }
+}
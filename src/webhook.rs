@@ -0,0 +1,202 @@
+use anyhow::{anyhow, Result};
+use std::{fs, io::Write, path, time::Duration};
+use tracing::{debug, info, warn};
+
+use crate::{WebhookConfig, WebhookFormat, WebhookOn};
+
+// changed applications listed in slack messages, the json summary lists all of them
+const MAX_SLACK_CHANGED_APPS: usize = 20;
+const MAX_SLACK_ERROR_LENGTH: usize = 2000;
+
+// what a run did, posted as is to json webhooks
+#[derive(serde::Serialize, Debug)]
+pub struct RunSummary {
+    pub success: bool,
+    pub input_path: String,
+    pub targets: Vec<String>,
+    pub applications: usize,
+    // output paths of the applications whose output differs from the previous run's
+    pub changed_applications: Vec<String>,
+    pub failed_applications: usize,
+    pub error: Option<String>,
+    pub duration_seconds: f64,
+}
+
+// posts the summary to every webhook configured for the run's outcome. A webhook failing is only
+// logged so it can't change the result of the run
+pub fn notify(webhooks: &[WebhookConfig], summary: &RunSummary) {
+    for webhook in webhooks {
+        let send = match webhook.on.unwrap_or_default() {
+            WebhookOn::Always => true,
+            WebhookOn::Failure => !summary.success,
+            WebhookOn::Success => summary.success,
+        };
+        if !send {
+            continue;
+        }
+        match post(webhook, summary) {
+            Ok(()) => info!(format = ?webhook.format.unwrap_or_default(), "sent run webhook"),
+            Err(e) => warn!("failed to send run webhook: {:#}", e),
+        }
+    }
+}
+
+// reports a run which failed before its config was loaded (eg. a bad template or a vars file
+// failing to fetch). The webhooks are read from bargo.toml on their own so they still fire when
+// another part of the file is invalid
+pub fn notify_setup_failure(input_path: &str, error: &anyhow::Error, duration: Duration) {
+    let input_path = path::Path::new(input_path);
+    let input_path = input_path
+        .canonicalize()
+        .unwrap_or_else(|_| input_path.to_path_buf());
+    let webhooks = configured_webhooks(&input_path);
+    if webhooks.is_empty() {
+        return;
+    }
+    let summary = RunSummary {
+        success: false,
+        input_path: input_path.display().to_string(),
+        targets: Vec::new(),
+        applications: 0,
+        changed_applications: Vec::new(),
+        failed_applications: 0,
+        error: Some(format!("{:#}", error)),
+        duration_seconds: duration.as_secs_f64(),
+    };
+    notify(&webhooks, &summary);
+}
+
+// the webhooks table of input_path's bargo.toml, empty if it can't be read
+fn configured_webhooks(input_path: &path::Path) -> Vec<WebhookConfig> {
+    #[derive(serde::Deserialize)]
+    struct WebhooksOnly {
+        webhooks: Option<Vec<WebhookConfig>>,
+    }
+
+    let config_file_path = input_path.join("bargo.toml");
+    let webhooks = fs::read_to_string(&config_file_path)
+        .map_err(|e| anyhow!("{}", e))
+        .and_then(|contents| {
+            toml::from_str::<WebhooksOnly>(&contents).map_err(|e| anyhow!("{}", e))
+        });
+    return match webhooks {
+        Ok(webhooks) => webhooks.webhooks.unwrap_or_default(),
+        Err(e) => {
+            debug!(?config_file_path, "failed to read webhooks: {}", e);
+            Vec::new()
+        }
+    };
+}
+
+fn post(webhook: &WebhookConfig, summary: &RunSummary) -> Result<()> {
+    let url = match (webhook.url.as_ref(), webhook.url_env.as_ref()) {
+        (Some(url), None) => url.clone(),
+        (None, Some(url_env)) => std::env::var(url_env)
+            .map_err(|_| anyhow!("webhook url env var {} isn't set", url_env))?,
+        _ => return Err(anyhow!("webhooks need one of url or url_env")),
+    };
+    if !url.starts_with("https://") {
+        return Err(anyhow!("webhook url must use https"));
+    }
+    let body = match webhook.format.unwrap_or_default() {
+        WebhookFormat::Json => serde_json::to_string(summary)?,
+        WebhookFormat::Slack => serde_json::json!({ "text": slack_text(summary) }).to_string(),
+    };
+
+    // the url and body are passed in a curl config on stdin so webhook urls, which usually embed a
+    // secret, don't show up in the process list
+    let mut child = std::process::Command::new("curl")
+        .args([
+            "--fail",
+            "--silent",
+            "--show-error",
+            "--proto",
+            "=https",
+            "--max-time",
+            "30",
+            "--header",
+            "Content-Type: application/json",
+            "--config",
+            "-",
+        ])
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .map_err(|e| anyhow!("failed to run curl: {}", e))?;
+    // unwrap since stdin is piped, dropping it closes curl's stdin
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(
+        format!(
+            "url = \"{}\"\ndata-binary = \"{}\"\n",
+            curl_config_escape(&url),
+            curl_config_escape(&body)
+        )
+        .as_bytes(),
+    )?;
+    drop(stdin);
+
+    let output = child.wait_with_output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "curl failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    return Ok(());
+}
+
+fn slack_text(summary: &RunSummary) -> String {
+    let targets = match summary.targets.is_empty() {
+        true => "no targets".to_string(),
+        false => summary.targets.join(", "),
+    };
+    if !summary.success {
+        let mut error = summary.error.clone().unwrap_or_default();
+        if error.len() > MAX_SLACK_ERROR_LENGTH {
+            let mut end = MAX_SLACK_ERROR_LENGTH;
+            while !error.is_char_boundary(end) {
+                end -= 1;
+            }
+            error.truncate(end);
+            error.push_str("...");
+        }
+        return format!(
+            ":x: bargo failed rendering {} ({}) after {:.1}s\n```{}```",
+            summary.input_path, targets, summary.duration_seconds, error
+        );
+    }
+
+    let mut text = format!(
+        ":white_check_mark: bargo rendered {} application(s) from {} ({}) in {:.1}s, {} changed",
+        summary.applications,
+        summary.input_path,
+        targets,
+        summary.duration_seconds,
+        summary.changed_applications.len()
+    );
+    for app in summary
+        .changed_applications
+        .iter()
+        .take(MAX_SLACK_CHANGED_APPS)
+    {
+        text.push_str(&format!("\n• {}", app));
+    }
+    if summary.changed_applications.len() > MAX_SLACK_CHANGED_APPS {
+        text.push_str(&format!(
+            "\n• and {} more",
+            summary.changed_applications.len() - MAX_SLACK_CHANGED_APPS
+        ));
+    }
+    return text;
+}
+
+// quotes a value for a double quoted string in a curl config file
+fn curl_config_escape(value: &str) -> String {
+    return value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+        .replace('\r', "\\r")
+        .replace('\t', "\\t");
+}
@@ -0,0 +1,123 @@
+#![allow(clippy::needless_return)]
+
+mod common;
+
+use std::{fs, path, process};
+
+struct Render {
+    // app output path to input hash
+    hashes: serde_json::Map<String, serde_json::Value>,
+    api: String,
+    worker: String,
+}
+
+fn render(input: &path::Path, output: &path::Path) -> Render {
+    let result = process::Command::new(env!("CARGO_BIN_EXE_bargo"))
+        .arg("--quiet")
+        .arg("--input-path")
+        .arg(input)
+        .arg("--output-path")
+        .arg(output)
+        .arg("--cache")
+        .output()
+        .unwrap();
+    assert!(
+        result.status.success(),
+        "{}",
+        String::from_utf8_lossy(&result.stderr)
+    );
+
+    let cache: serde_json::Value =
+        serde_json::from_slice(&fs::read(output.join(".bargo-cache.json")).unwrap()).unwrap();
+    let read = |app: &str| {
+        fs::read_to_string(output.join("prod/team").join(app).join("configmap.yaml")).unwrap()
+    };
+    return Render {
+        hashes: cache["prod"].as_object().unwrap().clone(),
+        api: read("api"),
+        worker: read("worker"),
+    };
+}
+
+fn edit(file: &path::Path, from: &str, to: &str) {
+    let contents = fs::read_to_string(file).unwrap();
+    assert!(
+        contents.contains(from),
+        "{:?} doesn't contain {:?}",
+        file,
+        from
+    );
+    fs::write(file, contents.replace(from, to)).unwrap();
+}
+
+fn prepend(file: &path::Path, line: &str) {
+    let contents = fs::read_to_string(file).unwrap();
+    fs::write(file, format!("{}{}", line, contents)).unwrap();
+}
+
+// only apps whose inputs changed get a new hash, and so are rendered again, while the others are
+// reused from the previous output
+#[test]
+fn only_changed_apps_are_rendered_again() {
+    let input_dir = tempdir::TempDir::new("bargo-cache-input").unwrap();
+    let output_dir = tempdir::TempDir::new("bargo-cache-output").unwrap();
+    let input = input_dir.path();
+    let output = output_dir.path();
+    common::copy_fixture("cache", input);
+
+    let first = render(input, output);
+    assert_eq!(first.hashes.len(), 2);
+    let unchanged = render(input, output);
+    assert_eq!(unchanged.hashes, first.hashes);
+    assert_eq!(unchanged.api, first.api);
+    assert_eq!(unchanged.worker, first.worker);
+
+    // a template only the api app includes
+    edit(
+        &input.join("shared/labels.tera"),
+        "team: payments",
+        "team: billing",
+    );
+    let template_changed = render(input, output);
+    assert_ne!(
+        template_changed.hashes["prod/team/api"],
+        first.hashes["prod/team/api"]
+    );
+    assert_eq!(
+        template_changed.hashes["prod/team/worker"],
+        first.hashes["prod/team/worker"]
+    );
+    assert!(template_changed.api.contains("team: billing"));
+    assert_eq!(template_changed.worker, first.worker);
+
+    // a var only the worker app sets
+    edit(
+        &input.join("team/worker/metadata.toml"),
+        "replicas = 1",
+        "replicas = 2",
+    );
+    let var_changed = render(input, output);
+    assert_eq!(
+        var_changed.hashes["prod/team/api"],
+        template_changed.hashes["prod/team/api"]
+    );
+    assert_ne!(
+        var_changed.hashes["prod/team/worker"],
+        template_changed.hashes["prod/team/worker"]
+    );
+    assert_eq!(var_changed.api, template_changed.api);
+    assert!(var_changed.worker.contains("replicas: \"2\""));
+
+    // write_inventory only adds a file next to the generated Applications
+    prepend(&input.join("bargo.toml"), "write_inventory = true\n");
+    let non_output_option_changed = render(input, output);
+    assert_eq!(non_output_option_changed.hashes, var_changed.hashes);
+
+    // generated_header changes every app's files
+    prepend(&input.join("bargo.toml"), "generated_header = true\n");
+    let output_option_changed = render(input, output);
+    for (app, hash) in output_option_changed.hashes.iter() {
+        assert_ne!(*hash, non_output_option_changed.hashes[app], "{}", app);
+    }
+    assert_ne!(output_option_changed.api, non_output_option_changed.api);
+}
@@ -0,0 +1,38 @@
+#![allow(clippy::needless_return, dead_code)]
+
+use std::{path, process};
+
+pub fn fixture(name: &str) -> path::PathBuf {
+    return path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/fixtures")
+        .join(name);
+}
+
+// runs bargo quietly with the fixture as its input path
+pub fn bargo(fixture_name: &str) -> process::Command {
+    let mut command = process::Command::new(env!("CARGO_BIN_EXE_bargo"));
+    command
+        .arg("--quiet")
+        .arg("--input-path")
+        .arg(fixture(fixture_name));
+    return command;
+}
+
+// copies a fixture into dir so a test can modify it
+pub fn copy_fixture(name: &str, dir: &path::Path) {
+    copy_dir(&fixture(name), dir);
+}
+
+fn copy_dir(from: &path::Path, to: &path::Path) {
+    std::fs::create_dir_all(to).unwrap();
+    for entry in std::fs::read_dir(from).unwrap() {
+        let entry = entry.unwrap();
+        let path = entry.path();
+        match entry.file_type().unwrap().is_dir() {
+            true => copy_dir(&path, &to.join(entry.file_name())),
+            false => {
+                std::fs::copy(&path, to.join(entry.file_name())).unwrap();
+            }
+        }
+    }
+}
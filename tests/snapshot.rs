@@ -0,0 +1,18 @@
+mod common;
+
+// the AppProject's destinations and source repos must come out in the same order every run for
+// the byte for byte comparison to pass, each run of the binary hashes sets differently
+#[test]
+fn multi_destination_project_matches_expected() {
+    for _ in 0..5 {
+        let output = common::bargo("multi-destination")
+            .arg("test")
+            .output()
+            .unwrap();
+        assert!(
+            output.status.success(),
+            "{}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+    }
+}
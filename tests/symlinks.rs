@@ -0,0 +1,17 @@
+mod common;
+
+// secret.yaml in the fixture links to a file in another fixture, outside its input path
+#[test]
+fn symlink_outside_input_is_rejected() {
+    let output = common::bargo("symlink-escape")
+        .arg("test")
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(
+        stderr.contains("secret.yaml") && stderr.contains("outside the input path"),
+        "{}",
+        stderr
+    );
+}